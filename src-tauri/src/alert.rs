@@ -1,40 +1,139 @@
-use crate::database::Database;
-use chrono::{Duration, Local};
+use crate::calendar::WorkingCalendar;
+use crate::database::{normalize_barcode, DepartmentMapping};
+use crate::db_service::DbService;
+use crate::notifier::EmailNotifier;
+use crate::webhook::WebhookDispatcher;
+use chrono::{DateTime, Duration, Local};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use std::collections::HashMap;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
 use tauri::{AppHandle, Emitter};
 
+/// Minutes between overdue checks when `alert_check_interval_minutes` is missing or invalid
+pub const DEFAULT_CHECK_INTERVAL_MINUTES: i64 = 60;
+
+/// Longest allowed gap between overdue checks (one week)
+pub const MAX_CHECK_INTERVAL_MINUTES: i64 = 7 * 24 * 60;
+
+/// An overdue loan turns critical once it has been out this many times its threshold
+pub const CRITICAL_MULTIPLIER: i64 = 2;
+
+/// Longest overdue threshold that can be set (a year)
+pub const MAX_THRESHOLD_HOURS: i64 = 365 * 24;
+
 pub struct AlertManager {
-    db: Database,
+    db: DbService,
     threshold_hours: i64,
+    /// Mappings that have their own threshold, longest prefix first
+    department_thresholds: Vec<DepartmentMapping>,
+    item_thresholds: HashMap<String, i64>,
+    calendar: WorkingCalendar,
+}
+
+/// Which threshold an overdue item was measured against
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ThresholdRule {
+    Item { barcode: String },
+    Department { prefix: String, department: String },
+    Global,
 }
 
 impl AlertManager {
-    pub fn new() -> Result<Self, rusqlite::Error> {
-        let db = Database::new()?;
-        let threshold_hours = db.get_setting("alert_threshold_hours")?
+    pub fn new(db: DbService) -> Result<Self, rusqlite::Error> {
+        let threshold_hours = db.with(|db| db.get_setting("alert_threshold_hours"))?
             .and_then(|s| s.parse().ok())
             .unwrap_or(24);
 
+        let mut department_thresholds: Vec<DepartmentMapping> = db
+            .with(|db| db.get_department_mappings())?
+            .into_iter()
+            .filter(|mapping| mapping.alert_threshold_hours.is_some())
+            .collect();
+        // Same precedence as get_department_from_barcode
+        department_thresholds.sort_by_key(|mapping| std::cmp::Reverse(mapping.prefix.chars().count()));
+
+        let item_thresholds = db
+            .with(|db| db.get_items(None))?
+            .into_iter()
+            .filter_map(|item| Some((item.barcode, item.alert_threshold_hours?)))
+            .collect();
+
+        let calendar = db.with(|db| db.get_working_calendar())?;
+
         Ok(AlertManager {
             db,
             threshold_hours,
+            department_thresholds,
+            item_thresholds,
+            calendar,
         })
     }
 
+    /// Threshold for a barcode: the item's own, else its department
+    /// mapping's, else the global setting
+    pub fn threshold_for(&self, barcode: &str) -> (i64, ThresholdRule) {
+        let barcode = normalize_barcode(barcode);
+        if let Some(hours) = self.item_thresholds.get(&barcode) {
+            return (*hours, ThresholdRule::Item { barcode });
+        }
+        for mapping in &self.department_thresholds {
+            // Prefixes are kept as typed in the admin panel
+            let matches = barcode.starts_with(&normalize_barcode(&mapping.prefix));
+            if let Some(hours) = mapping.alert_threshold_hours.filter(|_| matches) {
+                return (
+                    hours,
+                    ThresholdRule::Department {
+                        prefix: mapping.prefix.clone(),
+                        department: mapping.department.clone(),
+                    },
+                );
+            }
+        }
+        (self.threshold_hours, ThresholdRule::Global)
+    }
+
     pub fn check_overdue_items(&self) -> Result<Vec<OverdueItem>, rusqlite::Error> {
-        let checked_out_items = self.db.get_checked_out_items()?;
+        // Open loans carry the time of the check-out that started them, even if
+        // the item was scanned out again later
+        let open_loans = self.db.with(|db| db.get_open_loans())?;
         let now = Local::now();
-        let threshold = Duration::hours(self.threshold_hours);
         
         let mut overdue_items = Vec::new();
         
-        for item in checked_out_items {
-            let time_out = now.signed_duration_since(item.timestamp);
-            if time_out > threshold {
+        for loan in open_loans {
+            let (threshold_hours, rule) = self.threshold_for(&loan.barcode);
+            // Thresholds stored before they were validated may be out of range
+            let limits = Some(threshold_hours).filter(|hours| *hours > 0).and_then(|hours| {
+                let warning = Duration::try_hours(hours)?;
+                let critical = Duration::try_hours(hours.checked_mul(CRITICAL_MULTIPLIER)?)?;
+                Some((warning, critical))
+            });
+            let Some((warning, critical)) = limits else {
+                eprintln!("Skipping {}: invalid overdue threshold of {} hours", loan.barcode, threshold_hours);
+                continue;
+            };
+            // Only opening hours count when a working calendar is set up
+            let raw_time_out = now.signed_duration_since(loan.out_time);
+            let time_out = self.calendar.working_time(loan.out_time, now);
+            if time_out > warning {
+                let level = if time_out > critical {
+                    AlertLevel::Critical
+                } else {
+                    AlertLevel::Warning
+                };
                 overdue_items.push(OverdueItem {
-                    barcode: item.barcode,
-                    department: item.department,
-                    checked_out_time: item.timestamp.into(),
+                    loan_id: loan.id,
+                    barcode: loan.barcode,
+                    department: loan.department,
+                    checked_out_time: loan.out_time.into(),
+                    checked_out_station: loan.out_station,
                     hours_overdue: time_out.num_hours(),
+                    raw_hours: raw_time_out.num_hours(),
+                    threshold_hours,
+                    rule,
+                    level,
                 });
             }
         }
@@ -42,38 +141,46 @@ impl AlertManager {
         Ok(overdue_items)
     }
 
-    pub fn send_overdue_alerts(&self, app_handle: &AppHandle) -> Result<(), Box<dyn std::error::Error>> {
+    /// Brings the stored alerts in line with what is overdue now and tells the
+    /// UI only about alerts that are new, escalated or back from a snooze.
+    /// Returns how many alerts were sent.
+    pub fn send_overdue_alerts(&self, app_handle: &AppHandle, webhooks: &WebhookDispatcher) -> Result<usize, Box<dyn std::error::Error>> {
         let overdue_items = self.check_overdue_items()?;
+        let alerts = self.db.with(|db| db.sync_alerts(&overdue_items))?;
         
-        if !overdue_items.is_empty() {
+        if !alerts.is_empty() {
+            let items: Vec<&OverdueItem> = overdue_items
+                .iter()
+                .filter(|item| alerts.iter().any(|alert| alert.loan_id == item.loan_id))
+                .collect();
             let alert_data = serde_json::json!({
                 "type": "overdue_items",
-                "count": overdue_items.len(),
-                "items": overdue_items,
+                "count": alerts.len(),
+                "items": items,
+                "alerts": alerts,
                 "threshold_hours": self.threshold_hours
             });
             
-            let _ = app_handle.emit("overdue-alert", alert_data);
-            println!("Sent overdue alert for {} items", overdue_items.len());
+            let _ = app_handle.emit("overdue-alert", &alert_data);
+            webhooks.publish(&self.db, "overdue-alert", &alert_data);
+            println!("Sent overdue alert for {} items", alerts.len());
         }
+
+        // Email is best effort; a mail server being down shouldn't fail the check
+        let emailed = EmailNotifier::new(&self.db)
+            .map_err(|e| e.into())
+            .and_then(|notifier| notifier.send_digests(&self.db, &overdue_items));
+        match emailed {
+            Ok(0) => {}
+            Ok(sent) => println!("Emailed {} overdue digests", sent),
+            Err(e) => eprintln!("Failed to email overdue digests: {}", e),
+        }
+
+        // Sent after every check so the tray can follow resolved alerts too
+        let summary = self.db.with(|db| db.get_alert_summary())?;
+        let _ = app_handle.emit("overdue-summary", summary);
         
-        Ok(())
-    }
-
-    pub fn start_periodic_checks(app_handle: AppHandle) -> Result<(), Box<dyn std::error::Error>> {
-        std::thread::spawn(move || {
-            loop {
-                std::thread::sleep(std::time::Duration::from_secs(3600)); // Check every hour
-                
-                if let Ok(alert_manager) = AlertManager::new() {
-                    if let Err(e) = alert_manager.send_overdue_alerts(&app_handle) {
-                        eprintln!("Error sending overdue alerts: {}", e);
-                    }
-                }
-            }
-        });
-        
-        Ok(())
+        Ok(alerts.len())
     }
 
     pub fn get_department_alert_stats(&self) -> Result<Vec<DepartmentAlert>, rusqlite::Error> {
@@ -97,12 +204,255 @@ impl AlertManager {
     }
 }
 
-#[derive(Debug, Clone, serde::Serialize)]
+/// Runs the overdue check in the background: once at startup, then every
+/// `alert_check_interval_minutes`, and straight away when asked to after a
+/// change that affects what is overdue
+pub struct AlertScheduler {
+    wake: Mutex<Option<Sender<()>>>,
+    status: Arc<Mutex<SchedulerStatus>>,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct SchedulerStatus {
+    pub interval_minutes: i64,
+    pub last_run: Option<DateTime<Local>>,
+    pub next_run: Option<DateTime<Local>>,
+    /// Alerts sent by the last check
+    pub last_alert_count: usize,
+    pub last_error: Option<String>,
+}
+
+impl AlertScheduler {
+    pub fn new() -> Self {
+        AlertScheduler {
+            wake: Mutex::new(None),
+            status: Arc::new(Mutex::new(SchedulerStatus::default())),
+        }
+    }
+
+    pub fn start(&self, app_handle: AppHandle, db: DbService, webhooks: Arc<WebhookDispatcher>) {
+        let (wake, woken) = mpsc::channel();
+        if let Ok(mut sender) = self.wake.lock() {
+            *sender = Some(wake);
+        }
+        let status = Arc::clone(&self.status);
+
+        std::thread::spawn(move || loop {
+            // Re-read each round so interval and threshold changes are picked up
+            let interval_minutes = check_interval_minutes(&db);
+            let result = AlertManager::new(db.clone())
+                .map_err(|e| e.into())
+                .and_then(|alert_manager| alert_manager.send_overdue_alerts(&app_handle, &webhooks));
+            if let Err(e) = &result {
+                eprintln!("Error sending overdue alerts: {}", e);
+            }
+
+            let now = Local::now();
+            if let Ok(mut status) = status.lock() {
+                status.interval_minutes = interval_minutes;
+                status.last_run = Some(now);
+                status.next_run = Some(now + Duration::minutes(interval_minutes));
+                status.last_alert_count = *result.as_ref().unwrap_or(&0);
+                status.last_error = result.err().map(|e| e.to_string());
+            }
+
+            match woken.recv_timeout(std::time::Duration::from_secs(interval_minutes as u64 * 60)) {
+                // Several changes in a row only need one check
+                Ok(()) => while woken.try_recv().is_ok() {},
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        });
+    }
+
+    /// Runs a check now instead of waiting for the next one
+    pub fn request_check(&self) {
+        if let Some(wake) = self.wake.lock().ok().and_then(|wake| wake.clone()) {
+            let _ = wake.send(());
+        }
+    }
+
+    pub fn status(&self) -> SchedulerStatus {
+        self.status.lock().map(|status| status.clone()).unwrap_or_default()
+    }
+}
+
+impl Default for AlertScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn check_interval_minutes(db: &DbService) -> i64 {
+    db.with(|db| db.get_setting("alert_check_interval_minutes"))
+        .ok()
+        .flatten()
+        .and_then(|value| value.parse().ok())
+        .filter(|minutes: &i64| *minutes > 0)
+        // A huge value would overflow the timeout below; written before set_settings validated it
+        .map(|minutes| minutes.min(MAX_CHECK_INTERVAL_MINUTES))
+        .unwrap_or(DEFAULT_CHECK_INTERVAL_MINUTES)
+}
+
+/// Validates a new `alert_check_interval_minutes` value
+pub fn parse_check_interval(value: &str) -> Result<i64, String> {
+    match value.trim().parse::<i64>() {
+        Ok(minutes) if (1..=MAX_CHECK_INTERVAL_MINUTES).contains(&minutes) => Ok(minutes),
+        _ => Err(format!(
+            "Check interval must be a whole number of minutes between 1 and {}",
+            MAX_CHECK_INTERVAL_MINUTES
+        )),
+    }
+}
+
+fn threshold_error() -> String {
+    format!(
+        "Overdue threshold must be a whole number of hours between 1 and {}",
+        MAX_THRESHOLD_HOURS
+    )
+}
+
+/// Validates an overdue threshold in hours, for the per-department and
+/// per-item overrides
+pub fn validate_threshold_hours(hours: i64) -> Result<i64, String> {
+    if (1..=MAX_THRESHOLD_HOURS).contains(&hours) {
+        Ok(hours)
+    } else {
+        Err(threshold_error())
+    }
+}
+
+/// Validates a new `alert_threshold_hours` value
+pub fn parse_threshold_hours(value: &str) -> Result<i64, String> {
+    value
+        .trim()
+        .parse::<i64>()
+        .map_err(|_| threshold_error())
+        .and_then(validate_threshold_hours)
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct OverdueItem {
+    pub loan_id: i64,
     pub barcode: String,
     pub department: Option<String>,
     pub checked_out_time: chrono::DateTime<chrono::Utc>,
+    pub checked_out_station: Option<String>,
+    /// Hours out, counting opening hours only when a working calendar is set
     pub hours_overdue: i64,
+    /// Wall-clock hours since check-out
+    pub raw_hours: i64,
+    pub threshold_hours: i64,
+    /// The rule `threshold_hours` came from
+    pub rule: ThresholdRule,
+    pub level: AlertLevel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertLevel {
+    /// Out longer than its threshold
+    Warning,
+    /// Out longer than `CRITICAL_MULTIPLIER` times its threshold
+    Critical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertState {
+    New,
+    Acknowledged,
+    /// Quiet until `snoozed_until`, then new again
+    Snoozed,
+    /// The loan closed or is no longer overdue
+    Resolved,
+}
+
+/// Stored alert for one overdue loan
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Alert {
+    pub id: i64,
+    pub loan_id: i64,
+    pub barcode: String,
+    pub department: Option<String>,
+    pub level: AlertLevel,
+    pub state: AlertState,
+    pub threshold_hours: i64,
+    pub rule: Option<ThresholdRule>,
+    pub created_at: DateTime<Local>,
+    pub updated_at: DateTime<Local>,
+    pub escalated_at: Option<DateTime<Local>>,
+    pub acknowledged_at: Option<DateTime<Local>>,
+    pub acknowledged_by: Option<String>,
+    pub snoozed_until: Option<DateTime<Local>>,
+    pub snoozed_by: Option<String>,
+    pub resolved_at: Option<DateTime<Local>>,
+    /// Last time the alert went out by email, and at which level
+    pub emailed_at: Option<DateTime<Local>>,
+    pub emailed_level: Option<AlertLevel>,
+}
+
+impl AlertLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlertLevel::Warning => "warning",
+            AlertLevel::Critical => "critical",
+        }
+    }
+}
+
+impl AlertState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlertState::New => "new",
+            AlertState::Acknowledged => "acknowledged",
+            AlertState::Snoozed => "snoozed",
+            AlertState::Resolved => "resolved",
+        }
+    }
+}
+
+impl ToSql for AlertLevel {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
+    }
+}
+
+impl FromSql for AlertLevel {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "warning" => Ok(AlertLevel::Warning),
+            "critical" => Ok(AlertLevel::Critical),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+impl ToSql for AlertState {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
+    }
+}
+
+impl FromSql for AlertState {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "new" => Ok(AlertState::New),
+            "acknowledged" => Ok(AlertState::Acknowledged),
+            "snoozed" => Ok(AlertState::Snoozed),
+            "resolved" => Ok(AlertState::Resolved),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// Open (unresolved) alerts, for the tray badge and tooltip
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct AlertSummary {
+    pub open: i64,
+    pub critical: i64,
+    /// Not acknowledged or snoozed yet
+    pub new: i64,
 }
 
 #[derive(Debug, Clone, serde::Serialize)]
@@ -111,3 +461,97 @@ pub struct DepartmentAlert {
     pub overdue_count: i64,
     pub oldest_hours: i64,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tests::test_db;
+
+    #[test]
+    fn threshold_precedence_is_item_then_department_then_global() {
+        let db = test_db("alert-thresholds");
+        db.set_setting("alert_threshold_hours", "24", None).unwrap();
+        // Prefixes are stored as typed
+        db.set_department_mapping("hjärt", "Hjärtkirurgi", None).unwrap();
+        db.set_department_threshold("hjärt", Some(12), None).unwrap();
+        db.set_department_mapping("HJÄRTX", "Hjärtkirurgi sterilt", None).unwrap();
+        db.set_department_threshold("HJÄRTX", Some(6), None).unwrap();
+        db.add_item("hjärtx007", Some("Hjärtkirurgi"), None, None).unwrap();
+        db.set_item_threshold("hjärtx007", Some(2), None).unwrap();
+
+        let manager = AlertManager::new(DbService::from_database(db)).unwrap();
+        assert_eq!(
+            manager.threshold_for("HJÄRTX007"),
+            (2, ThresholdRule::Item { barcode: "HJÄRTX007".to_string() })
+        );
+        // The longest matching prefix wins
+        assert_eq!(
+            manager.threshold_for("hjärtx123"),
+            (
+                6,
+                ThresholdRule::Department {
+                    prefix: "HJÄRTX".to_string(),
+                    department: "Hjärtkirurgi sterilt".to_string()
+                }
+            )
+        );
+        assert_eq!(
+            manager.threshold_for("HJÄRT123"),
+            (
+                12,
+                ThresholdRule::Department {
+                    prefix: "hjärt".to_string(),
+                    department: "Hjärtkirurgi".to_string()
+                }
+            )
+        );
+        // ORTX has a mapping but no threshold of its own
+        assert_eq!(manager.threshold_for("ORTX1"), (24, ThresholdRule::Global));
+    }
+
+    #[test]
+    fn check_interval_is_kept_within_a_week() {
+        assert_eq!(parse_check_interval("15"), Ok(15));
+        assert_eq!(parse_check_interval(" 10080 "), Ok(MAX_CHECK_INTERVAL_MINUTES));
+        assert!(parse_check_interval("0").is_err());
+        assert!(parse_check_interval("10081").is_err());
+        assert!(parse_check_interval("9999999999999").is_err());
+        assert!(parse_check_interval("hourly").is_err());
+
+        let db = test_db("alert-interval");
+        // Stored before the command validated it
+        db.set_setting("alert_check_interval_minutes", "99999999", None).unwrap();
+        let db = DbService::from_database(db);
+        assert_eq!(check_interval_minutes(&db), MAX_CHECK_INTERVAL_MINUTES);
+        db.with(|db| db.set_setting("alert_check_interval_minutes", "-5", None)).unwrap();
+        assert_eq!(check_interval_minutes(&db), DEFAULT_CHECK_INTERVAL_MINUTES);
+    }
+
+    #[test]
+    fn out_of_range_thresholds_are_rejected_and_skipped() {
+        assert_eq!(validate_threshold_hours(1), Ok(1));
+        assert_eq!(parse_threshold_hours(" 8760 "), Ok(MAX_THRESHOLD_HOURS));
+        assert!(validate_threshold_hours(0).is_err());
+        assert!(validate_threshold_hours(-3).is_err());
+        assert!(validate_threshold_hours(MAX_THRESHOLD_HOURS + 1).is_err());
+        assert!(parse_threshold_hours("a day").is_err());
+
+        let db = test_db("alert-threshold-range");
+        let out = Local::now() - Duration::hours(50);
+        for barcode in ["ORTX1", "ORTX2", "ORTX3"] {
+            db.log_scan_at(barcode, "check-out", Some("Ortopedi"), out).unwrap();
+        }
+        // Stored before the commands validated them
+        db.set_item_threshold("ORTX1", Some(i64::MAX / 2), None).unwrap();
+        db.set_item_threshold("ORTX2", Some(0), None).unwrap();
+
+        let manager = AlertManager::new(DbService::from_database(db)).unwrap();
+        let overdue: Vec<(String, AlertLevel)> = manager
+            .check_overdue_items()
+            .unwrap()
+            .into_iter()
+            .map(|item| (item.barcode, item.level))
+            .collect();
+        assert_eq!(overdue, vec![("ORTX3".to_string(), AlertLevel::Critical)]);
+    }
+}
@@ -0,0 +1,219 @@
+//! Utilization and turnaround figures over a period, computed from loans.
+//!
+//! Management uses these to decide how many duplicate sets to buy, so the
+//! results are plain structures that the UI can chart directly and the
+//! exporter can write out one report per CSV.
+use crate::database::{InventoryItem, Loan};
+use chrono::{DateTime, Datelike, Local, Timelike};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// Items listed in the most/least used rankings unless asked otherwise
+pub const DEFAULT_TOP_ITEMS: usize = 10;
+
+/// Time-out figures for a group of completed loans, in minutes
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TurnaroundStats {
+    pub key: String,
+    pub loans: i64,
+    /// Loans in the group still out; they have no duration yet
+    pub open_loans: i64,
+    pub average_minutes: Option<f64>,
+    pub median_minutes: Option<i64>,
+    pub p90_minutes: Option<i64>,
+    pub max_minutes: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeriodCount {
+    /// "2024-03-01" for days, "2024-W09" for ISO weeks
+    pub period: String,
+    pub loans: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HourCount {
+    /// Local hour of the day, 0-23
+    pub hour: u32,
+    pub check_outs: i64,
+    pub check_ins: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ItemUsage {
+    pub barcode: String,
+    pub description: Option<String>,
+    pub department: Option<String>,
+    pub loans: i64,
+    pub total_minutes_out: i64,
+    /// Latest check-out, looking at the period only
+    pub last_used: Option<DateTime<Local>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Analytics {
+    pub from: Option<DateTime<Local>>,
+    pub to: Option<DateTime<Local>>,
+    pub by_department: Vec<TurnaroundStats>,
+    pub by_item: Vec<TurnaroundStats>,
+    pub loans_per_day: Vec<PeriodCount>,
+    pub loans_per_week: Vec<PeriodCount>,
+    /// All 24 hours, busiest first
+    pub busiest_hours: Vec<HourCount>,
+    pub most_used: Vec<ItemUsage>,
+    /// Items lent at least once, least first; unused ones are in `idle`
+    pub least_used: Vec<ItemUsage>,
+    /// Catalogue items not checked out at all during the period
+    pub idle: Vec<ItemUsage>,
+}
+
+/// One table of [`Analytics`], for exporting
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnalyticsReport {
+    Departments,
+    Items,
+    Days,
+    Weeks,
+    Hours,
+    Usage,
+    Idle,
+}
+
+impl Analytics {
+    /// `loans` are the loans that started in [from, to); `catalogue` is the
+    /// full item catalogue, used for descriptions and idle stock
+    pub fn compute(
+        loans: &[Loan],
+        catalogue: &[InventoryItem],
+        from: Option<DateTime<Local>>,
+        to: Option<DateTime<Local>>,
+        top: usize,
+    ) -> Self {
+        let by_department = turnaround_by(loans, |loan| {
+            loan.department.clone().unwrap_or_else(|| "Unknown".to_string())
+        });
+        let by_item = turnaround_by(loans, |loan| loan.barcode.clone());
+
+        let mut days: BTreeMap<String, i64> = BTreeMap::new();
+        let mut weeks: BTreeMap<String, i64> = BTreeMap::new();
+        let mut hours: Vec<HourCount> = (0..24)
+            .map(|hour| HourCount { hour, check_outs: 0, check_ins: 0 })
+            .collect();
+        for loan in loans {
+            *days.entry(loan.out_time.format("%Y-%m-%d").to_string()).or_insert(0) += 1;
+            let week = loan.out_time.iso_week();
+            *weeks.entry(format!("{}-W{:02}", week.year(), week.week())).or_insert(0) += 1;
+            hours[loan.out_time.hour() as usize].check_outs += 1;
+            // Check-ins after the period still belong to loans made in it
+            if let Some(in_time) = loan.in_time.filter(|in_time| to.is_none_or(|to| *in_time < to)) {
+                hours[in_time.hour() as usize].check_ins += 1;
+            }
+        }
+        let loans_per_day = into_counts(days);
+        let loans_per_week = into_counts(weeks);
+        hours.sort_by(|a, b| (b.check_outs + b.check_ins).cmp(&(a.check_outs + a.check_ins)).then(a.hour.cmp(&b.hour)));
+
+        let catalogue: HashMap<&str, &InventoryItem> = catalogue.iter().map(|item| (item.barcode.as_str(), item)).collect();
+        let mut usage: HashMap<String, ItemUsage> = HashMap::new();
+        for loan in loans {
+            let entry = usage.entry(loan.barcode.clone()).or_insert_with(|| {
+                let item = catalogue.get(loan.barcode.as_str());
+                ItemUsage {
+                    barcode: loan.barcode.clone(),
+                    description: item.and_then(|item| item.description.clone()),
+                    department: item.and_then(|item| item.department.clone()).or_else(|| loan.department.clone()),
+                    loans: 0,
+                    total_minutes_out: 0,
+                    last_used: None,
+                }
+            });
+            entry.loans += 1;
+            entry.total_minutes_out += loan.duration_minutes.unwrap_or(0);
+            entry.last_used = entry.last_used.max(Some(loan.out_time));
+        }
+        let mut idle: Vec<ItemUsage> = catalogue
+            .values()
+            .filter(|item| !usage.contains_key(&item.barcode))
+            .map(|item| ItemUsage {
+                barcode: item.barcode.clone(),
+                description: item.description.clone(),
+                department: item.department.clone(),
+                loans: 0,
+                total_minutes_out: 0,
+                last_used: None,
+            })
+            .collect();
+        idle.sort_by(|a, b| a.barcode.cmp(&b.barcode));
+
+        let mut used: Vec<ItemUsage> = usage.into_values().collect();
+        used.sort_by(|a, b| {
+            b.loans
+                .cmp(&a.loans)
+                .then(b.total_minutes_out.cmp(&a.total_minutes_out))
+                .then(a.barcode.cmp(&b.barcode))
+        });
+        let most_used: Vec<ItemUsage> = used.iter().take(top).cloned().collect();
+        let least_used: Vec<ItemUsage> = used.iter().rev().take(top).cloned().collect();
+
+        Analytics {
+            from,
+            to,
+            by_department,
+            by_item,
+            loans_per_day,
+            loans_per_week,
+            busiest_hours: hours,
+            most_used,
+            least_used,
+            idle,
+        }
+    }
+}
+
+/// Turnaround per group, most loans first
+fn turnaround_by(loans: &[Loan], key: impl Fn(&Loan) -> String) -> Vec<TurnaroundStats> {
+    let mut groups: HashMap<String, (i64, Vec<i64>)> = HashMap::new();
+    for loan in loans {
+        let group = groups.entry(key(loan)).or_default();
+        match loan.duration_minutes {
+            Some(minutes) => group.1.push(minutes),
+            None => group.0 += 1,
+        }
+    }
+
+    let mut stats: Vec<TurnaroundStats> = groups
+        .into_iter()
+        .map(|(key, (open_loans, mut minutes))| {
+            minutes.sort_unstable();
+            TurnaroundStats {
+                key,
+                loans: open_loans + minutes.len() as i64,
+                open_loans,
+                average_minutes: (!minutes.is_empty())
+                    .then(|| minutes.iter().sum::<i64>() as f64 / minutes.len() as f64),
+                median_minutes: percentile(&minutes, 50.0),
+                p90_minutes: percentile(&minutes, 90.0),
+                max_minutes: minutes.last().copied(),
+            }
+        })
+        .collect();
+    stats.sort_by(|a, b| b.loans.cmp(&a.loans).then_with(|| a.key.cmp(&b.key)));
+    stats
+}
+
+/// Nearest-rank percentile of sorted values
+fn percentile(sorted: &[i64], percent: f64) -> Option<i64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+fn into_counts(counts: BTreeMap<String, i64>) -> Vec<PeriodCount> {
+    counts
+        .into_iter()
+        .map(|(period, loans)| PeriodCount { period, loans })
+        .collect()
+}
@@ -0,0 +1,339 @@
+//! Yearly archive files for history moved out of the live database.
+//!
+//! Old scans and closed loans are never deleted; they are moved into
+//! `data/archive/inventory_<year>.db` next to the shared database. Each archive
+//! is a plain SQLite file with the same `logs` and `loans` columns as the live
+//! database, so it can be opened by the history and export commands (or any
+//! SQLite tool) long after the rows left the live file.
+use crate::database::{loan_from_row, normalize_barcode, scan_log_from_row, Loan, ScanLog, LOAN_COLUMNS, LOG_COLUMNS};
+use chrono::{Datelike, Local, TimeZone};
+use rusqlite::{params, Connection, OpenFlags, Result};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// Schema name the current year's archive is attached under while moving rows
+pub const ATTACHED_SCHEMA: &str = "archive";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchiveFile {
+    pub year: i32,
+    pub path: PathBuf,
+    pub log_count: i64,
+    pub loan_count: i64,
+}
+
+pub fn archive_dir(db_path: &Path) -> PathBuf {
+    db_path
+        .parent()
+        .unwrap_or(Path::new("."))
+        .join("archive")
+}
+
+pub fn archive_path(db_path: &Path, year: i32) -> PathBuf {
+    archive_dir(db_path).join(format!("inventory_{}.db", year))
+}
+
+/// Local-time [start, end) of a calendar year in database time (UTC ms)
+pub fn year_bounds(year: i32) -> (i64, i64) {
+    let start = |year| {
+        Local
+            .with_ymd_and_hms(year, 1, 1, 0, 0, 0)
+            .earliest()
+            .map(|time| time.timestamp_millis())
+            .unwrap_or(i64::MIN)
+    };
+    (start(year), start(year + 1))
+}
+
+/// Calendar year (local time) a database timestamp falls in
+pub fn year_of(millis: i64) -> Option<i32> {
+    crate::database::from_db_time(millis).map(|time| time.year())
+}
+
+/// Creates the archive tables in `schema` if they don't exist yet, and adds
+/// columns introduced since the archive was written
+pub fn ensure_schema(conn: &Connection, schema: &str) -> Result<()> {
+    conn.execute_batch(&format!(
+        "PRAGMA {schema}.synchronous = FULL;
+
+        CREATE TABLE IF NOT EXISTS {schema}.logs (
+            id INTEGER PRIMARY KEY,
+            timestamp INTEGER NOT NULL,
+            barcode TEXT NOT NULL,
+            action TEXT NOT NULL,
+            department TEXT,
+            station TEXT,
+            voided_at INTEGER,
+            voided_by TEXT,
+            void_reason TEXT
+        );
+        CREATE INDEX IF NOT EXISTS {schema}.idx_logs_barcode_timestamp ON logs (barcode, timestamp);
+        CREATE INDEX IF NOT EXISTS {schema}.idx_logs_timestamp ON logs (timestamp);
+
+        CREATE TABLE IF NOT EXISTS {schema}.loans (
+            id INTEGER PRIMARY KEY,
+            barcode TEXT NOT NULL,
+            department TEXT,
+            out_log_id INTEGER NOT NULL,
+            out_time INTEGER NOT NULL,
+            out_station TEXT,
+            in_log_id INTEGER,
+            in_time INTEGER,
+            in_station TEXT
+        );
+        CREATE INDEX IF NOT EXISTS {schema}.idx_loans_barcode_out_time ON loans (barcode, out_time);
+        CREATE INDEX IF NOT EXISTS {schema}.idx_loans_out_time ON loans (out_time);"
+    ))?;
+
+    // Archives written before scans could be voided
+    for column in ["voided_at INTEGER", "voided_by TEXT", "void_reason TEXT"] {
+        let name = column.split(' ').next().unwrap_or(column);
+        let exists: bool = conn.query_row(
+            &format!("SELECT COUNT(*) FROM pragma_table_info('logs', '{schema}') WHERE name = ?1"),
+            params![name],
+            |row| row.get::<_, i64>(0).map(|count| count > 0),
+        )?;
+        if !exists {
+            conn.execute(&format!("ALTER TABLE {schema}.logs ADD COLUMN {column}"), [])?;
+        }
+    }
+
+    // Archives written before scanned barcodes were stored upper-case
+    let version: i32 = conn.query_row(&format!("PRAGMA {schema}.user_version"), [], |row| row.get(0))?;
+    if version < 1 {
+        for table in ["logs", "loans"] {
+            let barcodes: Vec<String> = {
+                let mut stmt = conn.prepare(&format!("SELECT DISTINCT barcode FROM {schema}.{table}"))?;
+                let rows = stmt.query_map([], |row| row.get(0))?;
+                rows.collect::<Result<_>>()?
+            };
+            for barcode in barcodes {
+                let normalized = normalize_barcode(&barcode);
+                if normalized != barcode {
+                    conn.execute(
+                        &format!("UPDATE {schema}.{table} SET barcode = ?2 WHERE barcode = ?1"),
+                        params![barcode, normalized],
+                    )?;
+                }
+            }
+        }
+        conn.execute_batch(&format!("PRAGMA {schema}.user_version = 1"))?;
+    }
+    Ok(())
+}
+
+/// Every archive file next to the database, oldest year first
+pub fn list_archives(db_path: &Path) -> Result<Vec<ArchiveFile>> {
+    let mut archives = Vec::new();
+    for (year, conn) in open_archives(db_path)? {
+        let log_count = conn.query_row("SELECT COUNT(*) FROM logs", [], |row| row.get(0))?;
+        let loan_count = conn.query_row("SELECT COUNT(*) FROM loans", [], |row| row.get(0))?;
+        archives.push(ArchiveFile {
+            year,
+            path: archive_path(db_path, year),
+            log_count,
+            loan_count,
+        });
+    }
+    Ok(archives)
+}
+
+/// Years that have an archive file, oldest first
+pub fn archive_years(db_path: &Path) -> Vec<i32> {
+    let mut years: Vec<i32> = std::fs::read_dir(archive_dir(db_path))
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    name.strip_prefix("inventory_")?.strip_suffix(".db")?.parse().ok()
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    years.sort_unstable();
+    years
+}
+
+/// Opens every archive read-only, oldest year first. Archives are only
+/// written (and upgraded) by the archiving run, see [`upgrade_archives`].
+pub fn open_archives(db_path: &Path) -> Result<Vec<(i32, Connection)>> {
+    archive_years(db_path)
+        .into_iter()
+        .map(|year| {
+            let conn = Connection::open_with_flags(
+                archive_path(db_path, year),
+                OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )?;
+            conn.busy_timeout(std::time::Duration::from_millis(1000))?;
+            Ok((year, conn))
+        })
+        .collect()
+}
+
+/// Brings every existing archive up to the current archive schema
+pub fn upgrade_archives(db_path: &Path) -> Result<()> {
+    for year in archive_years(db_path) {
+        let conn = Connection::open_with_flags(
+            archive_path(db_path, year),
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        conn.busy_timeout(std::time::Duration::from_millis(1000))?;
+        ensure_schema(&conn, "main")?;
+    }
+    Ok(())
+}
+
+/// Most recent archived scans across all years, newest first
+pub fn read_logs(db_path: &Path, limit: Option<i64>, include_voided: bool) -> Result<Vec<ScanLog>> {
+    let mut logs = Vec::new();
+    // Newest archive first so a limit can stop early
+    for (_, conn) in open_archives(db_path)?.into_iter().rev() {
+        let remaining = limit.map(|limit| limit - logs.len() as i64);
+        if remaining.is_some_and(|remaining| remaining <= 0) {
+            break;
+        }
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM logs WHERE ?2 OR voided_at IS NULL
+             ORDER BY timestamp DESC, id DESC LIMIT ?1",
+            LOG_COLUMNS
+        ))?;
+        let rows = stmt.query_map(params![remaining.unwrap_or(-1), include_voided], scan_log_from_row)?;
+        for log in rows {
+            logs.push(log?);
+        }
+    }
+    Ok(logs)
+}
+
+/// All archived loans of one item, oldest first
+pub fn read_item_loans(db_path: &Path, barcode: &str) -> Result<Vec<Loan>> {
+    let mut loans = Vec::new();
+    for (_, conn) in open_archives(db_path)? {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM loans WHERE barcode = ?1 ORDER BY out_time, id",
+            LOAN_COLUMNS
+        ))?;
+        let rows = stmt.query_map(params![barcode], loan_from_row)?;
+        for loan in rows {
+            loans.push(loan?);
+        }
+    }
+    Ok(loans)
+}
+
+/// Archived loans that were open at `at` (database time), every year
+pub fn read_loans_open_at(db_path: &Path, at: i64) -> Result<Vec<Loan>> {
+    let mut loans = Vec::new();
+    for (_, conn) in open_archives(db_path)? {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM loans WHERE out_time <= ?1 AND (in_time IS NULL OR in_time > ?1)",
+            LOAN_COLUMNS
+        ))?;
+        let rows = stmt.query_map(params![at], loan_from_row)?;
+        for loan in rows {
+            loans.push(loan?);
+        }
+    }
+    Ok(loans)
+}
+
+/// Archived loans that started in [from, to) (database time), oldest year first
+pub fn read_loans_between(db_path: &Path, from: Option<i64>, to: Option<i64>) -> Result<Vec<Loan>> {
+    let mut loans = Vec::new();
+    for (year, conn) in open_archives(db_path)? {
+        // Loans are filed under the year they started in
+        let (start, end) = year_bounds(year);
+        if from.is_some_and(|from| from >= end) || to.is_some_and(|to| to <= start) {
+            continue;
+        }
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM loans
+             WHERE (?1 IS NULL OR out_time >= ?1) AND (?2 IS NULL OR out_time < ?2)
+             ORDER BY out_time, id",
+            LOAN_COLUMNS
+        ))?;
+        let rows = stmt.query_map(params![from, to], loan_from_row)?;
+        for loan in rows {
+            loans.push(loan?);
+        }
+    }
+    Ok(loans)
+}
+
+/// Most recent archived loans across all years, newest first
+pub fn read_loans(db_path: &Path, limit: Option<i64>) -> Result<Vec<Loan>> {
+    let mut loans = Vec::new();
+    for (_, conn) in open_archives(db_path)?.into_iter().rev() {
+        let remaining = limit.map(|limit| limit - loans.len() as i64);
+        if remaining.is_some_and(|remaining| remaining <= 0) {
+            break;
+        }
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM loans ORDER BY out_time DESC, id DESC LIMIT ?1",
+            LOAN_COLUMNS
+        ))?;
+        let rows = stmt.query_map(params![remaining.unwrap_or(-1)], loan_from_row)?;
+        for loan in rows {
+            loans.push(loan?);
+        }
+    }
+    Ok(loans)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tests::test_db_path;
+    use crate::database::Database;
+    use chrono::Duration;
+
+    fn has_void_columns(path: &Path) -> bool {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).unwrap();
+        conn.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('logs') WHERE name = 'voided_at'",
+            [],
+            |row| row.get::<_, i64>(0),
+        )
+        .unwrap()
+            > 0
+    }
+
+    #[test]
+    fn only_the_archiving_run_upgrades_archives() {
+        let db_path = test_db_path("archive-upgrade");
+        let db = Database::open_at(db_path.clone()).unwrap();
+
+        // An archive written before scans could be voided
+        let legacy = archive_path(&db_path, 2020);
+        std::fs::create_dir_all(archive_dir(&db_path)).unwrap();
+        Connection::open(&legacy)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE logs (id INTEGER PRIMARY KEY, timestamp INTEGER NOT NULL, barcode TEXT NOT NULL,
+                                    action TEXT NOT NULL, department TEXT, station TEXT);
+                 CREATE TABLE loans (id INTEGER PRIMARY KEY, barcode TEXT NOT NULL, department TEXT,
+                                     out_log_id INTEGER NOT NULL, out_time INTEGER NOT NULL, out_station TEXT,
+                                     in_log_id INTEGER, in_time INTEGER, in_station TEXT);
+                 INSERT INTO logs VALUES (1, 1583020800000, 'ortx7', 'check-out', 'Ortopedi', NULL);",
+            )
+            .unwrap();
+
+        let archives = db.list_archives().unwrap();
+        assert_eq!((archives[0].year, archives[0].log_count), (2020, 1));
+        assert!(!has_void_columns(&legacy), "reading must not alter an archive");
+
+        let out = Local::now() - Duration::days(400);
+        db.log_scan_at("ORTX1", "check-out", Some("Ortopedi"), out).unwrap();
+        db.log_scan_at("ORTX1", "check-in", Some("Ortopedi"), out + Duration::hours(2)).unwrap();
+        assert_eq!(db.archive_completed_transactions(30).unwrap(), 2);
+
+        assert!(has_void_columns(&legacy));
+        let archived: Vec<String> = read_logs(&db_path, None, false)
+            .unwrap()
+            .into_iter()
+            .map(|log| log.barcode)
+            .collect();
+        assert_eq!(archived, vec!["ORTX1", "ORTX1", "ORTX7"]);
+    }
+}
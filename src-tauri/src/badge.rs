@@ -0,0 +1,158 @@
+//! Tray icon variants with the overdue count drawn on top, so the count is
+//! visible without opening anything. Pure image work, no Tauri types.
+use image::{ImageResult, Rgba, RgbaImage};
+
+const WARNING_COLOUR: Rgba<u8> = Rgba([0xF5, 0x7C, 0x00, 0xFF]);
+const CRITICAL_COLOUR: Rgba<u8> = Rgba([0xD3, 0x2F, 0x2F, 0xFF]);
+const TEXT_COLOUR: Rgba<u8> = Rgba([0xFF, 0xFF, 0xFF, 0xFF]);
+
+/// 3x5 glyphs, one row per byte, most significant of the low three bits on the left
+const GLYPH_WIDTH: u32 = 3;
+const GLYPH_HEIGHT: u32 = 5;
+
+fn glyph(c: char) -> Option<[u8; 5]> {
+    Some(match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        _ => return None,
+    })
+}
+
+/// What the badge says: the count, or "9+" when it doesn't fit
+pub fn badge_text(count: i64) -> String {
+    if count > 9 {
+        "9+".to_string()
+    } else {
+        count.to_string()
+    }
+}
+
+/// `base_png` with a badge in the bottom-right corner: amber while all
+/// overdue items are warnings, red once any is critical. A count of zero
+/// returns the icon unchanged.
+pub fn overdue_badge(base_png: &[u8], count: i64, critical: bool) -> ImageResult<RgbaImage> {
+    let mut icon = image::load_from_memory(base_png)?.to_rgba8();
+    if count <= 0 {
+        return Ok(icon);
+    }
+
+    let size = icon.width().min(icon.height());
+    let scale = (size / 16).max(1);
+    let radius = (size as f32 * 0.32).max(4.0);
+    let centre_x = icon.width() as f32 - radius - 0.5;
+    let centre_y = icon.height() as f32 - radius - 0.5;
+    let colour = if critical { CRITICAL_COLOUR } else { WARNING_COLOUR };
+
+    for (x, y, pixel) in icon.enumerate_pixels_mut() {
+        let distance = ((x as f32 - centre_x).powi(2) + (y as f32 - centre_y).powi(2)).sqrt();
+        if distance <= radius - 1.0 {
+            *pixel = colour;
+        } else if distance <= radius {
+            // Light rim so the badge stands out on dark taskbars too
+            *pixel = TEXT_COLOUR;
+        }
+    }
+
+    let text = badge_text(count);
+    let glyphs: Vec<[u8; 5]> = text.chars().filter_map(glyph).collect();
+    let text_width = glyphs.len() as u32 * (GLYPH_WIDTH + 1) * scale - scale;
+    let text_height = GLYPH_HEIGHT * scale;
+    let left = (centre_x + 0.5 - text_width as f32 / 2.0).round().max(0.0) as u32;
+    let top = (centre_y + 0.5 - text_height as f32 / 2.0).round().max(0.0) as u32;
+    for (index, rows) in glyphs.iter().enumerate() {
+        let glyph_left = left + index as u32 * (GLYPH_WIDTH + 1) * scale;
+        for (row, bits) in rows.iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - column)) == 0 {
+                    continue;
+                }
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        let x = glyph_left + column * scale + dx;
+                        let y = top + row as u32 * scale + dy;
+                        if x < icon.width() && y < icon.height() {
+                            icon.put_pixel(x, y, TEXT_COLOUR);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(icon)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::ImageFormat;
+    use std::io::Cursor;
+
+    fn blank_icon(size: u32) -> Vec<u8> {
+        let mut png = Vec::new();
+        RgbaImage::new(size, size)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        png
+    }
+
+    fn count_pixels(icon: &RgbaImage, colour: Rgba<u8>) -> usize {
+        icon.pixels().filter(|pixel| **pixel == colour).count()
+    }
+
+    #[test]
+    fn badge_text_caps_at_nine_plus() {
+        let texts: Vec<String> = [0, 1, 9, 10, 99, 150].into_iter().map(badge_text).collect();
+        assert_eq!(texts, ["0", "1", "9", "9+", "9+", "9+"]);
+    }
+
+    #[test]
+    fn zero_leaves_the_icon_alone() {
+        let icon = overdue_badge(&blank_icon(32), 0, true).unwrap();
+        assert_eq!(icon.dimensions(), (32, 32));
+        assert!(icon.pixels().all(|pixel| pixel.0[3] == 0));
+    }
+
+    #[test]
+    fn draws_the_count_in_the_bottom_right_corner() {
+        for size in [16, 32, 64] {
+            let png = blank_icon(size);
+            let mut text_pixels = Vec::new();
+            for count in [1, 9, 10, 150] {
+                let icon = overdue_badge(&png, count, false).unwrap();
+                assert_eq!(icon.dimensions(), (size, size));
+                for (x, y, pixel) in icon.enumerate_pixels() {
+                    if pixel.0[3] != 0 {
+                        assert!(x >= size / 4 && y >= size / 4, "{} drawn at {},{} on a {}px icon", count, x, y, size);
+                    }
+                }
+                assert_eq!(icon.get_pixel(size - 1 - size / 4, size - 1 - size / 4).0[3], 0xFF);
+                assert!(count_pixels(&icon, WARNING_COLOUR) > 0);
+                assert_eq!(count_pixels(&icon, CRITICAL_COLOUR), 0);
+                text_pixels.push(count_pixels(&icon, TEXT_COLOUR));
+            }
+            // "9+" adds the plus sign; anything above nine looks the same
+            assert!(text_pixels[1] > text_pixels[0], "{:?}", text_pixels);
+            assert!(text_pixels[2] > text_pixels[1], "{:?}", text_pixels);
+            assert_eq!(text_pixels[2], text_pixels[3]);
+            assert_eq!(overdue_badge(&png, 10, false).unwrap(), overdue_badge(&png, 150, false).unwrap());
+        }
+    }
+
+    #[test]
+    fn critical_badges_are_red() {
+        let png = blank_icon(32);
+        let warning = overdue_badge(&png, 3, false).unwrap();
+        let critical = overdue_badge(&png, 3, true).unwrap();
+        assert_eq!(count_pixels(&critical, WARNING_COLOUR), 0);
+        assert_eq!(count_pixels(&critical, CRITICAL_COLOUR), count_pixels(&warning, WARNING_COLOUR));
+    }
+}
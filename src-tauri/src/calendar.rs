@@ -0,0 +1,131 @@
+//! Working calendar for overdue calculations.
+//!
+//! Opening hours are set per weekday and holidays are imported from a plain
+//! text file, one date per line. While no opening hours are configured every
+//! hour counts, which is how overdue time was always measured.
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// Opening hours for one weekday, as "HH:MM" local time. "24:00" closes at midnight.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpeningHours {
+    /// 0 = Monday ... 6 = Sunday
+    pub weekday: u32,
+    pub opens: String,
+    pub closes: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Holiday {
+    pub date: NaiveDate,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WorkingCalendar {
+    /// Open minutes [opens, closes) after midnight, by weekday
+    hours: HashMap<u32, (u32, u32)>,
+    holidays: HashSet<NaiveDate>,
+}
+
+/// Minutes after midnight for "HH:MM", allowing "24:00"
+pub fn parse_clock(text: &str) -> Option<u32> {
+    let (hours, minutes) = text.trim().split_once(':')?;
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    let total = hours * 60 + minutes;
+    (minutes < 60 && total <= 24 * 60).then_some(total)
+}
+
+pub fn format_clock(minutes: u32) -> String {
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+/// Reads a holiday file: one `YYYY-MM-DD` per line, optionally followed by a
+/// name after a comma, semicolon or whitespace. Blank lines and lines starting
+/// with `#` are skipped. Fails on the first line it can't read.
+pub fn parse_holidays(text: &str) -> Result<Vec<Holiday>, String> {
+    let mut holidays = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim().trim_start_matches('\u{feff}');
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (date, name) = match line.find([',', ';', ' ', '\t']) {
+            Some(split) => (&line[..split], line[split + 1..].trim()),
+            None => (line, ""),
+        };
+        let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .map_err(|e| format!("Line {}: invalid date '{}': {}", number + 1, date, e))?;
+        holidays.push(Holiday {
+            date,
+            name: (!name.is_empty()).then(|| name.to_string()),
+        });
+    }
+    Ok(holidays)
+}
+
+impl WorkingCalendar {
+    pub fn new(hours: &[OpeningHours], holidays: &[Holiday]) -> Self {
+        WorkingCalendar {
+            hours: hours
+                .iter()
+                .filter_map(|day| Some((day.weekday, (parse_clock(&day.opens)?, parse_clock(&day.closes)?))))
+                .filter(|(_, (opens, closes))| opens < closes)
+                .collect(),
+            holidays: holidays.iter().map(|holiday| holiday.date).collect(),
+        }
+    }
+
+    /// False while no opening hours are set, i.e. every hour counts
+    pub fn is_configured(&self) -> bool {
+        !self.hours.is_empty()
+    }
+
+    /// Time between `start` and `end` that falls inside opening hours
+    pub fn working_time(&self, start: DateTime<Local>, end: DateTime<Local>) -> Duration {
+        if end <= start {
+            return Duration::zero();
+        }
+        if !self.is_configured() {
+            return end.signed_duration_since(start);
+        }
+
+        let mut total = Duration::zero();
+        let mut date = start.date_naive();
+        while date <= end.date_naive() {
+            if let Some((opens, closes)) = self.open_on(date) {
+                let opens = at_minute(date, opens);
+                let closes = at_minute(date, closes);
+                let from = opens.max(start);
+                let to = closes.min(end);
+                if to > from {
+                    total += to.signed_duration_since(from);
+                }
+            }
+            date = match date.succ_opt() {
+                Some(next) => next,
+                None => break,
+            };
+        }
+        total
+    }
+
+    fn open_on(&self, date: NaiveDate) -> Option<(u32, u32)> {
+        if self.holidays.contains(&date) {
+            return None;
+        }
+        self.hours.get(&date.weekday().num_days_from_monday()).copied()
+    }
+}
+
+/// Local time `minutes` after midnight on `date`. Across a DST gap the
+/// earliest valid time is used.
+fn at_minute(date: NaiveDate, minutes: u32) -> DateTime<Local> {
+    let midnight = NaiveDateTime::new(date, NaiveTime::MIN);
+    let naive = midnight + Duration::minutes(minutes as i64);
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .unwrap_or_else(|| Local.from_utc_datetime(&naive))
+}
@@ -1,7 +1,18 @@
-use rusqlite::{params, Connection, Result};
-use chrono::{DateTime, Local,};
+use rusqlite::{ffi, params, Connection, OptionalExtension, Result, Row, Transaction, TransactionBehavior};
+use chrono::{DateTime, Local, NaiveDate, TimeZone, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::Duration;
+use crate::alert::{Alert, AlertState, AlertSummary, OverdueItem};
+use crate::analytics::Analytics;
+use crate::calendar::{format_clock, Holiday, OpeningHours, WorkingCalendar};
+use crate::archive::{self, ArchiveFile};
+use crate::history::ItemHistory;
+use crate::log_query::{LogPage, LogQuery, MAX_PAGE_SIZE};
+use crate::migrations::{self, MigrationReport};
+use crate::webhook::{self, OutboxEntry, WebhookTarget, WebhookTargetSettings};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ScanLog {
@@ -10,6 +21,35 @@ pub struct ScanLog {
     pub barcode: String,
     pub action: String, // "check-in" or "check-out"
     pub department: Option<String>,
+    /// Workstation that recorded the scan (unknown for scans made before v5)
+    pub station: Option<String>,
+    /// Set when the scan was voided as a mis-scan; voided scans don't count
+    /// towards an item's state, loans or statistics
+    pub voided_at: Option<DateTime<Local>>,
+    pub voided_by: Option<String>,
+    pub void_reason: Option<String>,
+}
+
+/// One check-out and, once the item is back, the check-in that ended it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Loan {
+    pub id: i64,
+    pub barcode: String,
+    pub department: Option<String>,
+    pub out_log_id: i64,
+    pub out_time: DateTime<Local>,
+    pub out_station: Option<String>,
+    pub in_log_id: Option<i64>,
+    pub in_time: Option<DateTime<Local>>,
+    pub in_station: Option<String>,
+    /// Minutes between check-out and check-in; `None` while the loan is open
+    pub duration_minutes: Option<i64>,
+}
+
+impl Loan {
+    pub fn is_open(&self) -> bool {
+        self.in_time.is_none()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -22,6 +62,12 @@ pub struct Settings {
 pub struct DepartmentMapping {
     pub prefix: String,
     pub department: String,
+    /// Overdue threshold for items under this prefix; `None` uses the global one
+    #[serde(default)]
+    pub alert_threshold_hours: Option<i64>,
+    /// Comma-separated addresses that get this department's overdue digest
+    #[serde(default)]
+    pub alert_emails: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,22 +75,373 @@ pub struct InventoryItem {
     pub barcode: String,
     pub department: Option<String>,
     pub description: Option<String>,
+    /// Overdue threshold for this item; `None` falls back to its department
+    #[serde(default)]
+    pub alert_threshold_hours: Option<i64>,
+}
+
+/// What was checked out at a given moment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckedOutSnapshot {
+    pub at: DateTime<Local>,
+    /// The check-out scan of each item that was out, newest first
+    pub items: Vec<ScanLog>,
+    /// (department, items out), most first
+    pub departments: Vec<(String, i64)>,
+}
+
+/// One change to settings, department mappings, the catalogue or the log.
+/// `before_value` and `after_value` hold JSON; `None` means "did not exist".
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub timestamp: DateTime<Local>,
+    pub station: Option<String>,
+    pub operator: Option<String>,
+    pub action: String,
+    pub entity: String,
+    pub entity_key: Option<String>,
+    pub before_value: Option<String>,
+    pub after_value: Option<String>,
+}
+
+/// Timestamps are stored as UTC milliseconds since the Unix epoch, so they sort
+/// and compare correctly across DST changes and between stations.
+pub fn to_db_time<Tz: TimeZone>(time: &DateTime<Tz>) -> i64 {
+    time.timestamp_millis()
+}
+
+pub fn from_db_time(millis: i64) -> Option<DateTime<Local>> {
+    Utc.timestamp_millis_opt(millis).single().map(|time| time.with_timezone(&Local))
+}
+
+/// Reads a timestamp column, failing loudly instead of inventing a time
+pub fn time_column(row: &Row, idx: usize) -> Result<DateTime<Local>> {
+    let millis: i64 = row.get(idx)?;
+    from_db_time(millis).ok_or(rusqlite::Error::IntegralValueOutOfRange(idx, millis))
+}
+
+/// Like [`time_column`] for a nullable column
+pub fn optional_time_column(row: &Row, idx: usize) -> Result<Option<DateTime<Local>>> {
+    match row.get::<_, Option<i64>>(idx)? {
+        Some(millis) => Ok(Some(from_db_time(millis).ok_or(rusqlite::Error::IntegralValueOutOfRange(idx, millis))?)),
+        None => Ok(None),
+    }
+}
+
+pub(crate) const LOG_COLUMNS: &str =
+    "id, timestamp, barcode, action, department, station, voided_at, voided_by, void_reason";
+
+/// Maps a row selected with `LOG_COLUMNS`
+pub(crate) fn scan_log_from_row(row: &Row) -> Result<ScanLog> {
+    Ok(ScanLog {
+        id: Some(row.get(0)?),
+        timestamp: time_column(row, 1)?,
+        barcode: row.get(2)?,
+        action: row.get(3)?,
+        department: row.get(4)?,
+        station: row.get(5)?,
+        voided_at: optional_time_column(row, 6)?,
+        voided_by: row.get(7)?,
+        void_reason: row.get(8)?,
+    })
+}
+
+pub(crate) const LOAN_COLUMNS: &str =
+    "id, barcode, department, out_log_id, out_time, out_station, in_log_id, in_time, in_station";
+
+/// Maps a row selected with `LOAN_COLUMNS`
+pub(crate) fn loan_from_row(row: &Row) -> Result<Loan> {
+    let out_time = time_column(row, 4)?;
+    let in_time = optional_time_column(row, 7)?;
+    Ok(Loan {
+        id: row.get(0)?,
+        barcode: row.get(1)?,
+        department: row.get(2)?,
+        out_log_id: row.get(3)?,
+        out_time,
+        out_station: row.get(5)?,
+        in_log_id: row.get(6)?,
+        in_time,
+        in_station: row.get(8)?,
+        duration_minutes: in_time.map(|in_time| in_time.signed_duration_since(out_time).num_minutes()),
+    })
+}
+
+fn audit_entry_from_row(row: &Row) -> Result<AuditEntry> {
+    Ok(AuditEntry {
+        id: row.get(0)?,
+        timestamp: time_column(row, 1)?,
+        station: row.get(2)?,
+        operator: row.get(3)?,
+        action: row.get(4)?,
+        entity: row.get(5)?,
+        entity_key: row.get(6)?,
+        before_value: row.get(7)?,
+        after_value: row.get(8)?,
+    })
+}
+
+const ALERT_COLUMNS: &str = "id, loan_id, barcode, department, level, state, threshold_hours, rule, created_at, \
+     updated_at, escalated_at, acknowledged_at, acknowledged_by, snoozed_until, snoozed_by, resolved_at, \
+     emailed_at, emailed_level";
+
+fn alert_from_row(row: &Row) -> Result<Alert> {
+    Ok(Alert {
+        id: row.get(0)?,
+        loan_id: row.get(1)?,
+        barcode: row.get(2)?,
+        department: row.get(3)?,
+        level: row.get(4)?,
+        state: row.get(5)?,
+        threshold_hours: row.get(6)?,
+        rule: row
+            .get::<_, Option<String>>(7)?
+            .and_then(|rule| serde_json::from_str(&rule).ok()),
+        created_at: time_column(row, 8)?,
+        updated_at: time_column(row, 9)?,
+        escalated_at: optional_time_column(row, 10)?,
+        acknowledged_at: optional_time_column(row, 11)?,
+        acknowledged_by: row.get(12)?,
+        snoozed_until: optional_time_column(row, 13)?,
+        snoozed_by: row.get(14)?,
+        resolved_at: optional_time_column(row, 15)?,
+        emailed_at: optional_time_column(row, 16)?,
+        emailed_level: row.get(17)?,
+    })
+}
+
+const WEBHOOK_TARGET_COLUMNS: &str = "id, name, url, secret, events, enabled, created_at";
+
+fn webhook_target_from_row(row: &Row) -> Result<WebhookTarget> {
+    let secret: Option<String> = row.get(3)?;
+    Ok(WebhookTarget {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        url: row.get(2)?,
+        has_secret: secret.is_some(),
+        secret,
+        events: row
+            .get::<_, String>(4)?
+            .split(',')
+            .map(str::trim)
+            .filter(|event| !event.is_empty() && *event != "*")
+            .map(str::to_string)
+            .collect(),
+        enabled: row.get(5)?,
+        created_at: time_column(row, 6)?,
+    })
+}
+
+const OUTBOX_COLUMNS: &str = "id, target_id, event, payload, created_at, attempts, next_attempt_at, last_attempt_at, \
+     last_status, last_error, delivered_at, failed_at";
+
+fn outbox_entry_from_row(row: &Row) -> Result<OutboxEntry> {
+    Ok(OutboxEntry {
+        id: row.get(0)?,
+        target_id: row.get(1)?,
+        event: row.get(2)?,
+        payload: row.get(3)?,
+        created_at: time_column(row, 4)?,
+        attempts: row.get(5)?,
+        next_attempt_at: time_column(row, 6)?,
+        last_attempt_at: optional_time_column(row, 7)?,
+        last_status: row.get(8)?,
+        last_error: row.get(9)?,
+        delivered_at: optional_time_column(row, 10)?,
+        failed_at: optional_time_column(row, 11)?,
+    })
+}
+
+fn to_audit_json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+/// Name of this workstation, recorded on every scan so multi-station setups
+/// can tell who checked what out. Falls back to "unknown".
+pub fn station_name() -> &'static str {
+    static STATION: OnceLock<String> = OnceLock::new();
+    STATION.get_or_init(|| {
+        ["COMPUTERNAME", "HOSTNAME"]
+            .iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .chain(std::fs::read_to_string("/etc/hostname").ok())
+            .map(|name| name.trim().to_string())
+            .find(|name| !name.is_empty())
+            .unwrap_or_else(|| "unknown".to_string())
+    })
+}
+
+/// Catalogue barcodes are stored trimmed and upper-case so that scanner input,
+/// manual entry and imports all land on the same row.
+pub fn normalize_barcode(barcode: &str) -> String {
+    barcode.trim().to_uppercase()
+}
+
+/// A check-out and the check-in (id, time, station) that ended it, if any
+struct LoanPair {
+    out_log_id: i64,
+    out_time: i64,
+    department: Option<String>,
+    out_station: Option<String>,
+    check_in: Option<(i64, i64, Option<String>)>,
 }
 
+/// Pairs `item`'s non-voided scans into loans by the rules `log_scan_at`
+/// follows, and makes the loans of `barcodes` (the spellings being merged
+/// into `item`, or just `item`) match. Loans keep their id when their
+/// check-out still opens one. Returns the ids of the loans removed.
+///
+/// A loan whose check-out was moved to an archive isn't re-paired or removed.
+/// The latest such loan that isn't closed by an archived check-in is carried
+/// on as the first pair instead: archiving goes by age, so its check-out came
+/// before every scan still in `logs`, and one of those may close it.
+pub(crate) fn repair_loans(conn: &Connection, item: &str, barcodes: &[&str]) -> Result<Vec<i64>> {
+    let barcodes = serde_json::json!(barcodes).to_string();
+    let mut pairs: Vec<LoanPair> = conn
+        .prepare_cached(
+            "SELECT out_log_id, out_time, department, out_station FROM loans
+             WHERE barcode IN (SELECT value FROM json_each(?1))
+               AND out_log_id NOT IN (SELECT id FROM logs)
+               AND (in_log_id IS NULL OR in_log_id IN (SELECT id FROM logs))
+             ORDER BY out_time DESC
+             LIMIT 1",
+        )?
+        .query_row(params![barcodes], |row| {
+            Ok(LoanPair {
+                out_log_id: row.get(0)?,
+                out_time: row.get(1)?,
+                department: row.get(2)?,
+                out_station: row.get(3)?,
+                check_in: None,
+            })
+        })
+        .optional()?
+        .into_iter()
+        .collect();
+    {
+        let mut stmt = conn.prepare_cached(
+            "SELECT id, timestamp, action, department, station FROM logs
+             WHERE barcode = ?1 AND voided_at IS NULL
+             ORDER BY timestamp, id",
+        )?;
+        let mut rows = stmt.query(params![item])?;
+        while let Some(row) = rows.next()? {
+            let (id, timestamp, action): (i64, i64, String) = (row.get(0)?, row.get(1)?, row.get(2)?);
+            let open = pairs.last_mut().filter(|pair| pair.check_in.is_none());
+            match (action.as_str(), open) {
+                ("check-out", None) => pairs.push(LoanPair {
+                    out_log_id: id,
+                    out_time: timestamp,
+                    department: row.get(3)?,
+                    out_station: row.get(4)?,
+                    check_in: None,
+                }),
+                ("check-in", Some(pair)) => pair.check_in = Some((id, timestamp, row.get(4)?)),
+                _ => {} // Same rules as when recording: repeated check-outs and stray check-ins don't form loans
+            }
+        }
+    }
+
+    // Drop loans first so no two open loans exist for the item at any point
+    let keep: Vec<i64> = pairs.iter().map(|pair| pair.out_log_id).collect();
+    let keep = serde_json::json!(keep).to_string();
+    let removed: Vec<i64> = {
+        let mut stmt = conn.prepare_cached(
+            "SELECT id FROM loans
+             WHERE barcode IN (SELECT value FROM json_each(?1))
+               AND out_log_id IN (SELECT id FROM logs)
+               AND out_log_id NOT IN (SELECT value FROM json_each(?2))",
+        )?;
+        let rows = stmt.query_map(params![barcodes, keep], |row| row.get(0))?;
+        rows.collect::<Result<_>>()?
+    };
+    conn.prepare_cached("DELETE FROM loans WHERE id IN (SELECT value FROM json_each(?1))")?
+        .execute(params![serde_json::json!(removed).to_string()])?;
+
+    // Oldest first, so an earlier loan is closed before a later one is reopened
+    for pair in &pairs {
+        let (in_log_id, in_time, in_station) = match &pair.check_in {
+            Some((id, time, station)) => (Some(*id), Some(*time), station.clone()),
+            None => (None, None, None),
+        };
+        let updated = conn.execute(
+            "UPDATE loans SET barcode = ?2, in_log_id = ?3, in_time = ?4, in_station = ?5 WHERE out_log_id = ?1",
+            params![pair.out_log_id, item, in_log_id, in_time, in_station],
+        )?;
+        if updated == 0 {
+            conn.execute(
+                "INSERT INTO loans (barcode, department, out_log_id, out_time, out_station, in_log_id, in_time, in_station)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    item,
+                    pair.department,
+                    pair.out_log_id,
+                    pair.out_time,
+                    pair.out_station,
+                    in_log_id,
+                    in_time,
+                    in_station
+                ],
+            )?;
+        }
+    }
+    Ok(removed)
+}
+
+/// Days of completed history kept in the live database when the
+/// `archive_retention_days` setting is missing or invalid
+pub const DEFAULT_ARCHIVE_RETENTION_DAYS: i32 = 30;
+
 pub struct Database {
     conn: Connection,
+    path: PathBuf,
+    migration_report: MigrationReport,
 }
 
 impl Database {
     pub fn new() -> Result<Self> {
-        let db_path = Self::get_db_path();
-        let conn = Connection::open(&db_path)?;
-        let db = Database { conn };
-        db.init_tables()?;
-        Ok(db)
+        Self::open_at(Self::get_db_path())
+    }
+
+    /// Opens (and migrates) the database file at `db_path`
+    pub fn open_at(db_path: PathBuf) -> Result<Self> {
+        let mut conn = Connection::open(&db_path)?;
+        Self::configure_connection(&conn)?;
+        let migration_report = migrations::run_migrations(&mut conn)?;
+        Ok(Database { conn, path: db_path, migration_report })
+    }
+
+    /// Single place for per-connection settings (pragmas, statement cache)
+    fn configure_connection(conn: &Connection) -> Result<()> {
+        conn.set_prepared_statement_cache_capacity(64);
+        conn.pragma_update(None, "foreign_keys", true)?;
+        conn.pragma_update(None, "temp_store", "MEMORY")?;
+
+        // Several stations share one file on a network drive. WAL relies on shared
+        // memory that SMB/NFS can't provide between hosts, so stay on the classic
+        // rollback journal with full syncs and let SQLite wait a little for locks
+        // before `DbService` starts its own backoff.
+        conn.busy_timeout(Duration::from_millis(1000))?;
+        let journal_mode: String = conn.pragma_update_and_check(None, "journal_mode", "DELETE", |row| row.get(0))?;
+        if !journal_mode.eq_ignore_ascii_case("delete") {
+            eprintln!("Warning: database is using journal_mode={} instead of DELETE", journal_mode);
+        }
+        conn.pragma_update(None, "synchronous", "FULL")?;
+        Ok(())
+    }
+
+    /// Schema migrations applied (if any) when this connection was opened
+    pub fn migration_report(&self) -> &MigrationReport {
+        &self.migration_report
     }
 
     fn get_db_path() -> PathBuf {
+        // Explicit override, e.g. to point several test processes at one file
+        if let Some(path) = std::env::var_os("INVENTORY_DB_PATH") {
+            return PathBuf::from(path);
+        }
+
         // Get the executable's directory for network drive compatibility
         let mut path = std::env::current_exe()
             .map(|p| p.parent().unwrap_or(std::path::Path::new(".")).to_path_buf())
@@ -57,107 +454,206 @@ impl Database {
         path
     }
 
-    fn init_tables(&self) -> Result<()> {
-        // Create logs table
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS logs (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                timestamp TEXT NOT NULL,
-                barcode TEXT NOT NULL,
-                action TEXT CHECK(action IN ('check-in', 'check-out')) NOT NULL,
-                department TEXT
-            )",
-            [],
-        )?;
+    pub fn log_scan(&self, barcode: &str, action: &str, department: Option<&str>) -> Result<i64> {
+        self.log_scan_at(barcode, action, department, Local::now())
+    }
 
-        // Create settings table
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS settings (
-                key TEXT PRIMARY KEY,
-                value TEXT NOT NULL
-            )",
-            [],
-        )?;
+    /// Logs a scan with an explicit time, used when replaying scans that were
+    /// queued while the database was unreachable
+    pub fn log_scan_at(&self, barcode: &str, action: &str, department: Option<&str>, scanned_at: DateTime<Local>) -> Result<i64> {
+        // Keyboard wedges type lower case; store what the catalogue and lookups use
+        let barcode = normalize_barcode(barcode);
+        let timestamp = to_db_time(&scanned_at);
+        let dept = department.map(|s| s.to_string());
+        let station = station_name();
+        
+        self.in_write_transaction(|| {
+            self.conn
+                .prepare_cached("INSERT INTO logs (timestamp, barcode, action, department, station) VALUES (?1, ?2, ?3, ?4, ?5)")?
+                .execute(params![timestamp, barcode, action, dept, station])?;
+            let log_id = self.conn.last_insert_rowid();
 
-        // Create department mappings table
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS department_mappings (
-                prefix TEXT PRIMARY KEY,
-                department TEXT NOT NULL
-            )",
-            [],
-        )?;
+            // Keep the item_status projection in step with the log. A replayed
+            // offline scan older than the current state must not overwrite it.
+            self.conn
+                .prepare_cached(
+                    "INSERT INTO item_status (barcode, state, department, since, log_id) VALUES (?1, ?2, ?3, ?4, ?5)
+                     ON CONFLICT(barcode) DO UPDATE SET
+                         state = excluded.state,
+                         department = excluded.department,
+                         since = excluded.since,
+                         log_id = excluded.log_id
+                     WHERE excluded.since >= item_status.since"
+                )?
+                .execute(params![barcode, action, dept, timestamp, log_id])?;
 
-        // Create items table (managed inventory catalogue)
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS items (
-                barcode TEXT PRIMARY KEY,
-                department TEXT,
-                description TEXT
-            )",
-            [],
-        )?;
+            if action == "check-out" {
+                // A check-out while the item is already out keeps the original loan
+                self.conn
+                    .prepare_cached(
+                        "INSERT INTO loans (barcode, department, out_log_id, out_time, out_station)
+                         SELECT ?1, ?2, ?3, ?4, ?5
+                         WHERE NOT EXISTS (SELECT 1 FROM loans WHERE barcode = ?1 AND in_time IS NULL)"
+                    )?
+                    .execute(params![barcode, dept, log_id, timestamp, station])?;
+            } else {
+                self.conn
+                    .prepare_cached(
+                        "UPDATE loans SET in_log_id = ?2, in_time = ?3, in_station = ?4
+                         WHERE barcode = ?1 AND in_time IS NULL AND out_time <= ?3"
+                    )?
+                    .execute(params![barcode, log_id, timestamp, station])?;
+                self.resolve_alerts_for(&barcode)?;
+            }
 
-        // Insert default department mappings only if table is empty
-        let count: i64 = self.conn.query_row(
-            "SELECT COUNT(*) FROM department_mappings",
-            [],
-            |row| row.get(0)
-        )?;
-        
-        if count == 0 {
+            Ok(log_id)
+        })
+    }
+
+    /// Records a regular scan by toggling the item's state. The current state is
+    /// read and the new row written inside one write transaction, so two stations
+    /// scanning the same item at once can't both decide it was on the shelf.
+    pub fn record_scan(&self, barcode: &str, department: Option<&str>) -> Result<String> {
+        let barcode = normalize_barcode(barcode);
+        self.in_write_transaction(|| {
+            let current_state: Option<String> = self.conn
+                .prepare_cached("SELECT state FROM item_status WHERE barcode = ?1")?
+                .query_row(params![barcode], |row| row.get(0))
+                .optional()?;
+
+            let action = if current_state.as_deref() == Some("check-out") {
+                "check-in"
+            } else {
+                "check-out"
+            };
+            self.log_scan(&barcode, action, department)?;
+            Ok(action.to_string())
+        })
+    }
+
+    /// Runs `f` in an immediate write transaction, or directly if the caller
+    /// already opened one
+    fn in_write_transaction<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        if !self.conn.is_autocommit() {
+            return f();
+        }
+        let tx = Transaction::new_unchecked(&self.conn, TransactionBehavior::Immediate)?;
+        let value = f()?;
+        tx.commit()?;
+        Ok(value)
+    }
+
+    /// Rebuilds the item_status projection from the full log history
+    pub fn rebuild_item_status(&self) -> Result<usize> {
+        self.in_write_transaction(|| {
+            self.conn.execute("DELETE FROM item_status", [])?;
             self.conn.execute(
-                "INSERT INTO department_mappings (prefix, department) VALUES 
-                ('KÄKX', 'Käkkirurgi'),
-                ('ORTX', 'Ortopedi'),
-                ('NEURX', 'Neurokirurgi')",
+                "INSERT INTO item_status (barcode, state, department, since, log_id)
+                 SELECT l.barcode, l.action, l.department, l.timestamp, l.id
+                 FROM logs l
+                 WHERE l.id = (
+                     SELECT latest.id FROM logs latest
+                     WHERE latest.barcode = l.barcode AND latest.voided_at IS NULL
+                     ORDER BY latest.timestamp DESC, latest.id DESC
+                     LIMIT 1
+                 )",
                 [],
-            )?;
-        }
+            )
+        })
+    }
 
-        // Insert default settings
-        self.conn.execute(
-            "INSERT OR IGNORE INTO settings (key, value) VALUES 
-            ('auto_export_enabled', 'false'),
-            ('export_path', ''),
-            ('alert_threshold_hours', '24'),
-            ('trigger_barcode', 'SCAN_START')",
-            [],
+    /// Recomputes one item's status from its remaining (non-voided) scans, or
+    /// from its open loan when that loan's check-out has been archived and no
+    /// scan is left. Run after [`Database::rebuild_loans_for`].
+    fn refresh_item_status_for(&self, barcode: &str) -> Result<()> {
+        self.conn.execute("DELETE FROM item_status WHERE barcode = ?1", params![barcode])?;
+        let inserted = self.conn.execute(
+            "INSERT INTO item_status (barcode, state, department, since, log_id)
+             SELECT barcode, action, department, timestamp, id
+             FROM logs
+             WHERE barcode = ?1 AND voided_at IS NULL
+             ORDER BY timestamp DESC, id DESC
+             LIMIT 1",
+            params![barcode],
         )?;
+        if inserted == 0 {
+            self.conn.execute(
+                "INSERT INTO item_status (barcode, state, department, since, log_id)
+                 SELECT barcode, 'check-out', department, out_time, out_log_id
+                 FROM loans
+                 WHERE barcode = ?1 AND in_time IS NULL",
+                params![barcode],
+            )?;
+        }
+        Ok(())
+    }
 
+    /// Re-pairs one item's live loans from its non-voided scans. Loans whose
+    /// check-out is still valid keep their id; the rest are removed or added.
+    fn rebuild_loans_for(&self, barcode: &str) -> Result<()> {
+        repair_loans(&self.conn, barcode, &[barcode])?;
         Ok(())
     }
 
-    pub fn log_scan(&self, barcode: &str, action: &str, department: Option<&str>) -> Result<i64> {
-        let timestamp = Local::now().to_rfc3339();
-        let dept = department.map(|s| s.to_string());
-        
-        self.conn.execute(
-            "INSERT INTO logs (timestamp, barcode, action, department) VALUES (?1, ?2, ?3, ?4)",
-            params![timestamp, barcode, action, dept],
-        )?;
+    pub fn get_log(&self, log_id: i64) -> Result<Option<ScanLog>> {
+        self.conn
+            .prepare_cached(&format!("SELECT {} FROM logs WHERE id = ?1", LOG_COLUMNS))?
+            .query_row(params![log_id], scan_log_from_row)
+            .optional()
+    }
 
-        Ok(self.conn.last_insert_rowid())
+    /// Most recent scan made at this station that hasn't been voided
+    pub fn get_last_scan(&self) -> Result<Option<ScanLog>> {
+        self.conn
+            .prepare_cached(&format!(
+                "SELECT {} FROM logs WHERE station = ?1 AND voided_at IS NULL ORDER BY id DESC LIMIT 1",
+                LOG_COLUMNS
+            ))?
+            .query_row(params![station_name()], scan_log_from_row)
+            .optional()
     }
 
-    pub fn get_logs(&self, limit: Option<i64>) -> Result<Vec<ScanLog>> {
-        let sql = match limit {
-            Some(l) => format!("SELECT id, timestamp, barcode, action, department FROM logs ORDER BY timestamp DESC LIMIT {}", l),
-            None => "SELECT id, timestamp, barcode, action, department FROM logs ORDER BY timestamp DESC".to_string(),
-        };
+    /// Marks a scan as voided and rolls the item's status and loans back as if
+    /// it had never been made. The row itself stays for traceability.
+    pub fn void_scan(&self, log_id: i64, reason: &str, operator: Option<&str>) -> Result<ScanLog> {
+        self.in_write_transaction(|| {
+            let before = self.get_log(log_id)?.ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+            if before.voided_at.is_some() {
+                return Err(rusqlite::Error::SqliteFailure(
+                    ffi::Error::new(ffi::SQLITE_CONSTRAINT),
+                    Some(format!("Scan {} is already voided", log_id)),
+                ));
+            }
 
-        let mut stmt = self.conn.prepare(&sql)?;
-        let logs = stmt.query_map([], |row| {
-            Ok(ScanLog {
-                id: Some(row.get(0)?),
-                timestamp: DateTime::parse_from_rfc3339(&row.get::<_, String>(1)?)
-                    .unwrap_or_else(|_| Local::now().into())
-                    .with_timezone(&Local),
-                barcode: row.get(2)?,
-                action: row.get(3)?,
-                department: row.get(4)?,
-            })
-        })?;
+            self.conn.execute(
+                "UPDATE logs SET voided_at = ?2, voided_by = ?3, void_reason = ?4 WHERE id = ?1",
+                params![log_id, to_db_time(&Local::now()), operator, reason],
+            )?;
+            self.rebuild_loans_for(&before.barcode)?;
+            self.refresh_item_status_for(&before.barcode)?;
+            self.resolve_alerts_for(&before.barcode)?;
+
+            let after = self.get_log(log_id)?.ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+            self.record_audit(
+                operator,
+                "void_scan",
+                "log",
+                Some(&log_id.to_string()),
+                Some(to_audit_json(&before)),
+                Some(to_audit_json(&after)),
+            )?;
+            Ok(after)
+        })
+    }
+
+    /// Voided scans are left out unless `include_voided` is set
+    pub fn get_logs(&self, limit: Option<i64>, include_voided: bool) -> Result<Vec<ScanLog>> {
+        // LIMIT -1 means no limit in SQLite
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT {} FROM logs WHERE ?2 OR voided_at IS NULL ORDER BY timestamp DESC, id DESC LIMIT ?1",
+            LOG_COLUMNS
+        ))?;
+        let logs = stmt.query_map(params![limit.unwrap_or(-1), include_voided], scan_log_from_row)?;
 
         let mut result = Vec::new();
         for log in logs {
@@ -166,32 +662,60 @@ impl Database {
         Ok(result)
     }
 
+    /// One page of logs matching `query`, from the live database and, if
+    /// requested, the archives
+    pub fn query_logs(&self, query: &LogQuery) -> Result<LogPage> {
+        let mut logs = self.query_logs_in("main", query)?;
+
+        if query.include_archived {
+            for year in archive::archive_years(&self.path) {
+                let (start, end) = archive::year_bounds(year);
+                if !query.overlaps(start, end) {
+                    continue;
+                }
+                self.conn.execute(
+                    "ATTACH DATABASE ?1 AS archive",
+                    params![archive::archive_path(&self.path, year).to_string_lossy()],
+                )?;
+                // Only read while the archive is attached (the join needs main.items)
+                self.conn.pragma_update(None, "query_only", true)?;
+                let result = self.query_logs_in(archive::ATTACHED_SCHEMA, query);
+                self.conn.pragma_update(None, "query_only", false)?;
+                self.conn.execute("DETACH DATABASE archive", [])?;
+                logs.extend(result?);
+            }
+            logs.sort_by(|a, b| query.compare(a, b));
+        }
+
+        let page_size = query.page_size() as usize;
+        let next_cursor = if logs.len() > page_size {
+            logs.truncate(page_size);
+            logs.last().map(|log| query.cursor_for(log))
+        } else {
+            None
+        };
+        Ok(LogPage { logs, next_cursor })
+    }
+
+    fn query_logs_in(&self, schema: &str, query: &LogQuery) -> Result<Vec<ScanLog>> {
+        let (sql, params) = query.to_sql(schema);
+        // Not cached: the SQL depends on the filters, and archive schemas come and go
+        let mut stmt = self.conn.prepare(&sql)?;
+        let logs = stmt.query_map(rusqlite::params_from_iter(params), scan_log_from_row)?;
+        logs.collect()
+    }
+
     pub fn get_checked_out_items(&self) -> Result<Vec<ScanLog>> {
-        // Get all items that have been checked out but not checked back in
-        let mut stmt = self.conn.prepare(
-            "SELECT l1.id, l1.timestamp, l1.barcode, l1.action, l1.department 
-             FROM logs l1 
-             WHERE l1.action = 'check-out' 
-             AND NOT EXISTS (
-                 SELECT 1 FROM logs l2 
-                 WHERE l2.barcode = l1.barcode 
-                 AND l2.action = 'check-in' 
-                 AND l2.timestamp > l1.timestamp
-             )
-             ORDER BY l1.timestamp DESC"
+        // Get all items whose current state is checked out
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT s.log_id, s.since, s.barcode, s.state, s.department, l.station, l.voided_at, l.voided_by, l.void_reason
+             FROM item_status s
+             LEFT JOIN logs l ON l.id = s.log_id
+             WHERE s.state = 'check-out'
+             ORDER BY s.since DESC"
         )?;
 
-        let logs = stmt.query_map([], |row| {
-            Ok(ScanLog {
-                id: Some(row.get(0)?),
-                timestamp: DateTime::parse_from_rfc3339(&row.get::<_, String>(1)?)
-                    .unwrap_or_else(|_| Local::now().into())
-                    .with_timezone(&Local),
-                barcode: row.get(2)?,
-                action: row.get(3)?,
-                department: row.get(4)?,
-            })
-        })?;
+        let logs = stmt.query_map([], scan_log_from_row)?;
 
         let mut result = Vec::new();
         for log in logs {
@@ -200,19 +724,299 @@ impl Database {
         Ok(result)
     }
 
+    /// Items that were checked out at `at`, rebuilt from live and archived
+    /// loans. For the present this is just [`Database::get_checked_out_items`].
+    pub fn get_checked_out_items_at(&self, at: DateTime<Local>) -> Result<Vec<ScanLog>> {
+        if at >= Local::now() {
+            return self.get_checked_out_items();
+        }
+
+        let at = to_db_time(&at);
+        let mut loans = archive::read_loans_open_at(&self.path, at)?;
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT {} FROM loans WHERE out_time <= ?1 AND (in_time IS NULL OR in_time > ?1)",
+            LOAN_COLUMNS
+        ))?;
+        for loan in stmt.query_map(params![at], loan_from_row)? {
+            loans.push(loan?);
+        }
+        loans.sort_by(|a, b| b.out_time.cmp(&a.out_time).then(b.id.cmp(&a.id)));
+
+        // Loans only ever start from a scan that wasn't voided
+        Ok(loans
+            .into_iter()
+            .map(|loan| ScanLog {
+                id: Some(loan.out_log_id),
+                timestamp: loan.out_time,
+                barcode: loan.barcode,
+                action: "check-out".to_string(),
+                department: loan.department,
+                station: loan.out_station,
+                voided_at: None,
+                voided_by: None,
+                void_reason: None,
+            })
+            .collect())
+    }
+
+    /// Checked-out set and per-department counts as of `at`
+    pub fn get_checked_out_snapshot(&self, at: DateTime<Local>) -> Result<CheckedOutSnapshot> {
+        let items = self.get_checked_out_items_at(at)?;
+
+        let mut counts: HashMap<String, i64> = HashMap::new();
+        for item in &items {
+            let department = item.department.clone().unwrap_or_else(|| "Unknown".to_string());
+            *counts.entry(department).or_insert(0) += 1;
+        }
+        let mut departments: Vec<(String, i64)> = counts.into_iter().collect();
+        departments.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        Ok(CheckedOutSnapshot { at, items, departments })
+    }
+
+    /// Alerts, most urgent first: critical before warning, then oldest first.
+    /// Resolved alerts are left out unless `include_resolved` is set.
+    pub fn get_alerts(&self, include_resolved: bool, limit: Option<i64>) -> Result<Vec<Alert>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT {} FROM alerts
+             WHERE ?1 OR state <> 'resolved'
+             ORDER BY state = 'resolved', level = 'warning', created_at, id
+             LIMIT ?2",
+            ALERT_COLUMNS
+        ))?;
+        let alerts = stmt.query_map(params![include_resolved, limit.unwrap_or(-1)], alert_from_row)?;
+        alerts.collect()
+    }
+
+    pub fn get_alert_summary(&self) -> Result<AlertSummary> {
+        self.conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(level = 'critical'), 0), COALESCE(SUM(state = 'new'), 0)
+             FROM alerts WHERE state <> 'resolved'",
+            [],
+            |row| Ok(AlertSummary { open: row.get(0)?, critical: row.get(1)?, new: row.get(2)? }),
+        )
+    }
+
+    pub fn get_alert(&self, alert_id: i64) -> Result<Option<Alert>> {
+        self.conn
+            .prepare_cached(&format!("SELECT {} FROM alerts WHERE id = ?1", ALERT_COLUMNS))?
+            .query_row(params![alert_id], alert_from_row)
+            .optional()
+    }
+
+    fn get_alert_for_loan(&self, loan_id: i64) -> Result<Option<Alert>> {
+        self.conn
+            .prepare_cached(&format!("SELECT {} FROM alerts WHERE loan_id = ?1", ALERT_COLUMNS))?
+            .query_row(params![loan_id], alert_from_row)
+            .optional()
+    }
+
+    /// Brings the alerts in line with the loans overdue right now and returns
+    /// the ones staff need to hear about: new, escalated to a higher level,
+    /// back from a snooze, or reopened. An escalation overrides an
+    /// acknowledgement or snooze. Alerts for loans no longer overdue resolve.
+    pub fn sync_alerts(&self, overdue: &[OverdueItem]) -> Result<Vec<Alert>> {
+        let now = to_db_time(&Local::now());
+        self.in_write_transaction(|| {
+            let mut notify = Vec::new();
+            for item in overdue {
+                let rule = to_audit_json(&item.rule);
+                let changed = match self.get_alert_for_loan(item.loan_id)? {
+                    None => {
+                        self.conn
+                            .prepare_cached(
+                                "INSERT INTO alerts (loan_id, barcode, department, level, state, threshold_hours, rule, created_at, updated_at)
+                                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?8)"
+                            )?
+                            .execute(params![item.loan_id, item.barcode, item.department, item.level, AlertState::New, item.threshold_hours, rule, now])?;
+                        true
+                    }
+                    Some(alert) => {
+                        let escalated = item.level > alert.level;
+                        let woke = alert.state == AlertState::Snoozed
+                            && alert.snoozed_until.is_none_or(|until| to_db_time(&until) <= now);
+                        let reopened = alert.state == AlertState::Resolved;
+                        if escalated || woke || reopened {
+                            self.conn
+                                .prepare_cached(
+                                    "UPDATE alerts SET level = ?2, state = ?3, threshold_hours = ?4, rule = ?5, updated_at = ?6,
+                                         escalated_at = CASE WHEN ?7 THEN ?6 ELSE escalated_at END,
+                                         snoozed_until = NULL, resolved_at = NULL
+                                     WHERE id = ?1"
+                                )?
+                                .execute(params![alert.id, item.level, AlertState::New, item.threshold_hours, rule, now, escalated])?;
+                        } else {
+                            // A lowered level or changed rule isn't worth telling anyone about
+                            self.conn
+                                .prepare_cached("UPDATE alerts SET level = ?2, threshold_hours = ?3, rule = ?4 WHERE id = ?1")?
+                                .execute(params![alert.id, item.level, item.threshold_hours, rule])?;
+                        }
+                        escalated || woke || reopened
+                    }
+                };
+                if changed {
+                    notify.extend(self.get_alert_for_loan(item.loan_id)?);
+                }
+            }
+
+            // Checked in (normally resolved at scan time), voided, or the threshold was raised
+            let overdue_loans: Vec<i64> = overdue.iter().map(|item| item.loan_id).collect();
+            self.resolve_alerts_for_closed_loans(Some(&overdue_loans))?;
+            Ok(notify)
+        })
+    }
+
+    /// Resolves one item's alerts whose loan is no longer open
+    fn resolve_alerts_for(&self, barcode: &str) -> Result<()> {
+        self.conn
+            .prepare_cached(
+                "UPDATE alerts SET state = 'resolved', resolved_at = ?2, updated_at = ?2
+                 WHERE barcode = ?1 AND state <> 'resolved'
+                   AND loan_id NOT IN (SELECT id FROM loans WHERE in_time IS NULL)"
+            )?
+            .execute(params![barcode, to_db_time(&Local::now())])?;
+        Ok(())
+    }
+
+    /// Resolves every unresolved alert whose loan isn't in `keep` (all of them
+    /// when `keep` is `None`)
+    fn resolve_alerts_for_closed_loans(&self, keep: Option<&[i64]>) -> Result<()> {
+        let now = to_db_time(&Local::now());
+        let open: Vec<(i64, i64)> = {
+            let mut stmt = self.conn.prepare_cached("SELECT id, loan_id FROM alerts WHERE state <> 'resolved'")?;
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<Result<_>>()?
+        };
+        for (alert_id, loan_id) in open {
+            if keep.is_some_and(|keep| keep.contains(&loan_id)) {
+                continue;
+            }
+            self.conn
+                .prepare_cached("UPDATE alerts SET state = 'resolved', resolved_at = ?2, updated_at = ?2 WHERE id = ?1")?
+                .execute(params![alert_id, now])?;
+        }
+        Ok(())
+    }
+
+    /// Claims the alerts not mailed at their current level yet for this
+    /// station until `lease` runs out, so another station checking at the same
+    /// time doesn't mail them too. Alerts claimed by a station that never
+    /// finished are handed out again once its lease has passed.
+    pub fn claim_alerts_to_email(&self, lease: chrono::Duration) -> Result<Vec<Alert>> {
+        let now = Local::now();
+        self.in_write_transaction(|| {
+            let mut stmt = self.conn.prepare_cached(&format!(
+                "SELECT {} FROM alerts
+                 WHERE state = 'new' AND (emailed_level IS NULL OR (emailed_level = 'warning' AND level = 'critical'))
+                   AND (email_claimed_until IS NULL OR email_claimed_until <= ?1)
+                 ORDER BY created_at, id",
+                ALERT_COLUMNS
+            ))?;
+            let alerts = stmt
+                .query_map(params![to_db_time(&now)], alert_from_row)?
+                .collect::<Result<Vec<_>>>()?;
+            for alert in &alerts {
+                self.conn
+                    .prepare_cached("UPDATE alerts SET email_claimed_by = ?2, email_claimed_until = ?3 WHERE id = ?1")?
+                    .execute(params![alert.id, station_name(), to_db_time(&(now + lease))])?;
+            }
+            Ok(alerts)
+        })
+    }
+
+    /// Records that the claimed alerts went out at the level they were claimed at
+    pub fn mark_alerts_emailed(&self, alerts: &[Alert]) -> Result<()> {
+        let now = to_db_time(&Local::now());
+        self.in_write_transaction(|| {
+            for alert in alerts {
+                self.conn
+                    .prepare_cached(
+                        "UPDATE alerts SET emailed_at = ?2, emailed_level = ?3, email_claimed_by = NULL, email_claimed_until = NULL
+                         WHERE id = ?1",
+                    )?
+                    .execute(params![alert.id, now, alert.level])?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Gives back claimed alerts that weren't mailed, so the next check tries them again
+    pub fn release_email_claims(&self, alerts: &[Alert]) -> Result<()> {
+        self.in_write_transaction(|| {
+            for alert in alerts {
+                self.conn
+                    .prepare_cached(
+                        "UPDATE alerts SET email_claimed_by = NULL, email_claimed_until = NULL
+                         WHERE id = ?1 AND email_claimed_by = ?2",
+                    )?
+                    .execute(params![alert.id, station_name()])?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn acknowledge_alert(&self, alert_id: i64, operator: Option<&str>) -> Result<Alert> {
+        self.change_alert_state(alert_id, AlertState::Acknowledged, None, operator)
+    }
+
+    /// Silences an alert until `until`; the next check after that raises it again
+    pub fn snooze_alert(&self, alert_id: i64, until: DateTime<Local>, operator: Option<&str>) -> Result<Alert> {
+        self.change_alert_state(alert_id, AlertState::Snoozed, Some(until), operator)
+    }
+
+    fn change_alert_state(&self, alert_id: i64, state: AlertState, until: Option<DateTime<Local>>, operator: Option<&str>) -> Result<Alert> {
+        self.in_write_transaction(|| {
+            let alert = self.get_alert(alert_id)?.ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+            if alert.state == AlertState::Resolved {
+                return Err(rusqlite::Error::SqliteFailure(
+                    ffi::Error::new(ffi::SQLITE_CONSTRAINT),
+                    Some(format!("Alert {} is already resolved", alert_id)),
+                ));
+            }
+
+            let now = to_db_time(&Local::now());
+            if state == AlertState::Snoozed {
+                self.conn.execute(
+                    "UPDATE alerts SET state = ?2, snoozed_until = ?3, snoozed_by = ?4, updated_at = ?5 WHERE id = ?1",
+                    params![alert_id, state, until.map(|until| to_db_time(&until)), operator, now],
+                )?;
+            } else {
+                self.conn.execute(
+                    "UPDATE alerts SET state = ?2, acknowledged_at = ?3, acknowledged_by = ?4, snoozed_until = NULL, updated_at = ?3 WHERE id = ?1",
+                    params![alert_id, state, now, operator],
+                )?;
+            }
+            self.get_alert(alert_id)?.ok_or(rusqlite::Error::QueryReturnedNoRows)
+        })
+    }
+
+    /// Loans that haven't been closed by a check-in, oldest first
+    pub fn get_open_loans(&self) -> Result<Vec<Loan>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT {} FROM loans WHERE in_time IS NULL ORDER BY out_time",
+            LOAN_COLUMNS
+        ))?;
+        let loans = stmt.query_map([], loan_from_row)?;
+        loans.collect()
+    }
+
+    /// Most recent loans first, open and closed
+    pub fn get_loans(&self, limit: Option<i64>) -> Result<Vec<Loan>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT {} FROM loans ORDER BY out_time DESC, id DESC LIMIT ?1",
+            LOAN_COLUMNS
+        ))?;
+        let loans = stmt.query_map(params![limit.unwrap_or(-1)], loan_from_row)?;
+        loans.collect()
+    }
+
     pub fn get_department_stats(&self) -> Result<Vec<(String, i64)>> {
-        let mut stmt = self.conn.prepare(
+        let mut stmt = self.conn.prepare_cached(
             "SELECT 
                 COALESCE(department, 'Unknown') as dept,
                 COUNT(*) as count
-             FROM logs l1 
-             WHERE l1.action = 'check-out' 
-             AND NOT EXISTS (
-                 SELECT 1 FROM logs l2 
-                 WHERE l2.barcode = l1.barcode 
-                 AND l2.action = 'check-in' 
-                 AND l2.timestamp > l1.timestamp
-             )
+             FROM item_status
+             WHERE state = 'check-out'
              GROUP BY dept
              ORDER BY count DESC"
         )?;
@@ -228,13 +1032,74 @@ impl Database {
         Ok(result)
     }
 
-    pub fn clear_logs(&self) -> Result<()> {
-        self.conn.execute("DELETE FROM logs", [])?;
+    pub fn clear_logs(&self, operator: Option<&str>) -> Result<()> {
+        self.in_write_transaction(|| {
+            let logs = self.conn.execute("DELETE FROM logs", [])?;
+            self.conn.execute("DELETE FROM item_status", [])?;
+            let loans = self.conn.execute("DELETE FROM loans", [])?;
+            self.resolve_alerts_for_closed_loans(None)?;
+            let before = serde_json::json!({ "logs": logs, "loans": loans });
+            self.record_audit(operator, "clear_logs", "logs", None, Some(before.to_string()), None)
+        })
+    }
+
+    /// Appends an entry to the audit trail. Call it inside the transaction that
+    /// makes the change, so the change and its record commit together.
+    fn record_audit(
+        &self,
+        operator: Option<&str>,
+        action: &str,
+        entity: &str,
+        entity_key: Option<&str>,
+        before_value: Option<String>,
+        after_value: Option<String>,
+    ) -> Result<()> {
+        self.conn
+            .prepare_cached(
+                "INSERT INTO audit_log (timestamp, station, operator, action, entity, entity_key, before_value, after_value)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"
+            )?
+            .execute(params![
+                to_db_time(&Local::now()),
+                station_name(),
+                operator,
+                action,
+                entity,
+                entity_key,
+                before_value,
+                after_value
+            ])?;
         Ok(())
     }
 
+    /// Audit entries for one entity, oldest first
+    pub fn get_audit_log_for(&self, entity: &str, entity_key: &str) -> Result<Vec<AuditEntry>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT id, timestamp, station, operator, action, entity, entity_key, before_value, after_value
+             FROM audit_log
+             WHERE entity = ?1 AND entity_key = ?2
+             ORDER BY timestamp, id"
+        )?;
+        let entries = stmt.query_map(params![entity, entity_key], audit_entry_from_row)?;
+        entries.collect()
+    }
+
+    /// Audit entries, newest first, optionally for one kind of entity
+    /// ("setting", "department_mapping", "item", "logs")
+    pub fn get_audit_log(&self, entity: Option<&str>, limit: Option<i64>) -> Result<Vec<AuditEntry>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT id, timestamp, station, operator, action, entity, entity_key, before_value, after_value
+             FROM audit_log
+             WHERE ?1 IS NULL OR entity = ?1
+             ORDER BY timestamp DESC, id DESC
+             LIMIT ?2"
+        )?;
+        let entries = stmt.query_map(params![entity, limit.unwrap_or(-1)], audit_entry_from_row)?;
+        entries.collect()
+    }
+
     pub fn get_setting(&self, key: &str) -> Result<Option<String>> {
-        let mut stmt = self.conn.prepare("SELECT value FROM settings WHERE key = ?1")?;
+        let mut stmt = self.conn.prepare_cached("SELECT value FROM settings WHERE key = ?1")?;
         let mut rows = stmt.query_map(params![key], |row| {
             Ok(row.get::<_, String>(0)?)
         })?;
@@ -246,20 +1111,41 @@ impl Database {
         }
     }
 
-    pub fn set_setting(&self, key: &str, value: &str) -> Result<()> {
-        self.conn.execute(
-            "INSERT OR REPLACE INTO settings (key, value) VALUES (?1, ?2)",
-            params![key, value],
-        )?;
-        Ok(())
+    pub fn set_setting(&self, key: &str, value: &str, operator: Option<&str>) -> Result<()> {
+        self.in_write_transaction(|| {
+            let before = self.get_setting(key)?;
+            self.conn
+                .prepare_cached("INSERT OR REPLACE INTO settings (key, value) VALUES (?1, ?2)")?
+                .execute(params![key, value])?;
+            // Record that a credential changed, not what it is
+            let audited = |value: &str| {
+                if key.ends_with("_password") || key.ends_with("_secret") {
+                    to_audit_json(&"********")
+                } else {
+                    to_audit_json(&value)
+                }
+            };
+            self.record_audit(
+                operator,
+                "set_setting",
+                "setting",
+                Some(key),
+                before.map(|before| audited(&before)),
+                Some(audited(value)),
+            )
+        })
     }
 
     pub fn get_department_mappings(&self) -> Result<Vec<DepartmentMapping>> {
-        let mut stmt = self.conn.prepare("SELECT prefix, department FROM department_mappings ORDER BY prefix")?;
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT prefix, department, alert_threshold_hours, alert_emails FROM department_mappings ORDER BY prefix"
+        )?;
         let mappings = stmt.query_map([], |row| {
             Ok(DepartmentMapping {
                 prefix: row.get(0)?,
                 department: row.get(1)?,
+                alert_threshold_hours: row.get(2)?,
+                alert_emails: row.get(3)?,
             })
         })?;
 
@@ -270,34 +1156,409 @@ impl Database {
         Ok(result)
     }
 
-    pub fn set_department_mapping(&self, prefix: &str, department: &str) -> Result<()> {
-        self.conn.execute(
-            "INSERT OR REPLACE INTO department_mappings (prefix, department) VALUES (?1, ?2)",
-            params![prefix, department],
+    fn get_department_mapping(&self, prefix: &str) -> Result<Option<DepartmentMapping>> {
+        self.conn
+            .prepare_cached("SELECT prefix, department, alert_threshold_hours, alert_emails FROM department_mappings WHERE prefix = ?1")?
+            .query_row(params![prefix], |row| {
+                Ok(DepartmentMapping {
+                    prefix: row.get(0)?,
+                    department: row.get(1)?,
+                    alert_threshold_hours: row.get(2)?,
+                    alert_emails: row.get(3)?,
+                })
+            })
+            .optional()
+    }
+
+    pub fn set_department_mapping(&self, prefix: &str, department: &str, operator: Option<&str>) -> Result<()> {
+        self.in_write_transaction(|| {
+            let before = self.get_department_mapping(prefix)?;
+            // Upsert rather than replace so the prefix keeps its threshold
+            self.conn.execute(
+                "INSERT INTO department_mappings (prefix, department) VALUES (?1, ?2)
+                 ON CONFLICT(prefix) DO UPDATE SET department = excluded.department",
+                params![prefix, department],
+            )?;
+            let after = self.get_department_mapping(prefix)?;
+            self.record_audit(
+                operator,
+                "set_department_mapping",
+                "department_mapping",
+                Some(prefix),
+                before.map(|before| to_audit_json(&before)),
+                after.map(|after| to_audit_json(&after)),
+            )
+        })
+    }
+
+    /// Sets or, with `None`, clears the overdue threshold of a department mapping
+    pub fn set_department_threshold(&self, prefix: &str, hours: Option<i64>, operator: Option<&str>) -> Result<()> {
+        self.in_write_transaction(|| {
+            let before = self.get_department_mapping(prefix)?.ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+            self.conn.execute(
+                "UPDATE department_mappings SET alert_threshold_hours = ?2 WHERE prefix = ?1",
+                params![prefix, hours],
+            )?;
+            let after = self.get_department_mapping(prefix)?;
+            self.record_audit(
+                operator,
+                "set_department_threshold",
+                "department_mapping",
+                Some(prefix),
+                Some(to_audit_json(&before)),
+                after.map(|after| to_audit_json(&after)),
+            )
+        })
+    }
+
+    /// Sets or, with `None`, clears who gets the department's overdue emails
+    pub fn set_department_alert_emails(&self, prefix: &str, emails: Option<&str>, operator: Option<&str>) -> Result<()> {
+        self.in_write_transaction(|| {
+            let before = self.get_department_mapping(prefix)?.ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+            self.conn.execute(
+                "UPDATE department_mappings SET alert_emails = ?2 WHERE prefix = ?1",
+                params![prefix, emails],
+            )?;
+            let after = self.get_department_mapping(prefix)?;
+            self.record_audit(
+                operator,
+                "set_department_alert_emails",
+                "department_mapping",
+                Some(prefix),
+                Some(to_audit_json(&before)),
+                after.map(|after| to_audit_json(&after)),
+            )
+        })
+    }
+
+    pub fn delete_department_mapping(&self, prefix: &str, operator: Option<&str>) -> Result<()> {
+        self.in_write_transaction(|| {
+            let before = self.get_department_mapping(prefix)?;
+            self.conn.execute(
+                "DELETE FROM department_mappings WHERE prefix = ?1",
+                params![prefix],
+            )?;
+            self.record_audit(
+                operator,
+                "delete_department_mapping",
+                "department_mapping",
+                Some(prefix),
+                before.map(|before| to_audit_json(&before)),
+                None,
+            )
+        })
+    }
+
+    // ------------------ Working calendar ------------------
+    pub fn get_opening_hours(&self) -> Result<Vec<OpeningHours>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT weekday, opens_minute, closes_minute FROM business_hours ORDER BY weekday"
         )?;
-        Ok(())
+        let hours = stmt.query_map([], |row| {
+            Ok(OpeningHours {
+                weekday: row.get(0)?,
+                opens: format_clock(row.get(1)?),
+                closes: format_clock(row.get(2)?),
+            })
+        })?;
+        hours.collect()
     }
 
-    pub fn delete_department_mapping(&self, prefix: &str) -> Result<()> {
-        self.conn.execute(
-            "DELETE FROM department_mappings WHERE prefix = ?1",
-            params![prefix],
+    /// Sets a weekday's opening hours in minutes after midnight, or closes it
+    /// with `None`. Clearing every weekday turns the calendar off.
+    pub fn set_opening_hours(&self, weekday: u32, minutes: Option<(u32, u32)>, operator: Option<&str>) -> Result<()> {
+        self.in_write_transaction(|| {
+            let find = |hours: Vec<OpeningHours>| hours.into_iter().find(|day| day.weekday == weekday);
+            let before = find(self.get_opening_hours()?);
+            match minutes {
+                Some((opens, closes)) => self.conn.execute(
+                    "INSERT INTO business_hours (weekday, opens_minute, closes_minute) VALUES (?1, ?2, ?3)
+                     ON CONFLICT(weekday) DO UPDATE SET opens_minute = excluded.opens_minute, closes_minute = excluded.closes_minute",
+                    params![weekday, opens, closes],
+                )?,
+                None => self.conn.execute("DELETE FROM business_hours WHERE weekday = ?1", params![weekday])?,
+            };
+            let after = find(self.get_opening_hours()?);
+            self.record_audit(
+                operator,
+                "set_opening_hours",
+                "business_hours",
+                Some(&weekday.to_string()),
+                before.map(|before| to_audit_json(&before)),
+                after.map(|after| to_audit_json(&after)),
+            )
+        })
+    }
+
+    pub fn get_holidays(&self) -> Result<Vec<Holiday>> {
+        let mut stmt = self.conn.prepare_cached("SELECT date, name FROM holidays ORDER BY date")?;
+        let holidays = stmt.query_map([], |row| {
+            let date: String = row.get(0)?;
+            Ok(Holiday {
+                date: NaiveDate::parse_from_str(&date, "%Y-%m-%d")
+                    .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e)))?,
+                name: row.get(1)?,
+            })
+        })?;
+        holidays.collect()
+    }
+
+    /// Adds holidays, renaming any date that is already listed
+    pub fn import_holidays(&self, holidays: &[Holiday], operator: Option<&str>) -> Result<usize> {
+        self.in_write_transaction(|| {
+            for holiday in holidays {
+                self.conn
+                    .prepare_cached(
+                        "INSERT INTO holidays (date, name) VALUES (?1, ?2)
+                         ON CONFLICT(date) DO UPDATE SET name = excluded.name"
+                    )?
+                    .execute(params![holiday.date.to_string(), holiday.name])?;
+            }
+            self.record_audit(operator, "import_holidays", "holiday", None, None, Some(to_audit_json(&holidays)))?;
+            Ok(holidays.len())
+        })
+    }
+
+    pub fn delete_holiday(&self, date: NaiveDate, operator: Option<&str>) -> Result<()> {
+        self.in_write_transaction(|| {
+            let before = self.get_holidays()?.into_iter().find(|holiday| holiday.date == date);
+            self.conn.execute("DELETE FROM holidays WHERE date = ?1", params![date.to_string()])?;
+            self.record_audit(
+                operator,
+                "delete_holiday",
+                "holiday",
+                Some(&date.to_string()),
+                before.map(|before| to_audit_json(&before)),
+                None,
+            )
+        })
+    }
+
+    pub fn get_working_calendar(&self) -> Result<WorkingCalendar> {
+        Ok(WorkingCalendar::new(&self.get_opening_hours()?, &self.get_holidays()?))
+    }
+
+    // ------------------ Webhooks ------------------
+    pub fn get_webhook_targets(&self) -> Result<Vec<WebhookTarget>> {
+        let mut stmt = self.conn.prepare_cached(&format!("SELECT {} FROM webhook_targets ORDER BY name, id", WEBHOOK_TARGET_COLUMNS))?;
+        let targets = stmt.query_map([], webhook_target_from_row)?;
+        targets.collect()
+    }
+
+    pub fn get_webhook_target(&self, target_id: i64) -> Result<Option<WebhookTarget>> {
+        self.conn
+            .prepare_cached(&format!("SELECT {} FROM webhook_targets WHERE id = ?1", WEBHOOK_TARGET_COLUMNS))?
+            .query_row(params![target_id], webhook_target_from_row)
+            .optional()
+    }
+
+    /// Adds a target, or updates `target_id`. Returns the saved target.
+    pub fn save_webhook_target(&self, target_id: Option<i64>, settings: &WebhookTargetSettings, operator: Option<&str>) -> Result<WebhookTarget> {
+        settings.validate().map_err(|msg| {
+            rusqlite::Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_CONSTRAINT), Some(msg))
+        })?;
+        let events = settings.events.join(",");
+        // No secret in the settings keeps the stored one; an empty one removes it
+        let replace_secret = settings.secret.is_some();
+        let secret = settings.secret.as_deref().filter(|secret| !secret.is_empty());
+        self.in_write_transaction(|| {
+            let before = match target_id {
+                Some(target_id) => Some(self.get_webhook_target(target_id)?.ok_or(rusqlite::Error::QueryReturnedNoRows)?),
+                None => None,
+            };
+            let target_id = match target_id {
+                Some(target_id) => {
+                    self.conn.execute(
+                        "UPDATE webhook_targets SET name = ?2, url = ?3, secret = CASE WHEN ?7 THEN ?4 ELSE secret END,
+                             events = ?5, enabled = ?6
+                         WHERE id = ?1",
+                        params![target_id, settings.name.trim(), settings.url.trim(), secret, events, settings.enabled, replace_secret],
+                    )?;
+                    target_id
+                }
+                None => {
+                    self.conn.execute(
+                        "INSERT INTO webhook_targets (name, url, secret, events, enabled, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                        params![settings.name.trim(), settings.url.trim(), secret, events, settings.enabled, to_db_time(&Local::now())],
+                    )?;
+                    self.conn.last_insert_rowid()
+                }
+            };
+            let after = self.get_webhook_target(target_id)?.ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+            self.record_audit(
+                operator,
+                "save_webhook_target",
+                "webhook_target",
+                Some(&target_id.to_string()),
+                before.as_ref().map(to_audit_json),
+                Some(to_audit_json(&after)),
+            )?;
+            Ok(after)
+        })
+    }
+
+    /// Removes a target along with its queued deliveries
+    pub fn delete_webhook_target(&self, target_id: i64, operator: Option<&str>) -> Result<()> {
+        self.in_write_transaction(|| {
+            let before = self.get_webhook_target(target_id)?;
+            self.conn.execute("DELETE FROM webhook_targets WHERE id = ?1", params![target_id])?;
+            self.record_audit(
+                operator,
+                "delete_webhook_target",
+                "webhook_target",
+                Some(&target_id.to_string()),
+                before.as_ref().map(to_audit_json),
+                None,
+            )
+        })
+    }
+
+    /// Adds an outbox row for every enabled target subscribed to `event`.
+    /// Returns how many were queued.
+    pub fn enqueue_webhook(&self, event: &str, payload: &str) -> Result<usize> {
+        let now = to_db_time(&Local::now());
+        self.in_write_transaction(|| {
+            let targets: Vec<WebhookTarget> = self
+                .get_webhook_targets()?
+                .into_iter()
+                .filter(|target| target.wants(event))
+                .collect();
+            for target in &targets {
+                self.conn
+                    .prepare_cached(
+                        "INSERT INTO webhook_outbox (target_id, event, payload, created_at, next_attempt_at)
+                         VALUES (?1, ?2, ?3, ?4, ?4)"
+                    )?
+                    .execute(params![target.id, event, payload, now])?;
+            }
+            Ok(targets.len())
+        })
+    }
+
+    /// Claims undelivered entries whose next attempt is due, oldest first, for
+    /// this station until `lease` runs out. Entries another station holds a
+    /// live claim on are left alone, so every delivery is attempted once.
+    pub fn claim_due_webhooks(&self, limit: i64, lease: chrono::Duration) -> Result<Vec<OutboxEntry>> {
+        let now = Local::now();
+        self.in_write_transaction(|| {
+            let mut stmt = self.conn.prepare_cached(&format!(
+                "SELECT {} FROM webhook_outbox
+                 WHERE delivered_at IS NULL AND failed_at IS NULL AND next_attempt_at <= ?1
+                   AND (claimed_until IS NULL OR claimed_until <= ?1)
+                 ORDER BY id
+                 LIMIT ?2",
+                OUTBOX_COLUMNS
+            ))?;
+            let entries = stmt
+                .query_map(params![to_db_time(&now), limit], outbox_entry_from_row)?
+                .collect::<Result<Vec<_>>>()?;
+            for entry in &entries {
+                self.conn
+                    .prepare_cached("UPDATE webhook_outbox SET claimed_by = ?2, claimed_until = ?3 WHERE id = ?1")?
+                    .execute(params![entry.id, station_name(), to_db_time(&(now + lease))])?;
+            }
+            Ok(entries)
+        })
+    }
+
+    /// Gives back claimed entries this station didn't attempt
+    pub fn release_webhook_claims(&self, entry_ids: &[i64]) -> Result<()> {
+        self.in_write_transaction(|| {
+            for entry_id in entry_ids {
+                self.conn
+                    .prepare_cached(
+                        "UPDATE webhook_outbox SET claimed_by = NULL, claimed_until = NULL
+                         WHERE id = ?1 AND claimed_by = ?2",
+                    )?
+                    .execute(params![entry_id, station_name()])?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Outbox entries, newest first. Delivered ones are left out unless `include_delivered` is set.
+    pub fn get_webhook_outbox(&self, include_delivered: bool, limit: Option<i64>) -> Result<Vec<OutboxEntry>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT {} FROM webhook_outbox
+             WHERE ?1 OR delivered_at IS NULL
+             ORDER BY id DESC
+             LIMIT ?2",
+            OUTBOX_COLUMNS
+        ))?;
+        let entries = stmt.query_map(params![include_delivered, limit.unwrap_or(-1)], outbox_entry_from_row)?;
+        entries.collect()
+    }
+
+    /// Records a delivery attempt: `error` is `None` on success. A failure
+    /// schedules the next attempt with backoff and holds back the target's
+    /// other queued entries until then, or gives up after `MAX_ATTEMPTS`.
+    pub fn record_webhook_attempt(&self, entry_id: i64, status: Option<u16>, error: Option<&str>) -> Result<OutboxEntry> {
+        let now = Local::now();
+        self.in_write_transaction(|| {
+            let attempts: i64 = self.conn.query_row(
+                "SELECT attempts + 1 FROM webhook_outbox WHERE id = ?1",
+                params![entry_id],
+                |row| row.get(0),
+            )?;
+            let next_attempt_at = to_db_time(&(now + webhook::retry_delay(attempts)));
+            let delivered = error.is_none();
+            let failed = !delivered && attempts >= webhook::MAX_ATTEMPTS;
+            self.conn
+                .prepare_cached(
+                    "UPDATE webhook_outbox SET attempts = ?2, last_attempt_at = ?3, last_status = ?4, last_error = ?5,
+                         delivered_at = CASE WHEN ?6 THEN ?3 END,
+                         failed_at = CASE WHEN ?7 THEN ?3 END,
+                         next_attempt_at = CASE WHEN ?6 OR ?7 THEN next_attempt_at ELSE ?8 END,
+                         claimed_by = NULL, claimed_until = NULL
+                     WHERE id = ?1"
+                )?
+                .execute(params![entry_id, attempts, to_db_time(&now), status, error, delivered, failed, next_attempt_at])?;
+            if !delivered && !failed {
+                self.conn
+                    .prepare_cached(
+                        "UPDATE webhook_outbox SET next_attempt_at = MAX(next_attempt_at, ?2)
+                         WHERE target_id = (SELECT target_id FROM webhook_outbox WHERE id = ?1)
+                           AND delivered_at IS NULL AND failed_at IS NULL"
+                    )?
+                    .execute(params![entry_id, next_attempt_at])?;
+            }
+            self.conn
+                .prepare_cached(&format!("SELECT {} FROM webhook_outbox WHERE id = ?1", OUTBOX_COLUMNS))?
+                .query_row(params![entry_id], outbox_entry_from_row)
+        })
+    }
+
+    /// Queues a given-up or pending entry for an immediate attempt with a fresh set of retries
+    pub fn retry_webhook(&self, entry_id: i64) -> Result<()> {
+        let changed = self.conn.execute(
+            "UPDATE webhook_outbox SET attempts = 0, failed_at = NULL, next_attempt_at = ?2
+             WHERE id = ?1 AND delivered_at IS NULL",
+            params![entry_id, to_db_time(&Local::now())],
         )?;
+        if changed == 0 {
+            return Err(rusqlite::Error::QueryReturnedNoRows);
+        }
         Ok(())
     }
 
+    pub fn purge_delivered_webhooks(&self, before: DateTime<Local>) -> Result<usize> {
+        self.conn.execute(
+            "DELETE FROM webhook_outbox WHERE delivered_at < ?1",
+            params![to_db_time(&before)],
+        )
+    }
+
     // ------------------ Items CRUD ------------------
     pub fn get_items(&self, limit: Option<i64>) -> Result<Vec<InventoryItem>> {
-        let sql = match limit {
-            Some(l) => format!("SELECT barcode, department, description FROM items ORDER BY barcode LIMIT {}", l),
-            None => "SELECT barcode, department, description FROM items ORDER BY barcode".to_string(),
-        };
-        let mut stmt = self.conn.prepare(&sql)?;
-        let items_iter = stmt.query_map([], |row| {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT barcode, department, description, alert_threshold_hours FROM items ORDER BY barcode LIMIT ?1"
+        )?;
+        let items_iter = stmt.query_map(params![limit.unwrap_or(-1)], |row| {
             Ok(InventoryItem {
                 barcode: row.get(0)?,
                 department: row.get(1)?,
                 description: row.get(2)?,
+                alert_threshold_hours: row.get(3)?,
             })
         })?;
         let mut items = Vec::new();
@@ -305,32 +1566,85 @@ impl Database {
         Ok(items)
     }
 
-    pub fn add_item(&self, barcode: &str, department: Option<&str>, description: Option<&str>) -> Result<()> {
-        self.conn.execute(
-            "INSERT OR REPLACE INTO items (barcode, department, description) VALUES (?1, ?2, ?3)",
-            params![barcode, department, description],
-        )?;
-        Ok(())
+    pub fn get_item(&self, barcode: &str) -> Result<Option<InventoryItem>> {
+        self.conn
+            .prepare_cached("SELECT barcode, department, description, alert_threshold_hours FROM items WHERE barcode = ?1")?
+            .query_row(params![normalize_barcode(barcode)], |row| {
+                Ok(InventoryItem {
+                    barcode: row.get(0)?,
+                    department: row.get(1)?,
+                    description: row.get(2)?,
+                    alert_threshold_hours: row.get(3)?,
+                })
+            })
+            .optional()
     }
 
-    pub fn update_item(&self, barcode: &str, department: Option<&str>, description: Option<&str>) -> Result<()> {
-        self.conn.execute(
-            "UPDATE items SET department = ?2, description = ?3 WHERE barcode = ?1",
-            params![barcode, department, description],
-        )?;
-        Ok(())
+    /// Runs a catalogue change for one item and records it in the audit trail
+    fn audited_item_change(&self, action: &str, barcode: &str, operator: Option<&str>, change: impl FnOnce(&str) -> Result<()>) -> Result<()> {
+        let barcode = normalize_barcode(barcode);
+        self.in_write_transaction(|| {
+            let before = self.get_item(&barcode)?;
+            change(&barcode)?;
+            let after = self.get_item(&barcode)?;
+            self.record_audit(
+                operator,
+                action,
+                "item",
+                Some(&barcode),
+                before.map(|before| to_audit_json(&before)),
+                after.map(|after| to_audit_json(&after)),
+            )
+        })
     }
 
-    pub fn delete_item(&self, barcode: &str) -> Result<()> {
-        self.conn.execute(
-            "DELETE FROM items WHERE barcode = ?1",
-            params![barcode],
-        )?;
-        Ok(())
+    pub fn add_item(&self, barcode: &str, department: Option<&str>, description: Option<&str>, operator: Option<&str>) -> Result<()> {
+        self.audited_item_change("add_item", barcode, operator, |barcode| {
+            self.conn.execute(
+                "INSERT INTO items (barcode, department, description) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(barcode) DO UPDATE SET department = excluded.department, description = excluded.description",
+                params![barcode, department, description],
+            )?;
+            Ok(())
+        })
+    }
+
+    pub fn update_item(&self, barcode: &str, department: Option<&str>, description: Option<&str>, operator: Option<&str>) -> Result<()> {
+        self.audited_item_change("update_item", barcode, operator, |barcode| {
+            self.conn.execute(
+                "UPDATE items SET department = ?2, description = ?3 WHERE barcode = ?1",
+                params![barcode, department, description],
+            )?;
+            Ok(())
+        })
+    }
+
+    /// Sets or, with `None`, clears an item's own overdue threshold. Items not
+    /// in the catalogue yet are added with the department from their prefix.
+    pub fn set_item_threshold(&self, barcode: &str, hours: Option<i64>, operator: Option<&str>) -> Result<()> {
+        self.audited_item_change("set_item_threshold", barcode, operator, |barcode| {
+            let department = self.get_department_from_barcode(barcode)?;
+            self.conn.execute(
+                "INSERT INTO items (barcode, department, alert_threshold_hours) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(barcode) DO UPDATE SET alert_threshold_hours = excluded.alert_threshold_hours",
+                params![barcode, department, hours],
+            )?;
+            Ok(())
+        })
+    }
+
+    pub fn delete_item(&self, barcode: &str, operator: Option<&str>) -> Result<()> {
+        self.audited_item_change("delete_item", barcode, operator, |barcode| {
+            self.conn.execute(
+                "DELETE FROM items WHERE barcode = ?1",
+                params![barcode],
+            )?;
+            Ok(())
+        })
     }
 
     pub fn get_department_from_barcode(&self, barcode: &str) -> Result<Option<String>> {
-        let mut stmt = self.conn.prepare(
+        let mut stmt = self.conn.prepare_cached(
             "SELECT department FROM department_mappings WHERE ?1 LIKE prefix || '%' ORDER BY LENGTH(prefix) DESC LIMIT 1"
         )?;
         
@@ -346,42 +1660,236 @@ impl Database {
     }
 
     /// Clean up old logs to prevent infinite database growth
-    /// Removes logs older than specified days, keeping only logs that have active check-outs
+    /// Moves logs older than specified days to the yearly archives, keeping
+    /// everything for items that are currently checked out
     pub fn cleanup_old_logs(&self, days_to_keep: i32) -> Result<usize> {
-        // Calculate the cutoff date
+        self.move_to_archive(
+            "cleanup_old_logs",
+            days_to_keep,
+            "barcode NOT IN (SELECT barcode FROM item_status WHERE state = 'check-out')",
+        )
+    }
+
+    /// How many days of completed history stay in the live database
+    pub fn get_archive_retention_days(&self) -> Result<i32> {
+        Ok(self
+            .get_setting("archive_retention_days")?
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(DEFAULT_ARCHIVE_RETENTION_DAYS))
+    }
+
+    /// Moves logs older than `days_to_keep` that match `log_filter` (an SQL
+    /// condition on `logs`), plus every loan closed before the cutoff, into the
+    /// archive file for the year they belong to. Returns the number of logs moved.
+    fn move_to_archive(&self, action: &str, days_to_keep: i32, log_filter: &str) -> Result<usize> {
         let cutoff_date = Local::now() - chrono::Duration::days(days_to_keep as i64);
-        let cutoff_timestamp = cutoff_date.to_rfc3339();
-        
-        // Delete old logs that are not part of currently checked-out items
-        let deleted_count = self.conn.execute(
-            "DELETE FROM logs 
-             WHERE timestamp < ?1 
-             AND NOT EXISTS (
-                 SELECT 1 FROM logs current_out 
-                 WHERE current_out.barcode = logs.barcode 
-                 AND current_out.action = 'check-out'
-                 AND NOT EXISTS (
-                     SELECT 1 FROM logs check_in 
-                     WHERE check_in.barcode = current_out.barcode 
-                     AND check_in.action = 'check-in' 
-                     AND check_in.timestamp > current_out.timestamp
-                 )
+        let cutoff_timestamp = to_db_time(&cutoff_date);
+
+        // Readers open the archives read-only, so older files are upgraded here
+        archive::upgrade_archives(&self.path)?;
+
+        // Pick the rows up front: a loan and its scans can end up in different
+        // years, and the filter may depend on loans moved by an earlier year
+        self.conn.execute_batch(
+            "CREATE TEMP TABLE IF NOT EXISTS archive_batch (id INTEGER PRIMARY KEY);
+             DELETE FROM temp.archive_batch;",
+        )?;
+        self.conn.execute(
+            &format!(
+                "INSERT INTO temp.archive_batch (id) SELECT id FROM logs WHERE timestamp < ?1 AND ({})",
+                log_filter
+            ),
+            params![cutoff_timestamp],
+        )?;
+
+        let (first, last): (Option<i64>, Option<i64>) = self.conn.query_row(
+            "SELECT MIN(time), MAX(time) FROM (
+                 SELECT timestamp AS time FROM logs WHERE id IN (SELECT id FROM temp.archive_batch)
+                 UNION ALL
+                 SELECT out_time AS time FROM loans WHERE in_time < ?1
              )",
             params![cutoff_timestamp],
+            |row| Ok((row.get(0)?, row.get(1)?)),
         )?;
-        
-        Ok(deleted_count)
+        let (Some(first_year), Some(last_year)) = (first.and_then(archive::year_of), last.and_then(archive::year_of)) else {
+            return Ok(0);
+        };
+
+        let mut moved = 0;
+        for year in first_year..=last_year {
+            let path = archive::archive_path(&self.path, year);
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir).unwrap_or_default();
+            }
+
+            // ATTACH isn't allowed inside a transaction, so attach first and move
+            // the rows in one write transaction that covers both files
+            self.conn.execute(
+                "ATTACH DATABASE ?1 AS archive",
+                params![path.to_string_lossy()],
+            )?;
+            let result = archive::ensure_schema(&self.conn, archive::ATTACHED_SCHEMA)
+                .and_then(|_| self.move_year_to_archive(year, cutoff_timestamp));
+            self.conn.execute("DETACH DATABASE archive", [])?;
+            moved += result?;
+        }
+
+        self.conn.execute("DELETE FROM temp.archive_batch", [])?;
+        if moved > 0 {
+            let after = serde_json::json!({ "moved_to_archive": moved, "days_to_keep": days_to_keep });
+            self.record_audit(None, action, "logs", None, None, Some(after.to_string()))?;
+        }
+        Ok(moved)
+    }
+
+    fn move_year_to_archive(&self, year: i32, cutoff_timestamp: i64) -> Result<usize> {
+        let (start, end) = archive::year_bounds(year);
+        self.in_write_transaction(|| {
+            self.conn.execute(
+                &format!(
+                    "INSERT OR IGNORE INTO archive.logs ({columns})
+                     SELECT {columns} FROM main.logs
+                     WHERE id IN (SELECT id FROM temp.archive_batch) AND timestamp >= ?1 AND timestamp < ?2",
+                    columns = LOG_COLUMNS
+                ),
+                params![start, end],
+            )?;
+            let moved = self.conn.execute(
+                "DELETE FROM main.logs
+                 WHERE id IN (SELECT id FROM temp.archive_batch) AND timestamp >= ?1 AND timestamp < ?2",
+                params![start, end],
+            )?;
+
+            self.conn.execute(
+                &format!(
+                    "INSERT OR IGNORE INTO archive.loans ({columns})
+                     SELECT {columns} FROM main.loans WHERE in_time < ?1 AND out_time >= ?2 AND out_time < ?3",
+                    columns = LOAN_COLUMNS
+                ),
+                params![cutoff_timestamp, start, end],
+            )?;
+            self.conn.execute(
+                "DELETE FROM main.loans WHERE in_time < ?1 AND out_time >= ?2 AND out_time < ?3",
+                params![cutoff_timestamp, start, end],
+            )?;
+
+            Ok(moved)
+        })
+    }
+
+    /// Archive files next to the database with their row counts
+    pub fn list_archives(&self) -> Result<Vec<ArchiveFile>> {
+        archive::list_archives(&self.path)
+    }
+
+    /// Like [`Database::get_logs`], including scans moved to the archives.
+    /// Scans of items still out stay live, so the two are merged by time.
+    pub fn get_logs_with_archives(&self, limit: Option<i64>, include_voided: bool) -> Result<Vec<ScanLog>> {
+        let mut logs = self.get_logs(limit, include_voided)?;
+        logs.extend(archive::read_logs(&self.path, limit, include_voided)?);
+        logs.sort_by(|a, b| b.timestamp.cmp(&a.timestamp).then(b.id.cmp(&a.id)));
+        if let Some(limit) = limit {
+            logs.truncate(limit.max(0) as usize);
+        }
+        Ok(logs)
+    }
+
+    /// Like [`Database::get_loans`], including loans moved to the archives
+    pub fn get_loans_with_archives(&self, limit: Option<i64>) -> Result<Vec<Loan>> {
+        let mut loans = self.get_loans(limit)?;
+        loans.extend(archive::read_loans(&self.path, limit)?);
+        loans.sort_by(|a, b| b.out_time.cmp(&a.out_time).then(b.id.cmp(&a.id)));
+        if let Some(limit) = limit {
+            loans.truncate(limit.max(0) as usize);
+        }
+        Ok(loans)
+    }
+
+    /// Every loan of one item, live and archived, oldest first
+    pub fn get_item_loans(&self, barcode: &str) -> Result<Vec<Loan>> {
+        let barcode = normalize_barcode(barcode);
+        let mut loans = archive::read_item_loans(&self.path, &barcode)?;
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT {} FROM loans WHERE barcode = ?1 ORDER BY out_time, id",
+            LOAN_COLUMNS
+        ))?;
+        for loan in stmt.query_map(params![barcode], loan_from_row)? {
+            loans.push(loan?);
+        }
+        // Archived loans are all closed, but an old one can still postdate a live one
+        loans.sort_by(|a, b| a.out_time.cmp(&b.out_time).then(a.id.cmp(&b.id)));
+        Ok(loans)
+    }
+
+    /// Loans that started in [from, to), live and archived, oldest first
+    pub fn get_loans_between(&self, from: Option<DateTime<Local>>, to: Option<DateTime<Local>>) -> Result<Vec<Loan>> {
+        let from = from.map(|from| to_db_time(&from));
+        let to = to.map(|to| to_db_time(&to));
+        let mut loans = archive::read_loans_between(&self.path, from, to)?;
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT {} FROM loans
+             WHERE (?1 IS NULL OR out_time >= ?1) AND (?2 IS NULL OR out_time < ?2)
+             ORDER BY out_time, id",
+            LOAN_COLUMNS
+        ))?;
+        for loan in stmt.query_map(params![from, to], loan_from_row)? {
+            loans.push(loan?);
+        }
+        loans.sort_by(|a, b| a.out_time.cmp(&b.out_time).then(a.id.cmp(&b.id)));
+        Ok(loans)
+    }
+
+    /// Utilization and turnaround for loans started in [from, to)
+    pub fn get_analytics(&self, from: Option<DateTime<Local>>, to: Option<DateTime<Local>>, top: usize) -> Result<Analytics> {
+        let loans = self.get_loans_between(from, to)?;
+        let catalogue = self.get_items(None)?;
+        Ok(Analytics::compute(&loans, &catalogue, from, to, top))
+    }
+
+    /// Complete lifecycle of one item from live and archived history,
+    /// optionally limited to [from, to)
+    pub fn get_item_history(&self, barcode: &str, from: Option<DateTime<Local>>, to: Option<DateTime<Local>>) -> Result<ItemHistory> {
+        let barcode = normalize_barcode(barcode);
+
+        // All of it, voided scans included: forced scans and the last sighting
+        // can only be worked out from the full sequence
+        let mut query = LogQuery {
+            barcode: Some(barcode.clone()),
+            include_voided: true,
+            include_archived: true,
+            descending: false,
+            page_size: Some(MAX_PAGE_SIZE),
+            ..Default::default()
+        };
+        let mut logs = Vec::new();
+        loop {
+            let page = self.query_logs(&query)?;
+            logs.extend(page.logs);
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => break,
+            }
+        }
+
+        Ok(ItemHistory::build(
+            barcode.clone(),
+            self.get_item(&barcode)?,
+            logs,
+            self.get_item_loans(&barcode)?,
+            self.get_audit_log_for("item", &barcode)?,
+            from,
+            to,
+        ))
     }
 
     /// Get database statistics including total logs and size
     pub fn get_database_stats(&self) -> Result<(i64, String)> {
         // Get total log count
-        let mut stmt = self.conn.prepare("SELECT COUNT(*) FROM logs")?;
+        let mut stmt = self.conn.prepare_cached("SELECT COUNT(*) FROM logs")?;
         let total_logs: i64 = stmt.query_row([], |row| row.get(0))?;
         
         // Get database file size
-        let db_path = Self::get_db_path();
-        let file_size = std::fs::metadata(&db_path)
+        let file_size = std::fs::metadata(&self.path)
             .map(|m| m.len())
             .unwrap_or(0);
         
@@ -395,10 +1903,12 @@ impl Database {
         Ok((total_logs, size_str))
     }
 
-    /// Get item name by barcode
+    /// Get item name by barcode (the catalogue description)
     pub fn get_item_name(&self, barcode: &str) -> Result<Option<String>> {
-        let mut stmt = self.conn.prepare("SELECT name FROM item_names WHERE barcode = ?1")?;
-        match stmt.query_row(params![barcode.to_uppercase()], |row| {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT description FROM items WHERE barcode = ?1 AND description IS NOT NULL AND description <> ''"
+        )?;
+        match stmt.query_row(params![normalize_barcode(barcode)], |row| {
             Ok(row.get::<_, String>(0)?)
         }) {
             Ok(name) => Ok(Some(name)),
@@ -407,64 +1917,171 @@ impl Database {
         }
     }
 
-    /// Set item name for barcode
-    pub fn set_item_name(&self, barcode: &str, name: &str) -> Result<()> {
-        self.conn.execute(
-            "INSERT OR REPLACE INTO item_names (barcode, name) VALUES (?1, ?2)",
-            params![barcode.to_uppercase(), name],
-        )?;
-        Ok(())
+    /// Set item name for barcode, keeping any department already in the catalogue
+    pub fn set_item_name(&self, barcode: &str, name: &str, operator: Option<&str>) -> Result<()> {
+        self.audited_item_change("set_item_name", barcode, operator, |barcode| {
+            let department = self.get_department_from_barcode(barcode)?;
+            self.conn.execute(
+                "INSERT INTO items (barcode, department, description) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(barcode) DO UPDATE SET description = excluded.description",
+                params![barcode, department, name],
+            )?;
+            Ok(())
+        })
     }
 
-    /// Import multiple item names from a list
-    pub fn import_item_names(&self, items: &[(String, String)]) -> Result<usize> {
-        let tx = self.conn.unchecked_transaction()?;
-        let mut count = 0;
-        
-        for (barcode, name) in items {
-            tx.execute(
-                "INSERT OR REPLACE INTO item_names (barcode, name) VALUES (?1, ?2)",
-                params![barcode.to_uppercase(), name],
-            )?;
-            count += 1;
-        }
-        
-        tx.commit()?;
-        Ok(count)
+    /// Import multiple (barcode, name) pairs into the catalogue.
+    /// Existing items keep their department; new items get one from the barcode prefix.
+    pub fn import_items(&self, items: &[(String, String)], operator: Option<&str>) -> Result<usize> {
+        self.in_write_transaction(|| {
+            for (barcode, name) in items {
+                self.audited_item_change("import_items", barcode, operator, |barcode| {
+                    let department = self.get_department_from_barcode(barcode)?;
+                    self.conn.execute(
+                        "INSERT INTO items (barcode, department, description) VALUES (?1, ?2, ?3)
+                         ON CONFLICT(barcode) DO UPDATE SET description = excluded.description",
+                        params![barcode, department, name],
+                    )?;
+                    Ok(())
+                })?;
+            }
+            Ok(items.len())
+        })
     }
 
     /// Get formatted display name for barcode (name + barcode or just barcode)
     pub fn get_display_name(&self, barcode: &str) -> Result<String> {
         match self.get_item_name(barcode)? {
-            Some(name) => Ok(format!("{} ({})", name.to_uppercase(), barcode.to_uppercase())),
-            None => Ok(barcode.to_uppercase()),
+            Some(name) => Ok(format!("{} ({})", name.to_uppercase(), normalize_barcode(barcode))),
+            None => Ok(normalize_barcode(barcode)),
         }
     }
 
     /// Archive old completed transactions (check-out followed by check-in pairs)
-    /// This keeps the database lean while preserving important data
+    /// This keeps the database lean while preserving important data: the loans
+    /// and their scans are moved to the yearly archive files, not deleted
     pub fn archive_completed_transactions(&self, days_to_keep: i32) -> Result<usize> {
-        let cutoff_date = Local::now() - chrono::Duration::days(days_to_keep as i64);
-        let cutoff_timestamp = cutoff_date.to_rfc3339();
-        
-        // Delete old check-in/check-out pairs that are completed
-        let deleted_count = self.conn.execute(
-            "DELETE FROM logs 
-             WHERE timestamp < ?1 
-             AND barcode IN (
-                 SELECT DISTINCT barcode FROM logs completed
-                 WHERE completed.action = 'check-in' 
-                 AND completed.timestamp < ?1
-                 AND EXISTS (
-                     SELECT 1 FROM logs checkout 
-                     WHERE checkout.barcode = completed.barcode 
-                     AND checkout.action = 'check-out' 
-                     AND checkout.timestamp < completed.timestamp
-                 )
+        self.move_to_archive(
+            "archive_completed_transactions",
+            days_to_keep,
+            "id IN (
+                 SELECT out_log_id FROM loans WHERE in_time < ?1
+                 UNION ALL
+                 SELECT in_log_id FROM loans WHERE in_time < ?1
              )",
-            params![cutoff_timestamp],
-        )?;
-        
-        Ok(deleted_count)
+        )
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    /// Path of a database file in an empty directory of its own
+    pub(crate) fn test_db_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("inventory-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("inventory.db")
+    }
+
+    /// A fresh, migrated database file of its own for each test
+    pub(crate) fn test_db(name: &str) -> Database {
+        Database::open_at(test_db_path(name)).unwrap()
+    }
+
+    /// (out_log_id, in_log_id) of every loan for `barcode`
+    fn loan_pairs(db: &Database, barcode: &str) -> Vec<(i64, Option<i64>)> {
+        db.get_item_loans(barcode)
+            .unwrap()
+            .iter()
+            .map(|loan| (loan.out_log_id, loan.in_log_id))
+            .collect()
+    }
+
+    #[test]
+    fn voiding_a_check_in_repairs_the_loans() {
+        let db = test_db("void-loans");
+        let start = Local::now() - Duration::hours(4);
+        let ids: Vec<i64> = ["check-out", "check-in", "check-out", "check-in"]
+            .iter()
+            .enumerate()
+            .map(|(n, action)| {
+                db.log_scan_at("ORTX1", action, Some("Ortopedi"), start + Duration::minutes(n as i64 * 10))
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(loan_pairs(&db, "ORTX1"), vec![(ids[0], Some(ids[1])), (ids[2], Some(ids[3]))]);
+
+        // Without the first check-in the second check-out is a repeat and the loan runs on
+        db.void_scan(ids[1], "Mis-scan", None).unwrap();
+        assert_eq!(loan_pairs(&db, "ORTX1"), vec![(ids[0], Some(ids[3]))]);
+
+        db.void_scan(ids[3], "Mis-scan", None).unwrap();
+        assert_eq!(loan_pairs(&db, "ORTX1"), vec![(ids[0], None)]);
+
+        db.void_scan(ids[0], "Mis-scan", None).unwrap();
+        assert_eq!(loan_pairs(&db, "ORTX1"), vec![(ids[2], None)]);
+
+        db.void_scan(ids[2], "Mis-scan", None).unwrap();
+        assert!(loan_pairs(&db, "ORTX1").is_empty());
+    }
+
+    #[test]
+    fn voiding_a_check_in_reopens_a_loan_whose_check_out_was_archived() {
+        let db = test_db("void-archived");
+        let out_id = db
+            .log_scan_at("ORTX1", "check-out", Some("Ortopedi"), Local::now() - Duration::days(60))
+            .unwrap();
+        let in_id = db
+            .log_scan_at("ORTX1", "check-in", Some("Ortopedi"), Local::now() - Duration::hours(2))
+            .unwrap();
+
+        // The item is back, so its old check-out goes; the loan closed recently and stays
+        assert_eq!(db.cleanup_old_logs(30).unwrap(), 1);
+        assert!(db.get_log(out_id).unwrap().is_none());
+        assert_eq!(loan_pairs(&db, "ORTX1"), vec![(out_id, Some(in_id))]);
+
+        db.void_scan(in_id, "Scanned the wrong set", None).unwrap();
+        assert_eq!(loan_pairs(&db, "ORTX1"), vec![(out_id, None)]);
+        let checked_out: Vec<(Option<i64>, String)> = db
+            .get_checked_out_items()
+            .unwrap()
+            .into_iter()
+            .map(|log| (log.id, log.barcode))
+            .collect();
+        assert_eq!(checked_out, vec![(Some(out_id), "ORTX1".to_string())]);
+
+        // The real check-in closes the same loan
+        let again = db.log_scan("ORTX1", "check-in", Some("Ortopedi")).unwrap();
+        assert_eq!(loan_pairs(&db, "ORTX1"), vec![(out_id, Some(again))]);
+        assert!(db.get_checked_out_items().unwrap().is_empty());
+    }
+
+    #[test]
+    fn lower_case_scans_show_up_in_the_item_history() {
+        let db = test_db("lower-case-history");
+        db.add_item("KÄKX019", Some("Käkkirurgi"), Some("Spegel"), None).unwrap();
+
+        // What a keyboard wedge with caps lock off types
+        assert_eq!(db.record_scan("käkx019", Some("Käkkirurgi")).unwrap(), "check-out");
+        assert_eq!(db.record_scan(" KÄKX019", Some("Käkkirurgi")).unwrap(), "check-in");
+        db.log_scan("käkx019", "check-out", Some("Käkkirurgi")).unwrap();
+
+        let history = db.get_item_history("KÄKX019", None, None).unwrap();
+        let scans = history
+            .timeline
+            .iter()
+            .filter(|entry| matches!(entry.event, crate::history::TimelineEvent::Scan { .. }))
+            .count();
+        assert_eq!(scans, 3);
+        assert_eq!(history.loans.len(), 2);
+        assert!(history.stats.currently_out);
+        assert_eq!(history.item.and_then(|item| item.description).as_deref(), Some("Spegel"));
+
+        assert_eq!(db.get_item_loans("käkx019").unwrap().len(), 2);
+        let checked_out: Vec<String> = db.get_checked_out_items().unwrap().into_iter().map(|log| log.barcode).collect();
+        assert_eq!(checked_out, vec!["KÄKX019"]);
     }
 }
@@ -0,0 +1,190 @@
+use crate::database::Database;
+use crate::offline_queue::is_unavailable;
+use rusqlite::{ffi, ErrorCode, Result};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Message shown to the user when another workstation keeps the shared file locked
+pub const LOCKED_BY_OTHER_STATION: &str = "Database locked by another station";
+
+/// How hard to retry when the database file is locked by another process.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Interactive commands: give up after a few seconds so the UI stays responsive
+    pub const DEFAULT: RetryPolicy = RetryPolicy {
+        max_attempts: 4,
+        initial_backoff: Duration::from_millis(50),
+        max_backoff: Duration::from_secs(1),
+    };
+
+    /// Recording a scan: keep trying for longer before the scan is handed to
+    /// the offline queue, since a colleague's station usually lets go quickly
+    pub const SCAN: RetryPolicy = RetryPolicy {
+        max_attempts: 8,
+        initial_backoff: Duration::from_millis(50),
+        max_backoff: Duration::from_secs(2),
+    };
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self.initial_backoff.saturating_mul(1 << attempt.min(16));
+        let base = exp.min(self.max_backoff);
+        // Jitter so stations that collided don't retry in lockstep
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let jitter = Duration::from_millis(u64::from(nanos % 50));
+        base + jitter
+    }
+}
+
+/// True for errors caused by another connection holding the lock
+pub fn is_lock_contention(error: &rusqlite::Error) -> bool {
+    matches!(
+        error.sqlite_error_code(),
+        Some(ErrorCode::DatabaseBusy) | Some(ErrorCode::DatabaseLocked)
+    )
+}
+
+/// True for I/O-level failures where the file handle itself is no longer usable
+fn is_connection_lost(error: &rusqlite::Error) -> bool {
+    matches!(
+        error.sqlite_error_code(),
+        Some(ErrorCode::CannotOpen) | Some(ErrorCode::SystemIoFailure)
+    )
+}
+
+/// Runs `f` until it succeeds, fails with something other than lock
+/// contention, or the policy runs out of attempts.
+fn retry<T>(policy: RetryPolicy, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(e) if is_lock_contention(&e) => {
+                attempt += 1;
+                if attempt >= policy.max_attempts {
+                    eprintln!("Giving up after {} attempts: {}", attempt, e);
+                    return Err(rusqlite::Error::SqliteFailure(
+                        ffi::Error::new(ffi::SQLITE_BUSY),
+                        Some(format!("{}, please try again", LOCKED_BY_OTHER_STATION)),
+                    ));
+                }
+                std::thread::sleep(policy.backoff(attempt));
+            }
+            other => return other,
+        }
+    }
+}
+
+/// Shared, long-lived handle to the inventory database.
+///
+/// The connection is opened (and migrated) once at startup and then shared by
+/// the Tauri commands, `Logger`, `Exporter`, `AlertManager` and the scanner
+/// thread. Cloning is cheap; every clone talks to the same connection.
+///
+/// When the share is down at startup there is no connection yet; the first
+/// call after it comes back opens one.
+#[derive(Clone)]
+pub struct DbService {
+    inner: Arc<Mutex<Option<Database>>>,
+}
+
+impl DbService {
+    pub fn open() -> Result<Self> {
+        // Several stations starting together all try to migrate the schema
+        let db = retry(RetryPolicy::SCAN, Database::new)?;
+        Ok(DbService {
+            inner: Arc::new(Mutex::new(Some(db))),
+        })
+    }
+
+    /// Like [`DbService::open`], but starts without a connection when the
+    /// shared file can't be reached, so the station can still queue scans
+    pub fn open_or_offline() -> Result<Self> {
+        match Self::open() {
+            Err(e) if is_unavailable(&e) => {
+                eprintln!("Shared database unavailable at startup, scans will be queued: {}", e);
+                Ok(DbService {
+                    inner: Arc::new(Mutex::new(None)),
+                })
+            }
+            other => other,
+        }
+    }
+
+    /// Whether a connection is open; false until the share was first reached
+    pub fn is_connected(&self) -> bool {
+        self.lock().is_some()
+    }
+
+    /// Runs `f` with exclusive access to the connection, retrying with the
+    /// default policy while another station holds the lock.
+    pub fn with<T>(&self, f: impl FnMut(&Database) -> Result<T>) -> Result<T> {
+        self.with_policy(RetryPolicy::DEFAULT, f)
+    }
+
+    /// Like [`DbService::with`] with an explicit retry policy.
+    ///
+    /// `f` may run more than once, so it must either be a single statement or
+    /// wrap its writes in a transaction.
+    pub fn with_policy<T>(&self, policy: RetryPolicy, mut f: impl FnMut(&Database) -> Result<T>) -> Result<T> {
+        let result = retry(policy, || {
+            // The mutex is released between attempts so other threads aren't stuck behind our backoff
+            let db = self.connected()?;
+            f(db.as_ref().expect("connected() opens the database"))
+        });
+
+        match result {
+            Err(e) if is_connection_lost(&e) => {
+                // The network share may have dropped and come back; the old file
+                // handle stays dead, so reopen once before reporting the error
+                self.reconnect().map_err(|_| e)?;
+                let db = self.lock();
+                f(db.as_ref().expect("reconnect() opens the database"))
+            }
+            other => other,
+        }
+    }
+
+    /// Replaces the connection with a freshly opened one
+    pub fn reconnect(&self) -> Result<()> {
+        let fresh = Database::new()?;
+        *self.lock() = Some(fresh);
+        println!("Reconnected to the shared database");
+        Ok(())
+    }
+
+    /// The lock, with a connection opened first if there isn't one yet
+    fn connected(&self) -> Result<MutexGuard<'_, Option<Database>>> {
+        let mut db = self.lock();
+        if db.is_none() {
+            let fresh = Database::new()?;
+            println!("Connected to the shared database");
+            println!("{}", fresh.migration_report());
+            *db = Some(fresh);
+        }
+        Ok(db)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<Database>> {
+        // A panic while holding the lock doesn't corrupt the connection itself,
+        // so keep serving requests instead of failing every command from then on
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+impl DbService {
+    /// Shares a database opened by the test helpers in `database::tests`
+    pub(crate) fn from_database(db: Database) -> Self {
+        DbService {
+            inner: Arc::new(Mutex::new(Some(db))),
+        }
+    }
+}
@@ -1,22 +1,24 @@
-use crate::database::Database;
+use crate::analytics::AnalyticsReport;
+use crate::database::ScanLog;
+use crate::db_service::DbService;
+use crate::log_query::{LogQuery, MAX_PAGE_SIZE};
+use chrono::{DateTime, Local};
 use csv::Writer;
 use std::path::PathBuf;
 use std::io::Write;
 use serde_json;
 
 pub struct Exporter {
-    db: Database,
+    db: DbService,
 }
 
 impl Exporter {
-    pub fn new() -> Result<Self, rusqlite::Error> {
-        let db = Database::new()?;
-        Ok(Exporter { db })
+    pub fn new(db: DbService) -> Self {
+        Exporter { db }
     }
 
-    pub fn export_logs_to_csv(&self, file_path: &str, limit: Option<i64>) -> Result<(), Box<dyn std::error::Error>> {
-        let logs = self.db.get_logs(limit)?;
-        
+    /// Writes every log matching `query` (all pages, up to `limit` rows)
+    pub fn export_logs_to_csv(&self, file_path: &str, query: &LogQuery, limit: Option<i64>) -> Result<(), Box<dyn std::error::Error>> {
         // Create file with UTF-8 BOM to ensure proper encoding
         let mut file = std::fs::File::create(file_path)?;
         file.write_all(&[0xEF, 0xBB, 0xBF])?; // UTF-8 BOM
@@ -24,32 +26,112 @@ impl Exporter {
         let mut wtr = Writer::from_writer(file);
 
         // Write header
-        wtr.write_record(&["Timestamp", "Barcode", "Action", "Department"])?;
+        wtr.write_record(&["Timestamp", "Barcode", "Action", "Department", "Station", "Voided", "Void reason"])?;
 
-        // Write data
-        for log in logs {
+        // Write data a page at a time instead of loading the whole history
+        self.for_each_log(query, limit, |log| {
             wtr.write_record(&[
                 log.timestamp.format("%Y-%m-%d %H:%M:%S").to_string(),
                 log.barcode,
                 log.action,
                 log.department.unwrap_or_default(),
+                log.station.unwrap_or_default(),
+                log.voided_at.map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string()).unwrap_or_default(),
+                log.void_reason.unwrap_or_default(),
             ])?;
-        }
+            Ok(())
+        })?;
 
         wtr.flush()?;
         Ok(())
     }
 
-    pub fn export_logs_to_json(&self, file_path: &str, limit: Option<i64>) -> Result<(), Box<dyn std::error::Error>> {
-        let logs = self.db.get_logs(limit)?;
+    pub fn export_logs_to_json(&self, file_path: &str, query: &LogQuery, limit: Option<i64>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut logs = Vec::new();
+        self.for_each_log(query, limit, |log| {
+            logs.push(log);
+            Ok(())
+        })?;
         let json = serde_json::to_string_pretty(&logs)?;
         // Explicitly write as UTF-8
         std::fs::write(file_path, json.as_bytes())?;
         Ok(())
     }
 
-    pub fn export_checked_out_to_csv(&self, file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let items = self.db.get_checked_out_items()?;
+    /// Pages through `query` from its cursor, handing each row to `f`
+    fn for_each_log(
+        &self,
+        query: &LogQuery,
+        limit: Option<i64>,
+        mut f: impl FnMut(ScanLog) -> Result<(), Box<dyn std::error::Error>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut query = LogQuery {
+            page_size: Some(MAX_PAGE_SIZE),
+            ..query.clone()
+        };
+        let mut remaining = limit;
+        loop {
+            let page = self.db.with(|db| db.query_logs(&query))?;
+            for log in page.logs {
+                if remaining == Some(0) {
+                    return Ok(());
+                }
+                f(log)?;
+                remaining = remaining.map(|remaining| remaining - 1);
+            }
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Audit trail for the quality department, newest change first
+    pub fn export_audit_log_to_csv(&self, file_path: &str, entity: Option<&str>, limit: Option<i64>) -> Result<(), Box<dyn std::error::Error>> {
+        let entries = self.db.with(|db| db.get_audit_log(entity, limit))?;
+        
+        // Create file with UTF-8 BOM to ensure proper encoding
+        let mut file = std::fs::File::create(file_path)?;
+        file.write_all(&[0xEF, 0xBB, 0xBF])?; // UTF-8 BOM
+        
+        let mut wtr = Writer::from_writer(file);
+
+        // Write header
+        wtr.write_record(["Tidpunkt", "Station", "Användare", "Åtgärd", "Typ", "Nyckel", "Före", "Efter"])?;
+
+        // Write data
+        for entry in entries {
+            wtr.write_record(&[
+                entry.timestamp.format("%Y-%m-%d %H:%M:%S").to_string(),
+                entry.station.unwrap_or_default(),
+                entry.operator.unwrap_or_default(),
+                entry.action,
+                entry.entity,
+                entry.entity_key.unwrap_or_default(),
+                entry.before_value.unwrap_or_default(),
+                entry.after_value.unwrap_or_default(),
+            ])?;
+        }
+
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Writes the items checked out now to a timestamped file in the default
+    /// export folder and returns its path
+    pub fn quick_export_checked_out(&self) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let timestamp = chrono::Utc::now().format("%Y%m%d_%H%M%S");
+        let file_path = Self::get_default_export_path().join(format!("utcheckade_artiklar_{}.csv", timestamp));
+        self.export_checked_out_to_csv(&file_path.to_string_lossy(), None)?;
+        Ok(file_path)
+    }
+
+    /// Items checked out now, or at `at` when given
+    pub fn export_checked_out_to_csv(&self, file_path: &str, at: Option<DateTime<Local>>) -> Result<(), Box<dyn std::error::Error>> {
+        let items = match at {
+            Some(at) => self.db.with(|db| db.get_checked_out_items_at(at))?,
+            None => self.db.with(|db| db.get_checked_out_items())?,
+        };
         
         // Create file with UTF-8 BOM to ensure proper encoding
         let mut file = std::fs::File::create(file_path)?;
@@ -74,6 +156,121 @@ impl Exporter {
         Ok(())
     }
 
+    /// One row per loan with its check-out, check-in and duration
+    pub fn export_loans_to_csv(&self, file_path: &str, limit: Option<i64>, include_archived: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let loans = if include_archived {
+            self.db.with(|db| db.get_loans_with_archives(limit))?
+        } else {
+            self.db.with(|db| db.get_loans(limit))?
+        };
+        
+        // Create file with UTF-8 BOM to ensure proper encoding
+        let mut file = std::fs::File::create(file_path)?;
+        file.write_all(&[0xEF, 0xBB, 0xBF])?; // UTF-8 BOM
+        
+        let mut wtr = Writer::from_writer(file);
+
+        // Write header
+        wtr.write_record(["Streckkod", "Enhet", "Utcheckad tid", "Utcheckad av", "Incheckad tid", "Incheckad av", "Lånetid (min)"])?;
+
+        // Write data
+        for loan in loans {
+            wtr.write_record(&[
+                loan.barcode,
+                loan.department.unwrap_or_default(),
+                loan.out_time.format("%Y-%m-%d %H:%M:%S").to_string(),
+                loan.out_station.unwrap_or_default(),
+                loan.in_time.map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string()).unwrap_or_default(),
+                loan.in_station.unwrap_or_default(),
+                loan.duration_minutes.map(|m| m.to_string()).unwrap_or_default(),
+            ])?;
+        }
+
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// One table of the analytics for loans started in [from, to)
+    pub fn export_analytics_to_csv(
+        &self,
+        file_path: &str,
+        report: AnalyticsReport,
+        from: Option<DateTime<Local>>,
+        to: Option<DateTime<Local>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Every item, not just the top ones, when writing the usage table
+        let analytics = self.db.with(|db| db.get_analytics(from, to, usize::MAX))?;
+        
+        // Create file with UTF-8 BOM to ensure proper encoding
+        let mut file = std::fs::File::create(file_path)?;
+        file.write_all(&[0xEF, 0xBB, 0xBF])?; // UTF-8 BOM
+        
+        let mut wtr = Writer::from_writer(file);
+        let optional = |value: Option<i64>| value.map(|v| v.to_string()).unwrap_or_default();
+
+        match report {
+            AnalyticsReport::Departments | AnalyticsReport::Items => {
+                let (first, rows) = if report == AnalyticsReport::Departments {
+                    ("Enhet", analytics.by_department)
+                } else {
+                    ("Streckkod", analytics.by_item)
+                };
+                wtr.write_record([first, "Lån", "Utlånade nu", "Medel (min)", "Median (min)", "P90 (min)", "Max (min)"])?;
+                for row in rows {
+                    wtr.write_record(&[
+                        row.key,
+                        row.loans.to_string(),
+                        row.open_loans.to_string(),
+                        row.average_minutes.map(|m| format!("{:.1}", m)).unwrap_or_default(),
+                        optional(row.median_minutes),
+                        optional(row.p90_minutes),
+                        optional(row.max_minutes),
+                    ])?;
+                }
+            }
+            AnalyticsReport::Days | AnalyticsReport::Weeks => {
+                let (first, rows) = if report == AnalyticsReport::Days {
+                    ("Dag", analytics.loans_per_day)
+                } else {
+                    ("Vecka", analytics.loans_per_week)
+                };
+                wtr.write_record([first, "Lån"])?;
+                for row in rows {
+                    wtr.write_record(&[row.period, row.loans.to_string()])?;
+                }
+            }
+            AnalyticsReport::Hours => {
+                wtr.write_record(["Timme", "Utcheckningar", "Incheckningar"])?;
+                let mut hours = analytics.busiest_hours;
+                hours.sort_by_key(|row| row.hour);
+                for row in hours {
+                    wtr.write_record(&[format!("{:02}:00", row.hour), row.check_outs.to_string(), row.check_ins.to_string()])?;
+                }
+            }
+            AnalyticsReport::Usage | AnalyticsReport::Idle => {
+                let rows = if report == AnalyticsReport::Usage {
+                    analytics.most_used
+                } else {
+                    analytics.idle
+                };
+                wtr.write_record(["Streckkod", "Benämning", "Enhet", "Lån", "Total tid ute (min)", "Senast använd"])?;
+                for row in rows {
+                    wtr.write_record(&[
+                        row.barcode,
+                        row.description.unwrap_or_default(),
+                        row.department.unwrap_or_default(),
+                        row.loans.to_string(),
+                        row.total_minutes_out.to_string(),
+                        row.last_used.map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string()).unwrap_or_default(),
+                    ])?;
+                }
+            }
+        }
+
+        wtr.flush()?;
+        Ok(())
+    }
+
     pub fn get_default_export_path() -> PathBuf {
         let mut path = dirs::desktop_dir().unwrap_or_else(|| {
             // Fallback to Documents folder if Desktop is not available
@@ -0,0 +1,152 @@
+//! Lifecycle of a single item: every scan, loan and catalogue edit for one
+//! barcode, live and archived, in time order with a few summary figures.
+//! This is what we look at when a set is reported missing.
+use crate::database::{AuditEntry, InventoryItem, Loan, ScanLog};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TimelineEvent {
+    Scan {
+        log: ScanLog,
+        /// Same action as the scan before it, so it didn't follow the normal
+        /// check-out/check-in toggle (a forced scan, or a missed one in between)
+        forced: bool,
+        /// Loan this scan opened or closed
+        loan_id: Option<i64>,
+        /// Time out, on the check-in that ended a loan
+        loan_minutes: Option<i64>,
+    },
+    /// Catalogue change from the audit trail
+    CatalogueChange { entry: AuditEntry },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimelineEntry {
+    pub timestamp: DateTime<Local>,
+    #[serde(flatten)]
+    pub event: TimelineEvent,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ItemHistoryStats {
+    pub total_loans: i64,
+    pub average_minutes_out: Option<f64>,
+    pub longest_minutes_out: Option<i64>,
+    /// Latest scan that wasn't voided, regardless of the requested range
+    pub last_seen: Option<DateTime<Local>>,
+    pub last_department: Option<String>,
+    pub last_station: Option<String>,
+    pub currently_out: bool,
+    pub forced_scans: i64,
+    pub voided_scans: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ItemHistory {
+    pub barcode: String,
+    /// Current catalogue entry, if the item is catalogued
+    pub item: Option<InventoryItem>,
+    /// Oldest first
+    pub timeline: Vec<TimelineEntry>,
+    pub loans: Vec<Loan>,
+    pub stats: ItemHistoryStats,
+}
+
+impl ItemHistory {
+    /// Builds the history from the item's complete record, oldest first, keeping
+    /// only what falls in [from, to). The whole record is needed to tell forced
+    /// scans and the last sighting even when only part of it is shown.
+    pub fn build(
+        barcode: String,
+        item: Option<InventoryItem>,
+        logs: Vec<ScanLog>,
+        loans: Vec<Loan>,
+        audit: Vec<AuditEntry>,
+        from: Option<DateTime<Local>>,
+        to: Option<DateTime<Local>>,
+    ) -> Self {
+        let in_range = |time: &DateTime<Local>| from.is_none_or(|from| *time >= from) && to.is_none_or(|to| *time < to);
+
+        let mut stats = ItemHistoryStats::default();
+        let mut timeline = Vec::new();
+        let mut previous_action: Option<String> = None;
+        for log in logs {
+            let voided = log.voided_at.is_some();
+            // The first scan of an unknown item is always a check-out
+            let forced = !voided
+                && match &previous_action {
+                    Some(previous) => *previous == log.action,
+                    None => log.action == "check-in",
+                };
+            if !voided {
+                previous_action = Some(log.action.clone());
+                stats.last_seen = Some(log.timestamp);
+                stats.last_department = log.department.clone();
+                stats.last_station = log.station.clone();
+            }
+            if !in_range(&log.timestamp) {
+                continue;
+            }
+
+            if voided {
+                stats.voided_scans += 1;
+            }
+            if forced {
+                stats.forced_scans += 1;
+            }
+            let loan = log.id.and_then(|id| {
+                loans
+                    .iter()
+                    .find(|loan| loan.out_log_id == id || loan.in_log_id == Some(id))
+            });
+            timeline.push(TimelineEntry {
+                timestamp: log.timestamp,
+                event: TimelineEvent::Scan {
+                    loan_id: loan.map(|loan| loan.id),
+                    loan_minutes: loan
+                        .filter(|loan| loan.in_log_id == log.id)
+                        .and_then(|loan| loan.duration_minutes),
+                    forced,
+                    log,
+                },
+            });
+        }
+        timeline.extend(
+            audit
+                .into_iter()
+                .filter(|entry| in_range(&entry.timestamp))
+                .map(|entry| TimelineEntry {
+                    timestamp: entry.timestamp,
+                    event: TimelineEvent::CatalogueChange { entry },
+                }),
+        );
+        // Stable, so scans keep their order within the same millisecond
+        timeline.sort_by_key(|entry| entry.timestamp);
+
+        stats.currently_out = loans.iter().any(|loan| loan.is_open());
+        // A loan belongs to the range if it was out at any point during it
+        let loans: Vec<Loan> = loans
+            .into_iter()
+            .filter(|loan| {
+                to.is_none_or(|to| loan.out_time < to)
+                    && from.is_none_or(|from| loan.in_time.is_none_or(|in_time| in_time >= from))
+            })
+            .collect();
+        let durations: Vec<i64> = loans.iter().filter_map(|loan| loan.duration_minutes).collect();
+        stats.total_loans = loans.len() as i64;
+        stats.longest_minutes_out = durations.iter().copied().max();
+        if !durations.is_empty() {
+            stats.average_minutes_out = Some(durations.iter().sum::<i64>() as f64 / durations.len() as f64);
+        }
+
+        ItemHistory {
+            barcode,
+            item,
+            timeline,
+            loans,
+            stats,
+        }
+    }
+}
@@ -25,8 +25,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     
     println!("Found {} items to import", items.len());
     
-    // Import items
-    let imported_count = db.import_items_from_list(&items)?;
+    // Import items, attributed to whoever ran the tool
+    let operator = std::env::var("USERNAME").or_else(|_| std::env::var("USER")).ok();
+    let imported_count = db.import_items(&items, operator.as_deref())?;
     
     println!("Successfully imported {} items!", imported_count);
     
@@ -1,18 +1,44 @@
 // Surgical Inventory Tracker - Tauri Backend
-mod database;
+pub mod analytics;
+mod archive;
+mod badge;
+pub mod calendar;
+pub mod database;
+pub mod db_service;
+pub mod history;
+pub mod log_query;
+mod migrations;
+mod offline_queue;
+mod scan_source;
 mod scanner;
-mod logger;
+mod serial_scanner;
+mod station_config;
+pub mod logger;
 mod export;
 mod alert;
+mod notifier;
+pub mod webhook;
 mod tray;
 
-use database::{Database, ScanLog, DepartmentMapping, InventoryItem};
-use logger::Logger;
-use scanner::Scanner;
+use analytics::{Analytics, AnalyticsReport};
+use archive::ArchiveFile;
+use calendar::{Holiday, OpeningHours};
+use database::{ScanLog, DepartmentMapping, InventoryItem, Loan, AuditEntry, CheckedOutSnapshot};
+use db_service::DbService;
+use history::ItemHistory;
+use log_query::{LogPage, LogQuery};
+use logger::{Logger, ScanAction};
+use offline_queue::FailedScan;
+use scan_source::{ReplayInput, ReplaySource, ScanSource, ScanSources, SourceStatus, TcpSource};
+use scanner::{KeyboardSource, Scanner};
+use serial_scanner::{SerialPortEntry, SerialSettings, SerialSource};
+use station_config::StationConfig;
 use export::Exporter;
-use alert::{AlertManager, OverdueItem, DepartmentAlert};
+use alert::{Alert, AlertManager, AlertSummary, AlertScheduler, OverdueItem, DepartmentAlert, SchedulerStatus};
 use tray::TrayManager;
+use webhook::{OutboxEntry, WebhookDispatcher, WebhookTarget, WebhookTargetSettings};
 
+use chrono::{DateTime, Local, NaiveDate};
 use std::sync::{Arc, Mutex};
 use tauri::{AppHandle, State, Emitter, Manager};
 use serde_json::Value;
@@ -20,24 +46,87 @@ use serde_json::Value;
 
 // Application State
 struct AppState {
+    db: DbService,
     logger: Arc<Mutex<Logger>>,
     scanner: Arc<Mutex<Scanner>>,
+    alert_scheduler: Arc<AlertScheduler>,
+    webhooks: Arc<WebhookDispatcher>,
+    scan_sources: Arc<ScanSources>,
 }
 
 impl AppState {
-    fn new() -> Result<Self, Box<dyn std::error::Error>> {        Ok(AppState {
-            logger: Arc::new(Mutex::new(Logger::new()?)),
+    fn new() -> Result<Self, Box<dyn std::error::Error>> {
+        // Opening the shared connection brings the schema up to date; report what happened.
+        // If the share is down the station starts anyway and queues scans locally.
+        let db = DbService::open_or_offline()?;
+        if db.is_connected() {
+            db.with(|db| {
+                println!("{}", db.migration_report());
+                Ok(())
+            })?;
+        }
+
+        let webhooks = Arc::new(WebhookDispatcher::new());
+        Ok(AppState {
+            logger: Arc::new(Mutex::new(Logger::new(db.clone(), Arc::clone(&webhooks))?)),
             scanner: Arc::new(Mutex::new(scanner::Scanner::new())),
+            alert_scheduler: Arc::new(AlertScheduler::new()),
+            webhooks,
+            scan_sources: Arc::new(ScanSources::new()),
+            db,
         })
     }
+
+    /// (Re)starts the serial scanner with this station's serial_* settings, or
+    /// just stops it when it is turned off
+    fn restart_serial_scanner(&self) -> Result<(), String> {
+        let settings = StationConfig::open()
+            .and_then(|config| SerialSettings::load(&config))
+            .map_err(|e| e.to_string())?;
+        if !settings.enabled {
+            self.scan_sources.remove("serial");
+            return Ok(());
+        }
+        self.scan_sources.add(Box::new(SerialSource::new(settings)))
+    }
+
+    /// Same for the TCP line socket and its tcp_scanner_* settings
+    fn restart_tcp_scanner(&self) -> Result<(), String> {
+        let (enabled, address) = StationConfig::open()
+            .and_then(|config| Ok((config.get("tcp_scanner_enabled")?, config.get("tcp_scanner_address")?)))
+            .map_err(|e| e.to_string())?;
+        if enabled.as_deref() != Some("true") {
+            self.scan_sources.remove("tcp");
+            return Ok(());
+        }
+        let address = address.filter(|address| !address.trim().is_empty());
+        let address = address.as_deref().unwrap_or(scan_source::DEFAULT_TCP_SCANNER_ADDRESS);
+        self.scan_sources.add(Box::new(TcpSource::new("tcp", address)))
+    }
 }
 
 // Tauri Commands
 
 #[tauri::command]
-fn get_recent_logs(state: State<AppState>, limit: Option<i64>) -> Result<Vec<ScanLog>, String> {
+fn get_recent_logs(state: State<AppState>, limit: Option<i64>, include_archived: Option<bool>, include_voided: Option<bool>) -> Result<Vec<ScanLog>, String> {
+    let include_voided = include_voided.unwrap_or(false);
+    if include_archived.unwrap_or(false) {
+        return state.db.with(|db| db.get_logs_with_archives(limit, include_voided)).map_err(|e| e.to_string());
+    }
     let logger = state.logger.lock().map_err(|e| e.to_string())?;
-    logger.get_recent_logs(limit).map_err(|e| e.to_string())
+    logger.get_recent_logs(limit, include_voided).map_err(|e| e.to_string())
+}
+
+/// Filtered, paginated view of the log for the admin history screens
+#[tauri::command]
+fn query_logs(state: State<AppState>, query: LogQuery) -> Result<LogPage, String> {
+    state.db.with(|db| db.query_logs(&query)).map_err(|e| e.to_string())
+}
+
+/// Everything that happened to one barcode, e.g. when a set is reported missing
+#[tauri::command]
+fn get_item_history(state: State<AppState>, barcode: String, from: Option<DateTime<Local>>, to: Option<DateTime<Local>>) -> Result<ItemHistory, String> {
+    state.db.with(|db| db.get_item_history(&barcode, from, to)).map_err(|e| e.to_string())
 }
 
 #[tauri::command]
@@ -46,6 +135,33 @@ fn get_checked_out_items(state: State<AppState>) -> Result<Vec<ScanLog>, String>
     logger.get_checked_out_items().map_err(|e| e.to_string())
 }
 
+/// What was out at a past moment, e.g. "at 14:00 yesterday"
+#[tauri::command]
+fn get_checked_out_snapshot(state: State<AppState>, at: DateTime<Local>) -> Result<CheckedOutSnapshot, String> {
+    state.db.with(|db| db.get_checked_out_snapshot(at)).map_err(|e| e.to_string())
+}
+
+/// Turnaround and utilization for loans started in [from, to)
+#[tauri::command]
+fn get_analytics(state: State<AppState>, from: Option<DateTime<Local>>, to: Option<DateTime<Local>>, top: Option<usize>) -> Result<Analytics, String> {
+    let top = top.unwrap_or(analytics::DEFAULT_TOP_ITEMS);
+    state.db.with(|db| db.get_analytics(from, to, top)).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn get_loans(state: State<AppState>, limit: Option<i64>, include_archived: Option<bool>) -> Result<Vec<Loan>, String> {
+    if include_archived.unwrap_or(false) {
+        state.db.with(|db| db.get_loans_with_archives(limit)).map_err(|e| e.to_string())
+    } else {
+        state.db.with(|db| db.get_loans(limit)).map_err(|e| e.to_string())
+    }
+}
+
+#[tauri::command]
+fn get_open_loans(state: State<AppState>) -> Result<Vec<Loan>, String> {
+    state.db.with(|db| db.get_open_loans()).map_err(|e| e.to_string())
+}
+
 #[tauri::command]
 fn get_department_stats(state: State<AppState>) -> Result<Vec<(String, i64)>, String> {
     let logger = state.logger.lock().map_err(|e| e.to_string())?;
@@ -53,205 +169,531 @@ fn get_department_stats(state: State<AppState>) -> Result<Vec<(String, i64)>, St
 }
 
 #[tauri::command]
-fn clear_all_logs(state: State<AppState>) -> Result<(), String> {
-    let logger = state.logger.lock().map_err(|e| e.to_string())?;
-    logger.clear_all_logs().map_err(|e| e.to_string())
+fn clear_all_logs(state: State<AppState>, operator: Option<String>) -> Result<(), String> {
+    let mut logger = state.logger.lock().map_err(|e| e.to_string())?;
+    logger.clear_all_logs(operator.as_deref()).map_err(|e| e.to_string())
 }
 
 // Database maintenance commands
+// Without `days_to_keep` the archive_retention_days setting is used
+#[tauri::command]
+fn cleanup_old_logs(state: State<AppState>, days_to_keep: Option<i32>) -> Result<usize, String> {
+    state.db
+        .with(|db| {
+            let days = match days_to_keep {
+                Some(days) => days,
+                None => db.get_archive_retention_days()?,
+            };
+            db.cleanup_old_logs(days)
+        })
+        .map_err(|e| e.to_string())
+}
+
 #[tauri::command]
-fn cleanup_old_logs(days_to_keep: i32) -> Result<usize, String> {
-    let db = Database::new().map_err(|e| e.to_string())?;
-    db.cleanup_old_logs(days_to_keep).map_err(|e| e.to_string())
+fn rebuild_item_status(state: State<AppState>) -> Result<usize, String> {
+    let mut logger = state.logger.lock().map_err(|e| e.to_string())?;
+    logger.rebuild_item_status().map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-fn get_database_stats() -> Result<Value, String> {
-    let db = Database::new().map_err(|e| e.to_string())?;
-    let (total_logs, file_size) = db.get_database_stats().map_err(|e| e.to_string())?;
+fn get_database_stats(state: State<AppState>) -> Result<Value, String> {
+    let (total_logs, file_size, schema_version) = state.db
+        .with(|db| {
+            let (total_logs, file_size) = db.get_database_stats()?;
+            Ok((total_logs, file_size, db.migration_report().current_version))
+        })
+        .map_err(|e| e.to_string())?;
     
     Ok(serde_json::json!({
         "total_logs": total_logs,
-        "file_size": file_size
+        "file_size": file_size,
+        "schema_version": schema_version
     }))
 }
 
 #[tauri::command]
-fn archive_completed_transactions(days_to_keep: i32) -> Result<usize, String> {
-    let db = Database::new().map_err(|e| e.to_string())?;
-    db.archive_completed_transactions(days_to_keep).map_err(|e| e.to_string())
+fn archive_completed_transactions(state: State<AppState>, days_to_keep: Option<i32>) -> Result<usize, String> {
+    state.db
+        .with(|db| {
+            let days = match days_to_keep {
+                Some(days) => days,
+                None => db.get_archive_retention_days()?,
+            };
+            db.archive_completed_transactions(days)
+        })
+        .map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-fn manual_scan_barcode(app: AppHandle, state: State<AppState>, barcode: String) -> Result<Value, String> {
-    let mut logger = state.logger.lock().map_err(|e| e.to_string())?;
-    let action = logger.process_barcode_scan(&barcode).map_err(|e| e.to_string())?;
-    
+fn list_archives(state: State<AppState>) -> Result<Vec<ArchiveFile>, String> {
+    state.db.with(|db| db.list_archives()).map_err(|e| e.to_string())
+}
+
+/// Tells the UI, the tray and webhooks about a scan made from a command
+fn announce_scan(app: &AppHandle, state: &AppState, barcode: &str, action: ScanAction, source: &str) -> Value {
     let result = serde_json::json!({
         "barcode": barcode,
         "action": action.action,
-        "department": action.department
+        "department": action.department,
+        "queued": action.queued,
+        "source": source
     });
 
     // Emit event to notify UI components of the scan
     let _ = app.emit("barcode-scanned", &result);
-    
-    Ok(result)
+    // Queued scans go out to webhooks when the offline queue is flushed
+    if !action.queued {
+        state.webhooks.publish(&state.db, "barcode-scanned", &result);
+    }
+    result
 }
 
 #[tauri::command]
-fn force_check_in(state: State<AppState>, barcode: String) -> Result<Value, String> {
-    let mut logger = state.logger.lock().map_err(|e| e.to_string())?;
-    let action = logger.force_check_in(&barcode).map_err(|e| e.to_string())?;
-    
-    Ok(serde_json::json!({
-        "barcode": barcode,
-        "action": action.action,
-        "department": action.department
-    }))
+fn manual_scan_barcode(app: AppHandle, state: State<AppState>, barcode: String) -> Result<Value, String> {
+    let action = state
+        .logger
+        .lock()
+        .map_err(|e| e.to_string())?
+        .process_barcode_scan(&barcode)
+        .map_err(|e| e.to_string())?;
+    Ok(announce_scan(&app, &state, &barcode, action, "manual"))
+}
+
+#[tauri::command]
+fn force_check_in(app: AppHandle, state: State<AppState>, barcode: String) -> Result<Value, String> {
+    let action = state
+        .logger
+        .lock()
+        .map_err(|e| e.to_string())?
+        .force_check_in(&barcode)
+        .map_err(|e| e.to_string())?;
+    Ok(announce_scan(&app, &state, &barcode, action, "forced"))
 }
 
 #[tauri::command]
-fn force_check_out(state: State<AppState>, barcode: String) -> Result<Value, String> {
+fn force_check_out(app: AppHandle, state: State<AppState>, barcode: String) -> Result<Value, String> {
+    let action = state
+        .logger
+        .lock()
+        .map_err(|e| e.to_string())?
+        .force_check_out(&barcode)
+        .map_err(|e| e.to_string())?;
+    Ok(announce_scan(&app, &state, &barcode, action, "forced"))
+}
+
+// Without `log_id` this station's latest scan is voided
+#[tauri::command]
+fn void_scan(app: AppHandle, state: State<AppState>, log_id: Option<i64>, reason: String, operator: Option<String>) -> Result<ScanLog, String> {
     let mut logger = state.logger.lock().map_err(|e| e.to_string())?;
-    let action = logger.force_check_out(&barcode).map_err(|e| e.to_string())?;
-    
-    Ok(serde_json::json!({
-        "barcode": barcode,
-        "action": action.action,
-        "department": action.department
-    }))
+    let voided = logger.void_scan(log_id, &reason, operator.as_deref()).map_err(|e| e.to_string())?;
+
+    // Let the UI refresh its lists the same way it does after a scan
+    let _ = app.emit("scan-voided", &voided);
+
+    Ok(voided)
+}
+
+#[tauri::command]
+fn get_pending_scan_count(state: State<AppState>) -> Result<usize, String> {
+    let logger = state.logger.lock().map_err(|e| e.to_string())?;
+    logger.pending_scan_count().map_err(|e| e.to_string())
+}
+
+/// Queued scans the shared database refused, so they can be entered by hand
+#[tauri::command]
+fn get_failed_scans(state: State<AppState>) -> Result<Vec<FailedScan>, String> {
+    let logger = state.logger.lock().map_err(|e| e.to_string())?;
+    logger.failed_scans().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn discard_failed_scan(state: State<AppState>, id: i64) -> Result<bool, String> {
+    let logger = state.logger.lock().map_err(|e| e.to_string())?;
+    logger.discard_failed_scan(id).map_err(|e| e.to_string())
 }
 
 #[tauri::command]
 fn start_manual_scan_session(state: State<AppState>) -> Result<(), String> {
-    let mut scanner = state.scanner.lock().map_err(|e| e.to_string())?;
-    scanner.start_manual_session();
+    state.scanner.lock().map_err(|e| e.to_string())?.start_manual_session();
+    // Not while holding the scanner: the keyboard hook waits for it on every key
+    state.webhooks.publish(&state.db, "scan-session-started", &serde_json::json!({ "trigger": "manual" }));
     Ok(())
 }
 
 #[tauri::command]
 fn stop_scan_session(state: State<AppState>) -> Result<(), String> {
-    let mut scanner = state.scanner.lock().map_err(|e| e.to_string())?;
-    scanner.stop_session();
+    state.scanner.lock().map_err(|e| e.to_string())?.stop_session();
+    state.webhooks.publish(&state.db, "scan-session-ended", &serde_json::json!({ "reason": "stopped" }));
     Ok(())
 }
 
+/// Serial/COM ports present on this machine, for picking the scanner's port
 #[tauri::command]
-fn export_logs_csv(file_path: String, limit: Option<i64>) -> Result<(), String> {
-    let exporter = Exporter::new().map_err(|e| e.to_string())?;
-    exporter.export_logs_to_csv(&file_path, limit).map_err(|e| e.to_string())
+fn list_serial_ports() -> Result<Vec<SerialPortEntry>, String> {
+    serial_scanner::list_ports()
 }
 
 #[tauri::command]
-fn export_logs_json(file_path: String, limit: Option<i64>) -> Result<(), String> {
-    let exporter = Exporter::new().map_err(|e| e.to_string())?;
-    exporter.export_logs_to_json(&file_path, limit).map_err(|e| e.to_string())
+fn restart_serial_scanner(state: State<AppState>) -> Result<(), String> {
+    state.restart_serial_scanner()
 }
 
+/// Every running scanner input, with whether it is connected and what it last read
 #[tauri::command]
-fn export_checked_out_csv(file_path: String) -> Result<(), String> {
-    let exporter = Exporter::new().map_err(|e| e.to_string())?;
-    exporter.export_checked_out_to_csv(&file_path).map_err(|e| e.to_string())
+fn get_scan_sources(state: State<AppState>) -> Vec<SourceStatus> {
+    state.scan_sources.status()
 }
 
+/// Feeds the barcodes in a file, one per line, through the scan pipeline as
+/// if they were scanned. Returns the id of the replay source.
 #[tauri::command]
-fn quick_export_checked_out() -> Result<String, String> {
-    let exporter = Exporter::new().map_err(|e| e.to_string())?;
-    let export_path = Exporter::get_default_export_path();
-    let timestamp = chrono::Utc::now().format("%Y%m%d_%H%M%S");
-    let file_path = export_path.join(format!("utcheckade_artiklar_{}.csv", timestamp));
-    
-    exporter.export_checked_out_to_csv(file_path.to_str().unwrap()).map_err(|e| e.to_string())?;
-    
+fn replay_scan_file(state: State<AppState>, path: String, interval_ms: Option<u64>) -> Result<String, String> {
+    let interval = interval_ms.map(std::time::Duration::from_millis).unwrap_or(scan_source::DEFAULT_REPLAY_INTERVAL);
+    let source = ReplaySource::new(ReplayInput::File(path.into()), interval);
+    let id = source.id().to_string();
+    state.scan_sources.add(Box::new(source))?;
+    Ok(id)
+}
+
+/// Stops a running scan source; returns whether it was running
+#[tauri::command]
+fn stop_scan_source(state: State<AppState>, source_id: String) -> bool {
+    state.scan_sources.remove(&source_id)
+}
+
+#[tauri::command]
+fn export_logs_csv(state: State<AppState>, file_path: String, limit: Option<i64>, query: Option<LogQuery>) -> Result<(), String> {
+    let exporter = Exporter::new(state.db.clone());
+    exporter
+        .export_logs_to_csv(&file_path, &query.unwrap_or_default(), limit)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn export_logs_json(state: State<AppState>, file_path: String, limit: Option<i64>, query: Option<LogQuery>) -> Result<(), String> {
+    let exporter = Exporter::new(state.db.clone());
+    exporter
+        .export_logs_to_json(&file_path, &query.unwrap_or_default(), limit)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn export_checked_out_csv(state: State<AppState>, file_path: String, at: Option<DateTime<Local>>) -> Result<(), String> {
+    let exporter = Exporter::new(state.db.clone());
+    exporter.export_checked_out_to_csv(&file_path, at).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn export_analytics_csv(state: State<AppState>, file_path: String, report: AnalyticsReport, from: Option<DateTime<Local>>, to: Option<DateTime<Local>>) -> Result<(), String> {
+    let exporter = Exporter::new(state.db.clone());
+    exporter.export_analytics_to_csv(&file_path, report, from, to).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn export_loans_csv(state: State<AppState>, file_path: String, limit: Option<i64>, include_archived: Option<bool>) -> Result<(), String> {
+    let exporter = Exporter::new(state.db.clone());
+    exporter.export_loans_to_csv(&file_path, limit, include_archived.unwrap_or(false)).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn quick_export_checked_out(state: State<AppState>) -> Result<String, String> {
+    let exporter = Exporter::new(state.db.clone());
+    let file_path = exporter.quick_export_checked_out().map_err(|e| e.to_string())?;
     Ok(file_path.to_string_lossy().to_string())
 }
 
 #[tauri::command]
-fn get_overdue_items() -> Result<Vec<OverdueItem>, String> {
-    let alert_manager = AlertManager::new().map_err(|e| e.to_string())?;
+fn get_overdue_items(state: State<AppState>) -> Result<Vec<OverdueItem>, String> {
+    let alert_manager = AlertManager::new(state.db.clone()).map_err(|e| e.to_string())?;
     alert_manager.check_overdue_items().map_err(|e| e.to_string())
 }
 
+/// When the overdue check last ran and when it runs next
+#[tauri::command]
+fn get_alert_scheduler_status(state: State<AppState>) -> SchedulerStatus {
+    state.alert_scheduler.status()
+}
+
 #[tauri::command]
-fn get_department_alerts() -> Result<Vec<DepartmentAlert>, String> {
-    let alert_manager = AlertManager::new().map_err(|e| e.to_string())?;
+fn run_alert_check(state: State<AppState>) {
+    state.alert_scheduler.request_check();
+}
+
+/// Stored overdue alerts, most urgent first
+#[tauri::command]
+fn get_alerts(state: State<AppState>, include_resolved: Option<bool>, limit: Option<i64>) -> Result<Vec<Alert>, String> {
+    state.db.with(|db| db.get_alerts(include_resolved.unwrap_or(false), limit)).map_err(|e| e.to_string())
+}
+
+/// Counts of unresolved alerts, as shown on the tray badge
+#[tauri::command]
+fn get_alert_summary(state: State<AppState>) -> Result<AlertSummary, String> {
+    state.db.with(|db| db.get_alert_summary()).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn acknowledge_alert(app: AppHandle, state: State<AppState>, alert_id: i64, operator: Option<String>) -> Result<Alert, String> {
+    let alert = state.db.with(|db| db.acknowledge_alert(alert_id, operator.as_deref())).map_err(|e| e.to_string())?;
+    let _ = app.emit("alert-updated", &alert);
+    Ok(alert)
+}
+
+#[tauri::command]
+fn snooze_alert(app: AppHandle, state: State<AppState>, alert_id: i64, until: DateTime<Local>, operator: Option<String>) -> Result<Alert, String> {
+    let alert = state.db.with(|db| db.snooze_alert(alert_id, until, operator.as_deref())).map_err(|e| e.to_string())?;
+    let _ = app.emit("alert-updated", &alert);
+    Ok(alert)
+}
+
+#[tauri::command]
+fn get_department_alerts(state: State<AppState>) -> Result<Vec<DepartmentAlert>, String> {
+    let alert_manager = AlertManager::new(state.db.clone()).map_err(|e| e.to_string())?;
     alert_manager.get_department_alert_stats().map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-fn get_settings(key: String) -> Result<Option<String>, String> {
-    let db = Database::new().map_err(|e| e.to_string())?;
-    db.get_setting(&key).map_err(|e| e.to_string())
+fn get_settings(state: State<AppState>, key: String) -> Result<Option<String>, String> {
+    let value = if station_config::is_station_setting(&key) {
+        StationConfig::open().and_then(|config| config.get(&key))
+    } else {
+        state.db.with(|db| db.get_setting(&key))
+    }
+    .map_err(|e| e.to_string())?;
+    // The UI only needs to know a password is set
+    if station_config::is_secret(&key) {
+        return Ok(value.filter(|value| !value.is_empty()).map(|_| station_config::REDACTED.to_string()));
+    }
+    Ok(value)
+}
+
+#[tauri::command]
+fn set_settings(state: State<AppState>, key: String, value: String, operator: Option<String>) -> Result<(), String> {
+    if key == "alert_check_interval_minutes" {
+        alert::parse_check_interval(&value)?;
+    }
+    if key == "alert_threshold_hours" {
+        alert::parse_threshold_hours(&value)?;
+    }
+    // A form saved unchanged sends the placeholder back
+    if station_config::is_secret(&key) && value == station_config::REDACTED {
+        return Ok(());
+    }
+    if station_config::is_station_setting(&key) {
+        StationConfig::open().and_then(|config| config.set(&key, &value)).map_err(|e| e.to_string())?;
+    } else {
+        state.db.with(|db| db.set_setting(&key, &value, operator.as_deref())).map_err(|e| e.to_string())?;
+    }
+    if key == "alert_threshold_hours" || key == "alert_check_interval_minutes" {
+        state.alert_scheduler.request_check();
+    }
+    if key.starts_with("serial_") {
+        state.restart_serial_scanner()?;
+    }
+    if key.starts_with("tcp_scanner_") {
+        state.restart_tcp_scanner()?;
+    }
+    Ok(())
+}
+
+// Working calendar commands
+#[tauri::command]
+fn get_opening_hours(state: State<AppState>) -> Result<Vec<OpeningHours>, String> {
+    state.db.with(|db| db.get_opening_hours()).map_err(|e| e.to_string())
+}
+
+/// `opens`/`closes` as "HH:MM"; leave both out to close the day
+#[tauri::command]
+fn set_opening_hours(state: State<AppState>, weekday: u32, opens: Option<String>, closes: Option<String>, operator: Option<String>) -> Result<(), String> {
+    if weekday > 6 {
+        return Err(format!("Invalid weekday {} (0 = Monday ... 6 = Sunday)", weekday));
+    }
+    let minutes = match (opens, closes) {
+        (Some(opens), Some(closes)) => {
+            let parse = |clock: &str| calendar::parse_clock(clock).ok_or_else(|| format!("Invalid time '{}', expected HH:MM", clock));
+            let (opens_minute, closes_minute) = (parse(&opens)?, parse(&closes)?);
+            if closes_minute <= opens_minute {
+                return Err(format!("Closing time {} must be after opening time {}", closes, opens));
+            }
+            Some((opens_minute, closes_minute))
+        }
+        (None, None) => None,
+        _ => return Err("Both opening and closing time are needed".to_string()),
+    };
+    state.db.with(|db| db.set_opening_hours(weekday, minutes, operator.as_deref())).map_err(|e| e.to_string())?;
+    state.alert_scheduler.request_check();
+    Ok(())
+}
+
+#[tauri::command]
+fn get_holidays(state: State<AppState>) -> Result<Vec<Holiday>, String> {
+    state.db.with(|db| db.get_holidays()).map_err(|e| e.to_string())
+}
+
+/// Imports a holiday file (one YYYY-MM-DD per line, optional name after it)
+#[tauri::command]
+fn import_holidays(state: State<AppState>, file_path: String, operator: Option<String>) -> Result<usize, String> {
+    let text = std::fs::read_to_string(&file_path).map_err(|e| e.to_string())?;
+    let holidays = calendar::parse_holidays(&text)?;
+    let imported = state.db.with(|db| db.import_holidays(&holidays, operator.as_deref())).map_err(|e| e.to_string())?;
+    state.alert_scheduler.request_check();
+    Ok(imported)
+}
+
+#[tauri::command]
+fn delete_holiday(state: State<AppState>, date: NaiveDate, operator: Option<String>) -> Result<(), String> {
+    state.db.with(|db| db.delete_holiday(date, operator.as_deref())).map_err(|e| e.to_string())?;
+    state.alert_scheduler.request_check();
+    Ok(())
+}
+
+// Audit trail commands
+#[tauri::command]
+fn get_audit_log(state: State<AppState>, entity: Option<String>, limit: Option<i64>) -> Result<Vec<AuditEntry>, String> {
+    state.db.with(|db| db.get_audit_log(entity.as_deref(), limit)).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn export_audit_log_csv(state: State<AppState>, file_path: String, entity: Option<String>, limit: Option<i64>) -> Result<(), String> {
+    let exporter = Exporter::new(state.db.clone());
+    exporter.export_audit_log_to_csv(&file_path, entity.as_deref(), limit).map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-fn set_settings(key: String, value: String) -> Result<(), String> {
-    let db = Database::new().map_err(|e| e.to_string())?;
-    db.set_setting(&key, &value).map_err(|e| e.to_string())
+fn get_department_mappings(state: State<AppState>) -> Result<Vec<DepartmentMapping>, String> {
+    state.db.with(|db| db.get_department_mappings()).map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-fn get_department_mappings() -> Result<Vec<DepartmentMapping>, String> {
-    let db = Database::new().map_err(|e| e.to_string())?;
-    db.get_department_mappings().map_err(|e| e.to_string())
+fn set_department_mapping(state: State<AppState>, prefix: String, department: String, operator: Option<String>) -> Result<(), String> {
+    state.db.with(|db| db.set_department_mapping(&prefix, &department, operator.as_deref())).map_err(|e| e.to_string())?;
+    // Items may now fall under a different department threshold
+    state.alert_scheduler.request_check();
+    Ok(())
 }
 
+/// `hours: None` clears the department's own threshold
 #[tauri::command]
-fn set_department_mapping(prefix: String, department: String) -> Result<(), String> {
-    let db = Database::new().map_err(|e| e.to_string())?;
-    db.set_department_mapping(&prefix, &department).map_err(|e| e.to_string())
+fn set_department_threshold(state: State<AppState>, prefix: String, hours: Option<i64>, operator: Option<String>) -> Result<(), String> {
+    if let Some(hours) = hours {
+        alert::validate_threshold_hours(hours)?;
+    }
+    state.db.with(|db| db.set_department_threshold(&prefix, hours, operator.as_deref())).map_err(|e| e.to_string())?;
+    state.alert_scheduler.request_check();
+    Ok(())
 }
 
+/// Comma- or semicolon-separated addresses; `None` or blank stops emailing the department
 #[tauri::command]
-fn delete_department_mapping(prefix: String) -> Result<(), String> {
-    let db = Database::new().map_err(|e| e.to_string())?;
-    db.delete_department_mapping(&prefix).map_err(|e| e.to_string())
+fn set_department_alert_emails(state: State<AppState>, prefix: String, emails: Option<String>, operator: Option<String>) -> Result<(), String> {
+    let emails = emails.filter(|emails| !emails.trim().is_empty());
+    if let Some(emails) = &emails {
+        notifier::parse_recipients(emails)?;
+    }
+    state.db.with(|db| db.set_department_alert_emails(&prefix, emails.as_deref(), operator.as_deref())).map_err(|e| e.to_string())
+}
+
+/// Sends a test message with the current SMTP settings
+#[tauri::command]
+fn send_test_email(state: State<AppState>, to: String) -> Result<(), String> {
+    let notifier = notifier::EmailNotifier::new(&state.db).map_err(|e| e.to_string())?;
+    notifier.send_test(&to).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn delete_department_mapping(state: State<AppState>, prefix: String, operator: Option<String>) -> Result<(), String> {
+    state.db.with(|db| db.delete_department_mapping(&prefix, operator.as_deref())).map_err(|e| e.to_string())?;
+    state.alert_scheduler.request_check();
+    Ok(())
 }
 
 // Items (inventory catalogue) commands
 #[tauri::command]
-fn get_items(limit: Option<i64>) -> Result<Vec<InventoryItem>, String> {
-    let db = Database::new().map_err(|e| e.to_string())?;
-    db.get_items(limit).map_err(|e| e.to_string())
+fn get_items(state: State<AppState>, limit: Option<i64>) -> Result<Vec<InventoryItem>, String> {
+    state.db.with(|db| db.get_items(limit)).map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-fn add_item(barcode: String, department: Option<String>, description: Option<String>) -> Result<(), String> {
-    let db = Database::new().map_err(|e| e.to_string())?;
-    db.add_item(&barcode, department.as_deref(), description.as_deref()).map_err(|e| e.to_string())
+fn add_item(state: State<AppState>, barcode: String, department: Option<String>, description: Option<String>, operator: Option<String>) -> Result<(), String> {
+    state.db.with(|db| db.add_item(&barcode, department.as_deref(), description.as_deref(), operator.as_deref())).map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-fn update_item(barcode: String, department: Option<String>, description: Option<String>) -> Result<(), String> {
-    let db = Database::new().map_err(|e| e.to_string())?;
-    db.update_item(&barcode, department.as_deref(), description.as_deref()).map_err(|e| e.to_string())
+fn update_item(state: State<AppState>, barcode: String, department: Option<String>, description: Option<String>, operator: Option<String>) -> Result<(), String> {
+    state.db.with(|db| db.update_item(&barcode, department.as_deref(), description.as_deref(), operator.as_deref())).map_err(|e| e.to_string())
 }
 
+/// `hours: None` clears the item's own threshold
 #[tauri::command]
-fn delete_item(barcode: String) -> Result<(), String> {
-    let db = Database::new().map_err(|e| e.to_string())?;
-    db.delete_item(&barcode).map_err(|e| e.to_string())
+fn set_item_threshold(state: State<AppState>, barcode: String, hours: Option<i64>, operator: Option<String>) -> Result<(), String> {
+    if let Some(hours) = hours {
+        alert::validate_threshold_hours(hours)?;
+    }
+    state.db.with(|db| db.set_item_threshold(&barcode, hours, operator.as_deref())).map_err(|e| e.to_string())?;
+    state.alert_scheduler.request_check();
+    Ok(())
+}
+
+#[tauri::command]
+fn delete_item(state: State<AppState>, barcode: String, operator: Option<String>) -> Result<(), String> {
+    state.db.with(|db| db.delete_item(&barcode, operator.as_deref())).map_err(|e| e.to_string())
+}
+
+// Legacy item name functions (kept for the frontend; they read and write the items catalogue)
+#[tauri::command]
+fn import_item_names(state: State<AppState>, items: Vec<(String, String)>, operator: Option<String>) -> Result<usize, String> {
+    state.db.with(|db| db.import_items(&items, operator.as_deref())).map_err(|e| e.to_string())
 }
 
-// Legacy item name functions (for backward compatibility)
 #[tauri::command]
-fn import_item_names(items: Vec<(String, String)>) -> Result<usize, String> {
-    let db = Database::new().map_err(|e| e.to_string())?;
-    db.import_item_names(&items).map_err(|e| e.to_string())
+fn get_item_display_name(state: State<AppState>, barcode: String) -> Result<String, String> {
+    state.db.with(|db| db.get_display_name(&barcode)).map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-fn get_item_display_name(barcode: String) -> Result<String, String> {
-    let db = Database::new().map_err(|e| e.to_string())?;
-    db.get_display_name(&barcode).map_err(|e| e.to_string())
+fn set_item_name(state: State<AppState>, barcode: String, name: String, operator: Option<String>) -> Result<(), String> {
+    state.db.with(|db| db.set_item_name(&barcode, &name, operator.as_deref())).map_err(|e| e.to_string())
 }
 
+// Webhook commands
 #[tauri::command]
-fn set_item_name(barcode: String, name: String) -> Result<(), String> {
-    let db = Database::new().map_err(|e| e.to_string())?;
-    db.set_item_name(&barcode, &name).map_err(|e| e.to_string())
+fn get_webhook_targets(state: State<AppState>) -> Result<Vec<WebhookTarget>, String> {
+    state.db.with(|db| db.get_webhook_targets()).map_err(|e| e.to_string())
 }
+
+/// Adds a target, or updates `target_id` when given
+#[tauri::command]
+fn save_webhook_target(state: State<AppState>, target_id: Option<i64>, settings: WebhookTargetSettings, operator: Option<String>) -> Result<WebhookTarget, String> {
+    settings.validate()?;
+    state.db.with(|db| db.save_webhook_target(target_id, &settings, operator.as_deref())).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn delete_webhook_target(state: State<AppState>, target_id: i64, operator: Option<String>) -> Result<(), String> {
+    state.db.with(|db| db.delete_webhook_target(target_id, operator.as_deref())).map_err(|e| e.to_string())
+}
+
+/// Posts a "test" event to the target right away and returns the HTTP status
+#[tauri::command]
+fn send_test_webhook(state: State<AppState>, target_id: i64) -> Result<u16, String> {
+    let target = state
+        .db
+        .with(|db| db.get_webhook_target(target_id))
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("Webhook target {} not found", target_id))?;
+    webhook::send_test(&target)
+}
+
+#[tauri::command]
+fn get_webhook_outbox(state: State<AppState>, include_delivered: Option<bool>, limit: Option<i64>) -> Result<Vec<OutboxEntry>, String> {
+    state.db.with(|db| db.get_webhook_outbox(include_delivered.unwrap_or(false), limit)).map_err(|e| e.to_string())
+}
+
+/// Gives a failed or waiting delivery a fresh set of attempts, starting now
+#[tauri::command]
+fn retry_webhook(state: State<AppState>, entry_id: i64) -> Result<(), String> {
+    state.db.with(|db| db.retry_webhook(entry_id)).map_err(|e| e.to_string())?;
+    state.webhooks.request_delivery();
+    Ok(())
 }
 
 // Window Management Commands
@@ -283,55 +725,126 @@ pub fn run() {
                 }
             }
         })        .plugin(tauri_plugin_opener::init())
+        .plugin(tauri_plugin_notification::init())
         .setup(|app| {
-                       // Setup system tray
-            if let Err(e) = TrayManager::setup_system_tray(app.handle()) {
+            let state = app.state::<AppState>();
+
+            // Setup system tray
+            if let Err(e) = TrayManager::setup_system_tray(app.handle(), Arc::clone(&state.logger), state.db.clone()) {
                 eprintln!("Failed to setup system tray: {}", e);
             }
 
-            // Perform automatic database cleanup on startup (keep 30 days of completed transactions)
-            if let Ok(db) = Database::new() {
-                match db.archive_completed_transactions(30) {
-                    Ok(deleted_count) => {
-                        if deleted_count > 0 {
-                            println!("Database cleanup: Archived {} old completed transactions", deleted_count);
-                        }
+            // Move completed transactions past the retention period to the yearly archives
+            // (0 or less in archive_retention_days turns this off)
+            let archived = state.db.with(|db| match db.get_archive_retention_days()? {
+                days if days > 0 => db.archive_completed_transactions(days),
+                _ => Ok(0),
+            });
+            match archived {
+                Ok(archived_count) => {
+                    if archived_count > 0 {
+                        println!("Database cleanup: Archived {} old completed transactions", archived_count);
                     }
-                    Err(e) => eprintln!("Failed to perform database cleanup: {}", e),
                 }
+                Err(e) => eprintln!("Failed to perform database cleanup: {}", e),
             }
 
+            // Every scanner input feeds this one pipeline
+            let pipeline_app = app.handle().clone();
+            let pipeline_logger = Arc::clone(&state.logger);
+            let pipeline_db = state.db.clone();
+            let pipeline_webhooks = Arc::clone(&state.webhooks);
+            state.scan_sources.start(move |scan| {
+                scanner::process_scanned_barcode(&pipeline_app, &pipeline_logger, &pipeline_db, &pipeline_webhooks, scan);
+            });
+
             // Start keyboard scanner
-            if let Err(e) = scanner::Scanner::start_listening(app.handle().clone()) {
+            let keyboard = KeyboardSource::new(
+                app.handle().clone(),
+                Arc::clone(&state.scanner),
+                state.db.clone(),
+                Arc::clone(&state.webhooks),
+            );
+            if let Err(e) = state.scan_sources.add(Box::new(keyboard)) {
                 eprintln!("Failed to start keyboard scanner: {}", e);
             }
 
-            // Start periodic alert checks
-            if let Err(e) = AlertManager::start_periodic_checks(app.handle().clone()) {
-                eprintln!("Failed to start alert manager: {}", e);
+            // Start the serial scanner and TCP socket, if configured
+            if let Err(e) = state.restart_serial_scanner() {
+                eprintln!("Failed to start serial scanner: {}", e);
+            }
+            if let Err(e) = state.restart_tcp_scanner() {
+                eprintln!("Failed to start TCP scan source: {}", e);
+            }
+
+            // INVENTORY_REPLAY_SCANS=<file> (or "-" for stdin) replays recorded
+            // scans, for trying things out without a scanner
+            if let Some(replay) = std::env::var("INVENTORY_REPLAY_SCANS").ok().filter(|replay| !replay.trim().is_empty()) {
+                let source = ReplaySource::from_arg(&replay, scan_source::DEFAULT_REPLAY_INTERVAL);
+                if let Err(e) = state.scan_sources.add(Box::new(source)) {
+                    eprintln!("Failed to start scan replay: {}", e);
+                }
             }
 
+            // Replay scans queued while the shared database was unreachable
+            Logger::start_offline_flush(app.handle().clone(), Arc::clone(&state.logger));
+
+            // Live tray menu, overdue badge and desktop notifications for new alerts;
+            // listening before the first check so its result isn't missed
+            TrayManager::watch_events(app.handle(), state.db.clone());
+
+            // Check for overdue items now and then on the configured interval
+            state.alert_scheduler.start(app.handle().clone(), state.db.clone(), Arc::clone(&state.webhooks));
+
+            // Deliver queued webhooks, including any left over from the last run
+            state.webhooks.start(state.db.clone());
+
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             // Core inventory commands
             get_recent_logs,
+            query_logs,
+            get_item_history,
             get_checked_out_items,
+            get_checked_out_snapshot,
+            get_analytics,
             get_department_stats,
+            get_loans,
+            get_open_loans,
             clear_all_logs,
             manual_scan_barcode,
             force_check_in,
             force_check_out,
+            void_scan,
+            get_pending_scan_count,
+            get_failed_scans,
+            discard_failed_scan,
             
             // Database maintenance commands
             cleanup_old_logs,
             get_database_stats,
             archive_completed_transactions,
+            list_archives,
+            rebuild_item_status,
             
                     
             // Scanner commands
             start_manual_scan_session,
             stop_scan_session,
+            get_scan_sources,
+            list_serial_ports,
+            restart_serial_scanner,
+            replay_scan_file,
+            stop_scan_source,
+            
+            // Webhook commands
+            get_webhook_targets,
+            save_webhook_target,
+            delete_webhook_target,
+            send_test_webhook,
+            get_webhook_outbox,
+            retry_webhook,
             
             // Window management commands
             close_window,
@@ -341,23 +854,46 @@ pub fn run() {
             export_logs_csv,
             export_logs_json,
             export_checked_out_csv,
+            export_analytics_csv,
+            export_loans_csv,
             quick_export_checked_out,
             
             // Alert commands
             get_overdue_items,
             get_department_alerts,
+            get_alerts,
+            get_alert_summary,
+            get_alert_scheduler_status,
+            run_alert_check,
+            acknowledge_alert,
+            snooze_alert,
             
             // Settings commands
             get_settings,
             set_settings,
             get_department_mappings,
             set_department_mapping,
+            set_department_threshold,
+            set_department_alert_emails,
             delete_department_mapping,
+            send_test_email,
+            
+            // Working calendar commands
+            get_opening_hours,
+            set_opening_hours,
+            get_holidays,
+            import_holidays,
+            delete_holiday,
+            
+            // Audit trail commands
+            get_audit_log,
+            export_audit_log_csv,
             
             // Items catalogue commands
             get_items,
             add_item,
             update_item,
+            set_item_threshold,
             delete_item,
             
             // Legacy item name commands
@@ -0,0 +1,405 @@
+//! Filtered, paginated queries over the scan log.
+//!
+//! Pages are addressed with a keyset cursor (the sort value, timestamp and id
+//! of the last row returned) instead of an offset, so paging stays cheap and
+//! stable while new scans keep arriving. The same query runs against the live
+//! database and, when asked, each yearly archive; results are merged on the
+//! sort key.
+use crate::database::{normalize_barcode, to_db_time, ScanLog, LOG_COLUMNS};
+use chrono::{DateTime, Local};
+use rusqlite::types::Value;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+pub const DEFAULT_PAGE_SIZE: i64 = 100;
+pub const MAX_PAGE_SIZE: i64 = 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogSortField {
+    #[default]
+    Timestamp,
+    Barcode,
+    Department,
+    Action,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogQuery {
+    /// Inclusive lower bound on the scan time
+    pub from: Option<DateTime<Local>>,
+    /// Exclusive upper bound on the scan time
+    pub to: Option<DateTime<Local>>,
+    pub department: Option<String>,
+    pub action: Option<String>,
+    /// Exact barcode
+    pub barcode: Option<String>,
+    /// Barcodes starting with this, e.g. a department prefix
+    pub barcode_prefix: Option<String>,
+    /// Substring of the item's catalogue description
+    pub description: Option<String>,
+    pub station: Option<String>,
+    pub include_voided: bool,
+    /// Also search the yearly archive files
+    pub include_archived: bool,
+    pub sort: LogSortField,
+    /// Newest / Z first (the default)
+    pub descending: bool,
+    /// Rows per page, capped at `MAX_PAGE_SIZE`
+    pub page_size: Option<i64>,
+    /// `next_cursor` from the previous page
+    pub cursor: Option<LogCursor>,
+}
+
+/// Position of the last row on a page
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogCursor {
+    /// Value of the sort column; unused when sorting by timestamp
+    pub value: Option<String>,
+    pub timestamp: i64,
+    pub id: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogPage {
+    pub logs: Vec<ScanLog>,
+    /// Pass back as `cursor` to fetch the next page; `None` on the last page
+    pub next_cursor: Option<LogCursor>,
+}
+
+impl Default for LogQuery {
+    fn default() -> Self {
+        LogQuery {
+            from: None,
+            to: None,
+            department: None,
+            action: None,
+            barcode: None,
+            barcode_prefix: None,
+            description: None,
+            station: None,
+            include_voided: false,
+            include_archived: false,
+            sort: LogSortField::Timestamp,
+            descending: true,
+            page_size: None,
+            cursor: None,
+        }
+    }
+}
+
+impl LogQuery {
+    pub fn page_size(&self) -> i64 {
+        self.page_size.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+    }
+
+    fn sort_column(&self) -> Option<&'static str> {
+        match self.sort {
+            LogSortField::Timestamp => None,
+            LogSortField::Barcode => Some("l.barcode"),
+            LogSortField::Department => Some("COALESCE(l.department, '')"),
+            LogSortField::Action => Some("l.action"),
+        }
+    }
+
+    /// SQL and parameters for one page from `schema`'s `logs` table. The
+    /// catalogue is always read from `main`, since archives don't carry one.
+    pub fn to_sql(&self, schema: &str) -> (String, Vec<Value>) {
+        let columns: Vec<String> = LOG_COLUMNS.split(", ").map(|column| format!("l.{}", column)).collect();
+        let mut sql = format!("SELECT {} FROM {}.logs l", columns.join(", "), schema);
+        let mut conditions: Vec<String> = Vec::new();
+        let mut params: Vec<Value> = Vec::new();
+
+        if let Some(description) = &self.description {
+            sql.push_str(" JOIN main.items i ON i.barcode = l.barcode");
+            let placeholder = bind(&mut params, Value::Text(format!("%{}%", escape_like(description))));
+            conditions.push(format!("i.description LIKE {} ESCAPE '\\'", placeholder));
+        }
+        if let Some(from) = &self.from {
+            let placeholder = bind(&mut params, Value::Integer(to_db_time(from)));
+            conditions.push(format!("l.timestamp >= {}", placeholder));
+        }
+        if let Some(to) = &self.to {
+            let placeholder = bind(&mut params, Value::Integer(to_db_time(to)));
+            conditions.push(format!("l.timestamp < {}", placeholder));
+        }
+        if let Some(department) = &self.department {
+            let placeholder = bind(&mut params, Value::Text(department.clone()));
+            conditions.push(format!("l.department = {}", placeholder));
+        }
+        if let Some(action) = &self.action {
+            let placeholder = bind(&mut params, Value::Text(action.clone()));
+            conditions.push(format!("l.action = {}", placeholder));
+        }
+        // Scans and catalogue keys are stored normalised (see Database::log_scan_at),
+        // so the filters only need to normalise their own input
+        if let Some(barcode) = &self.barcode {
+            let placeholder = bind(&mut params, Value::Text(normalize_barcode(barcode)));
+            conditions.push(format!("l.barcode = {}", placeholder));
+        }
+        if let Some(prefix) = &self.barcode_prefix {
+            let placeholder = bind(&mut params, Value::Text(format!("{}%", escape_like(&normalize_barcode(prefix)))));
+            conditions.push(format!("l.barcode LIKE {} ESCAPE '\\'", placeholder));
+        }
+        if let Some(station) = &self.station {
+            let placeholder = bind(&mut params, Value::Text(station.clone()));
+            conditions.push(format!("l.station = {}", placeholder));
+        }
+        if !self.include_voided {
+            conditions.push("l.voided_at IS NULL".to_string());
+        }
+
+        let direction = if self.descending { "DESC" } else { "ASC" };
+        let comparison = if self.descending { "<" } else { ">" };
+        // A list rather than one string: the department column itself contains ", "
+        let key: Vec<&str> = self.sort_column().into_iter().chain(["l.timestamp", "l.id"]).collect();
+        if let Some(cursor) = &self.cursor {
+            let mut placeholders = Vec::new();
+            if self.sort_column().is_some() {
+                placeholders.push(bind(&mut params, Value::Text(cursor.value.clone().unwrap_or_default())));
+            }
+            placeholders.push(bind(&mut params, Value::Integer(cursor.timestamp)));
+            placeholders.push(bind(&mut params, Value::Integer(cursor.id)));
+            conditions.push(format!("({}) {} ({})", key.join(", "), comparison, placeholders.join(", ")));
+        }
+
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+
+        let order: Vec<String> = key.iter().map(|column| format!("{} {}", column, direction)).collect();
+        sql.push_str(&format!(" ORDER BY {}", order.join(", ")));
+
+        // One extra row tells us whether there is another page
+        let placeholder = bind(&mut params, Value::Integer(self.page_size() + 1));
+        sql.push_str(&format!(" LIMIT {}", placeholder));
+
+        (sql, params)
+    }
+
+    /// Cursor pointing at `log` under this query's sort
+    pub fn cursor_for(&self, log: &ScanLog) -> LogCursor {
+        let value = match self.sort {
+            LogSortField::Timestamp => None,
+            LogSortField::Barcode => Some(log.barcode.clone()),
+            LogSortField::Department => Some(log.department.clone().unwrap_or_default()),
+            LogSortField::Action => Some(log.action.clone()),
+        };
+        LogCursor {
+            value,
+            timestamp: to_db_time(&log.timestamp),
+            id: log.id.unwrap_or_default(),
+        }
+    }
+
+    /// Orders rows from different sources the same way the SQL does
+    pub fn compare(&self, a: &ScanLog, b: &ScanLog) -> Ordering {
+        let (a, b) = (self.cursor_for(a), self.cursor_for(b));
+        let ordering = a
+            .value
+            .cmp(&b.value)
+            .then(a.timestamp.cmp(&b.timestamp))
+            .then(a.id.cmp(&b.id));
+        if self.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+
+    /// Whether an archive covering [start, end) can contain matching rows
+    pub fn overlaps(&self, start: i64, end: i64) -> bool {
+        self.from.as_ref().is_none_or(|from| to_db_time(from) < end)
+            && self.to.as_ref().is_none_or(|to| to_db_time(to) > start)
+    }
+}
+
+/// Adds a parameter and returns its placeholder
+fn bind(params: &mut Vec<Value>, value: Value) -> String {
+    params.push(value);
+    format!("?{}", params.len())
+}
+
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tests::test_db;
+    use chrono::Duration;
+
+    fn log(id: i64, barcode: &str, department: Option<&str>, minutes: i64) -> ScanLog {
+        let base = DateTime::parse_from_rfc3339("2024-05-01T08:00:00Z").unwrap().with_timezone(&Local);
+        ScanLog {
+            id: Some(id),
+            timestamp: base + Duration::minutes(minutes),
+            barcode: barcode.to_string(),
+            action: "check-out".to_string(),
+            department: department.map(str::to_string),
+            station: None,
+            voided_at: None,
+            voided_by: None,
+            void_reason: None,
+        }
+    }
+
+    #[test]
+    fn default_query_sql() {
+        let (sql, params) = LogQuery::default().to_sql("main");
+        let columns: Vec<String> = LOG_COLUMNS.split(", ").map(|column| format!("l.{}", column)).collect();
+        assert_eq!(
+            sql,
+            format!(
+                "SELECT {} FROM main.logs l WHERE l.voided_at IS NULL ORDER BY l.timestamp DESC, l.id DESC LIMIT ?1",
+                columns.join(", ")
+            )
+        );
+        assert_eq!(params, vec![Value::Integer(DEFAULT_PAGE_SIZE + 1)]);
+    }
+
+    #[test]
+    fn filters_normalise_their_input() {
+        let query = LogQuery {
+            barcode: Some(" käkx019 ".to_string()),
+            barcode_prefix: Some("ort_".to_string()),
+            description: Some("50%".to_string()),
+            include_voided: true,
+            page_size: Some(5000),
+            ..Default::default()
+        };
+        let (sql, params) = query.to_sql("archive");
+        assert!(sql.contains(" FROM archive.logs l JOIN main.items i ON i.barcode = l.barcode WHERE "), "{}", sql);
+        assert!(sql.contains("i.description LIKE ?1 ESCAPE '\\'"), "{}", sql);
+        assert!(sql.contains("l.barcode = ?2 AND l.barcode LIKE ?3 ESCAPE '\\'"), "{}", sql);
+        assert!(!sql.contains("l.voided_at IS NULL"), "{}", sql);
+        assert_eq!(
+            params,
+            vec![
+                Value::Text("%50\\%%".to_string()),
+                Value::Text("KÄKX019".to_string()),
+                Value::Text("ORT\\_%".to_string()),
+                Value::Integer(MAX_PAGE_SIZE + 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn cursor_continues_after_the_sort_key() {
+        let query = LogQuery {
+            sort: LogSortField::Department,
+            descending: false,
+            cursor: Some(LogQuery::default().cursor_for(&log(7, "ORTX1", None, 0))),
+            ..Default::default()
+        };
+        // The cursor was made under a timestamp sort, so it carries no value
+        assert_eq!(query.cursor.as_ref().unwrap().value, None);
+
+        let (sql, params) = query.to_sql("main");
+        assert!(sql.contains(
+            "(COALESCE(l.department, ''), l.timestamp, l.id) > (?1, ?2, ?3) \
+             ORDER BY COALESCE(l.department, '') ASC, l.timestamp ASC, l.id ASC LIMIT ?4"
+        ), "{}", sql);
+        assert_eq!(params[0], Value::Text(String::new()));
+        assert_eq!(params[2], Value::Integer(7));
+    }
+
+    #[test]
+    fn compare_matches_the_sql_order() {
+        let logs = [
+            log(1, "ORTX2", Some("Ortopedi"), 10),
+            log(2, "KÄKX1", None, 10),
+            log(3, "ORTX1", Some("Ortopedi"), 0),
+            log(4, "ORTX1", Some("Käkkirurgi"), 10),
+        ];
+        let order = |query: &LogQuery| {
+            let mut sorted = logs.to_vec();
+            sorted.sort_by(|a, b| query.compare(a, b));
+            sorted.iter().map(|log| log.id.unwrap()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(order(&LogQuery::default()), vec![4, 2, 1, 3]);
+        let by_department = LogQuery {
+            sort: LogSortField::Department,
+            descending: false,
+            ..Default::default()
+        };
+        assert_eq!(order(&by_department), vec![2, 4, 3, 1]);
+        let by_barcode = LogQuery {
+            sort: LogSortField::Barcode,
+            ..Default::default()
+        };
+        assert_eq!(order(&by_barcode), vec![1, 4, 3, 2]);
+    }
+
+    #[test]
+    fn pages_cover_every_row_once() {
+        let db = test_db("log-query-pages");
+        let start = Local::now() - Duration::hours(1);
+        for (n, barcode) in ["ortx3", "ORTX1", "käkx2", "ORTX1", "ortx3", "ORTX2", "käkx2"].iter().enumerate() {
+            let action = if n % 2 == 0 { "check-out" } else { "check-in" };
+            let department = match barcode.chars().next() {
+                Some('k') => Some("Käkkirurgi"),
+                _ if *barcode == "ORTX2" => None,
+                _ => Some("Ortopedi"),
+            };
+            db.log_scan_at(barcode, action, department, start + Duration::minutes(n as i64 / 2))
+                .unwrap();
+        }
+
+        for sort in [LogSortField::Timestamp, LogSortField::Barcode, LogSortField::Department, LogSortField::Action] {
+            for descending in [true, false] {
+                let mut query = LogQuery {
+                    sort,
+                    descending,
+                    page_size: Some(2),
+                    ..Default::default()
+                };
+                let mut seen = Vec::new();
+                loop {
+                    let page = db.query_logs(&query).unwrap();
+                    seen.extend(page.logs);
+                    match page.next_cursor {
+                        Some(cursor) => query.cursor = Some(cursor),
+                        None => break,
+                    }
+                }
+                let mut expected = seen.clone();
+                expected.sort_by(|a, b| query.compare(a, b));
+                let ids = |logs: &[ScanLog]| logs.iter().map(|log| log.id.unwrap()).collect::<Vec<_>>();
+                assert_eq!(ids(&seen), ids(&expected), "{:?} descending={}", sort, descending);
+                assert_eq!(seen.len(), 7);
+            }
+        }
+
+        let query = LogQuery {
+            barcode: Some("Käkx2".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(db.query_logs(&query).unwrap().logs.len(), 2);
+        let query = LogQuery {
+            barcode_prefix: Some("ortx".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(db.query_logs(&query).unwrap().logs.len(), 5);
+    }
+
+    #[test]
+    fn description_filter_joins_the_catalogue() {
+        let db = test_db("log-query-description");
+        db.add_item("ortx1", Some("Ortopedi"), Some("Benfil 50%"), None).unwrap();
+        db.log_scan("ORTX1", "check-out", Some("Ortopedi")).unwrap();
+        db.log_scan("ortx2", "check-out", Some("Ortopedi")).unwrap();
+
+        let query = |description: &str| LogQuery {
+            description: Some(description.to_string()),
+            ..Default::default()
+        };
+        let found = db.query_logs(&query("50%")).unwrap().logs;
+        assert_eq!(found.iter().map(|log| log.barcode.as_str()).collect::<Vec<_>>(), vec!["ORTX1"]);
+        assert!(db.query_logs(&query("5_%")).unwrap().logs.is_empty());
+    }
+}
@@ -1,40 +1,89 @@
-use crate::database::{Database, ScanLog};
+use crate::database::{normalize_barcode, DepartmentMapping, ScanLog};
+use crate::db_service::{DbService, RetryPolicy};
+use crate::offline_queue::{is_unavailable, FailedScan, OfflineQueue};
+use crate::webhook::WebhookDispatcher;
+use chrono::Local;
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tauri::{AppHandle, Emitter};
 
 #[derive(Debug, Clone)]
 pub struct ScanAction {
     pub action: String,
     pub department: Option<String>,
+    /// The shared database was unreachable; the scan is waiting in the offline queue
+    pub queued: bool,
 }
 
 pub struct Logger {
-    db: Database,
+    db: DbService,
     checked_out_cache: HashMap<String, bool>,
+    // Last known mappings, so scans can still be validated while the share is down
+    mappings_cache: Vec<DepartmentMapping>,
+    offline_queue: OfflineQueue,
+    webhooks: Arc<WebhookDispatcher>,
 }
 
 impl Logger {
-    pub fn new() -> Result<Self, rusqlite::Error> {
-        let db = Database::new()?;
+    pub fn new(db: DbService, webhooks: Arc<WebhookDispatcher>) -> Result<Self, rusqlite::Error> {
+        Self::with_queue(db, webhooks, OfflineQueue::open()?)
+    }
+
+    fn with_queue(db: DbService, webhooks: Arc<WebhookDispatcher>, offline_queue: OfflineQueue) -> Result<Self, rusqlite::Error> {
         let mut logger = Logger {
             db,
             checked_out_cache: HashMap::new(),
+            mappings_cache: Vec::new(),
+            offline_queue,
+            webhooks,
         };
-        logger.refresh_cache()?;
+        match logger.refresh_cache() {
+            // Started while the share is down: carry on with what this station last saw
+            Err(e) if is_unavailable(&e) => {
+                eprintln!("Shared database unavailable, using the locally cached state: {}", e);
+                let (mappings, checked_out) = logger.offline_queue.load_cache()?;
+                logger.mappings_cache = mappings;
+                logger.checked_out_cache = checked_out.into_iter().map(|barcode| (barcode, true)).collect();
+            }
+            other => other?,
+        }
         Ok(logger)
     }
 
     fn refresh_cache(&mut self) -> Result<(), rusqlite::Error> {
+        let checked_out_items = self.db.with(|db| db.get_checked_out_items())?;
         self.checked_out_cache.clear();
-        let checked_out_items = self.db.get_checked_out_items()?;
         for item in checked_out_items {
             self.checked_out_cache.insert(item.barcode, true);
         }
+        self.mappings_cache = self.db.with(|db| db.get_department_mappings())?;
+
+        let checked_out: Vec<String> = self.checked_out_cache.keys().cloned().collect();
+        if let Err(e) = self.offline_queue.save_cache(&self.mappings_cache, &checked_out) {
+            eprintln!("Failed to save the scan cache locally: {}", e);
+        }
         Ok(())
     }
 
+    fn cached_department(&self, barcode: &str) -> Option<String> {
+        let barcode = barcode.to_uppercase();
+        self.mappings_cache
+            .iter()
+            .filter(|mapping| barcode.starts_with(&mapping.prefix.to_uppercase()))
+            .max_by_key(|mapping| mapping.prefix.len())
+            .map(|mapping| mapping.department.clone())
+    }
+
+    fn resolve_department(&self, barcode: &str) -> Result<Option<String>, rusqlite::Error> {
+        match self.db.with(|db| db.get_department_from_barcode(barcode)) {
+            Err(e) if is_unavailable(&e) => Ok(self.cached_department(barcode)),
+            other => other,
+        }
+    }
+
     pub fn has_valid_department_prefix(&self, barcode: &str) -> bool {
         // Check if barcode starts with any valid department prefix
-        match self.db.get_department_mappings() {
+        match self.db.with(|db| db.get_department_mappings()) {
             Ok(mappings) => {
                 for mapping in mappings {
                     if barcode.to_uppercase().starts_with(&mapping.prefix.to_uppercase()) {
@@ -43,86 +92,286 @@ impl Logger {
                 }
                 false
             }
+            // Fall back to the last known mappings so offline scans aren't ignored
+            Err(e) if is_unavailable(&e) => self.cached_department(barcode).is_some(),
             Err(_) => false, // If we can't check, default to false to be safe
         }
     }
 
-    pub fn process_barcode_scan(&mut self, barcode: &str) -> Result<ScanAction, Box<dyn std::error::Error>> {
-        // Determine department from barcode prefix
-        let department = self.db.get_department_from_barcode(barcode)?;
-        
+    /// Writes a scan to the shared database, or to the offline queue when the
+    /// database can't be reached. `forced_action` skips the check-in/out toggle.
+    fn record(&mut self, barcode: &str, forced_action: Option<&str>) -> Result<ScanAction, Box<dyn std::error::Error>> {
+        // Same form the database stores, so the cache and offline queue agree with it
+        let barcode = normalize_barcode(barcode);
+        let barcode = barcode.as_str();
+        let department = self.resolve_department(barcode)?;
+
         // Reject barcodes that don't match any department prefix
         if department.is_none() {
             return Err("No matching department found for barcode prefix".into());
         }
-        
-        // Determine action based on current state
-        let action = if self.checked_out_cache.contains_key(barcode) {
-            // Item is currently checked out, so this is a check-in
-            self.checked_out_cache.remove(barcode);
-            "check-in"
+
+        // Queued scans happened first and must reach the database before this one
+        let queue_drained = match self.flush_offline_queue() {
+            Ok(remaining) => remaining == 0,
+            Err(e) => {
+                eprintln!("Error flushing offline scan queue: {}", e);
+                false
+            }
+        };
+
+        let written = if queue_drained {
+            Some(match forced_action {
+                Some(action) => self
+                    .db
+                    .with_policy(RetryPolicy::SCAN, |db| db.log_scan(barcode, action, department.as_deref()))
+                    .map(|_| action.to_string()),
+                // Determine the action from the shared database rather than our cache:
+                // another station may have scanned this item since we last looked
+                None => self
+                    .db
+                    .with_policy(RetryPolicy::SCAN, |db| db.record_scan(barcode, department.as_deref())),
+            })
         } else {
-            // Item is not checked out, so this is a check-out
-            self.checked_out_cache.insert(barcode.to_string(), true);
-            "check-out"
+            None
+        };
+
+        let (action, queued) = match written {
+            Some(Ok(action)) => (action, false),
+            Some(Err(e)) if !is_unavailable(&e) => return Err(e.into()),
+            _ => {
+                // Offline: toggle based on what this station last knew
+                let action = match forced_action {
+                    Some(action) => action.to_string(),
+                    None if self.checked_out_cache.contains_key(barcode) => "check-in".to_string(),
+                    None => "check-out".to_string(),
+                };
+                self.offline_queue.enqueue(barcode, &action, department.as_deref(), Local::now())?;
+                eprintln!("Shared database unavailable, queued {} of {} locally", action, barcode);
+                (action, true)
+            }
         };
 
-        // Log the scan to database
-        self.db.log_scan(barcode, action, department.as_deref())?;
+        if action == "check-out" {
+            self.checked_out_cache.insert(barcode.to_string(), true);
+        } else {
+            self.checked_out_cache.remove(barcode);
+        }
+        if let Err(e) = self.offline_queue.cache_item_state(barcode, action == "check-out") {
+            eprintln!("Failed to save the scan cache locally: {}", e);
+        }
 
         Ok(ScanAction {
-            action: action.to_string(),
+            action,
             department,
+            queued,
         })
     }
 
-    pub fn get_recent_logs(&self, limit: Option<i64>) -> Result<Vec<ScanLog>, rusqlite::Error> {
-        self.db.get_logs(limit)
+    /// Replays queued scans in the order they were made and publishes them to
+    /// webhooks, which couldn't be reached while they were queued. Stops at the
+    /// first one that still can't be written and returns how many are left.
+    /// A scan the database rejects outright is moved to the failed scans
+    /// instead, so it can't hold up the ones behind it.
+    pub fn flush_offline_queue(&mut self) -> Result<usize, rusqlite::Error> {
+        let pending = self.offline_queue.pending()?;
+        if pending.is_empty() {
+            return Ok(0);
+        }
+
+        let mut flushed = 0;
+        let mut failed = 0;
+        for scan in &pending {
+            let result = self.db.with_policy(RetryPolicy::SCAN, |db| {
+                db.log_scan_at(&scan.barcode, &scan.action, scan.department.as_deref(), scan.scanned_at)
+            });
+            match result {
+                Ok(_) => {
+                    self.offline_queue.remove(scan.id)?;
+                    flushed += 1;
+                    let result = serde_json::json!({
+                        "barcode": scan.barcode,
+                        "action": scan.action,
+                        "department": scan.department,
+                        "queued": false,
+                        "source": "offline-queue",
+                        "scanned_at": scan.scanned_at
+                    });
+                    self.webhooks.publish(&self.db, "barcode-scanned", &result);
+                }
+                Err(e) if is_unavailable(&e) => break,
+                Err(e) => {
+                    eprintln!("Queued {} of {} was rejected, setting it aside: {}", scan.action, scan.barcode, e);
+                    self.offline_queue.move_to_failed(scan.id, &e.to_string())?;
+                    failed += 1;
+                }
+            }
+        }
+
+        if flushed > 0 {
+            println!("Flushed {} queued scans to the shared database", flushed);
+            // Other stations may have changed things while we were offline
+            if let Err(e) = self.refresh_cache() {
+                eprintln!("Failed to refresh scan cache after flush: {}", e);
+            }
+        }
+
+        Ok(pending.len() - flushed - failed)
+    }
+
+    pub fn pending_scan_count(&self) -> Result<usize, rusqlite::Error> {
+        self.offline_queue.count()
+    }
+
+    /// Queued scans the shared database rejected, for someone to enter by hand
+    pub fn failed_scans(&self) -> Result<Vec<FailedScan>, rusqlite::Error> {
+        self.offline_queue.failed()
+    }
+
+    pub fn discard_failed_scan(&self, id: i64) -> Result<bool, rusqlite::Error> {
+        self.offline_queue.discard_failed(id)
+    }
+
+    /// Periodically retries the offline queue and tells the UI how many scans
+    /// are waiting and how many were rejected
+    pub fn start_offline_flush(app_handle: AppHandle, logger: Arc<Mutex<Logger>>) {
+        std::thread::spawn(move || {
+            let mut last_reported = None;
+            loop {
+                let pending = match logger.lock() {
+                    Ok(mut logger) => match logger.pending_scan_count() {
+                        Ok(0) => Ok(0),
+                        Ok(_) => logger.flush_offline_queue(),
+                        Err(e) => Err(e),
+                    }
+                    // Also counts scans rejected by a flush from `record`
+                    .and_then(|count| Ok((count, logger.offline_queue.failed_count()?))),
+                    Err(_) => break,
+                };
+
+                match pending {
+                    Ok((count, failed)) if last_reported != Some((count, failed)) => {
+                        let _ = app_handle.emit(
+                            "pending-scans-changed",
+                            serde_json::json!({ "count": count, "failed": failed }),
+                        );
+                        last_reported = Some((count, failed));
+                    }
+                    Ok(_) => {}
+                    Err(e) => eprintln!("Error flushing offline scan queue: {}", e),
+                }
+
+                std::thread::sleep(std::time::Duration::from_secs(5));
+            }
+        });
+    }
+
+    pub fn process_barcode_scan(&mut self, barcode: &str) -> Result<ScanAction, Box<dyn std::error::Error>> {
+        self.record(barcode, None)
+    }
+
+    pub fn get_recent_logs(&self, limit: Option<i64>, include_voided: bool) -> Result<Vec<ScanLog>, rusqlite::Error> {
+        self.db.with(|db| db.get_logs(limit, include_voided))
+    }
+
+    /// Voids a mis-scan (this station's latest scan when `log_id` is `None`)
+    /// and rolls the cached state back with it
+    pub fn void_scan(&mut self, log_id: Option<i64>, reason: &str, operator: Option<&str>) -> Result<ScanLog, Box<dyn std::error::Error>> {
+        let reason = reason.trim();
+        if reason.is_empty() {
+            return Err("A reason is required to void a scan".into());
+        }
+
+        let log_id = match log_id {
+            Some(log_id) => log_id,
+            None => self
+                .db
+                .with(|db| db.get_last_scan())?
+                .and_then(|log| log.id)
+                .ok_or("No scan to undo at this station")?,
+        };
+
+        let voided = match self.db.with(|db| db.void_scan(log_id, reason, operator)) {
+            Ok(voided) => voided,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                return Err(format!("Scan {} not found (it may have been archived)", log_id).into())
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        // The item's state may have flipped back; reload rather than guess
+        self.refresh_cache()?;
+        Ok(voided)
     }
 
     pub fn get_checked_out_items(&self) -> Result<Vec<ScanLog>, rusqlite::Error> {
-        self.db.get_checked_out_items()
+        self.db.with(|db| db.get_checked_out_items())
     }
 
     pub fn get_department_stats(&self) -> Result<Vec<(String, i64)>, rusqlite::Error> {
-        self.db.get_department_stats()
+        self.db.with(|db| db.get_department_stats())
     }
 
-    pub fn clear_all_logs(&self) -> Result<(), rusqlite::Error> {
-        self.db.clear_logs()
+    pub fn clear_all_logs(&mut self, operator: Option<&str>) -> Result<(), rusqlite::Error> {
+        self.db.with(|db| db.clear_logs(operator))?;
+        self.refresh_cache()
+    }
+
+    /// Recomputes every item's current state from the logs and reloads the cache
+    pub fn rebuild_item_status(&mut self) -> Result<usize, rusqlite::Error> {
+        let count = self.db.with(|db| db.rebuild_item_status())?;
+        self.refresh_cache()?;
+        Ok(count)
     }
 
     pub fn force_check_in(&mut self, barcode: &str) -> Result<ScanAction, Box<dyn std::error::Error>> {
-        let department = self.db.get_department_from_barcode(barcode)?;
-        
-        // Reject barcodes that don't match any department prefix
-        if department.is_none() {
-            return Err("No matching department found for barcode prefix".into());
-        }
-        
-        self.db.log_scan(barcode, "check-in", department.as_deref())?;
-        self.checked_out_cache.remove(barcode);
-        
-        Ok(ScanAction {
-            action: "check-in".to_string(),
-            department,
-        })
+        self.record(barcode, Some("check-in"))
     }
 
     pub fn force_check_out(&mut self, barcode: &str) -> Result<ScanAction, Box<dyn std::error::Error>> {
-        let department = self.db.get_department_from_barcode(barcode)?;
-        
-        // Reject barcodes that don't match any department prefix
-        if department.is_none() {
-            return Err("No matching department found for barcode prefix".into());
-        }
-        
-        self.db.log_scan(barcode, "check-out", department.as_deref())?;
-        self.checked_out_cache.insert(barcode.to_string(), true);
-        
-        Ok(ScanAction {
-            action: "check-out".to_string(),
-            department,
-        })
+        self.record(barcode, Some("check-out"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tests::{test_db, test_db_path};
+
+    #[test]
+    fn a_rejected_queued_scan_does_not_block_the_rest() {
+        let db = DbService::from_database(test_db("dead-letter"));
+        let queue_path = test_db_path("dead-letter-queue").with_file_name("pending_scans.db");
+        let queue = OfflineQueue::open_at(&queue_path).unwrap();
+        let scanned_at = Local::now() - chrono::Duration::minutes(10);
+        queue.enqueue("ORTX1", "check-out", Some("Ortopedi"), scanned_at).unwrap();
+        // Fails the CHECK on logs.action however often it is retried
+        queue.enqueue("ORTX2", "lost", Some("Ortopedi"), scanned_at).unwrap();
+        queue.enqueue("ORTX3", "check-out", Some("Ortopedi"), scanned_at).unwrap();
+
+        let mut logger = Logger::with_queue(db.clone(), Arc::new(WebhookDispatcher::new()), queue).unwrap();
+        assert_eq!(logger.flush_offline_queue().unwrap(), 0);
+        assert_eq!(logger.pending_scan_count().unwrap(), 0);
+
+        let failed = logger.failed_scans().unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!((failed[0].barcode.as_str(), failed[0].action.as_str()), ("ORTX2", "lost"));
+        assert!(failed[0].error.contains("CHECK"), "{}", failed[0].error);
+
+        let mut logged: Vec<String> = logger
+            .get_recent_logs(None, false)
+            .unwrap()
+            .into_iter()
+            .map(|log| log.barcode)
+            .collect();
+        logged.sort();
+        assert_eq!(logged, vec!["ORTX1", "ORTX3"]);
+
+        // New scans go straight to the database again instead of queueing behind it
+        let scan = logger.process_barcode_scan("ORTX2").unwrap();
+        assert_eq!((scan.action.as_str(), scan.queued), ("check-out", false));
+
+        assert!(logger.discard_failed_scan(failed[0].id).unwrap());
+        assert!(logger.failed_scans().unwrap().is_empty());
     }
 }
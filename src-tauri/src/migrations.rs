@@ -0,0 +1,1033 @@
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use rusqlite::types::Value;
+use rusqlite::{ffi, params, Connection, OptionalExtension, Result, Transaction, TransactionBehavior};
+use serde::Serialize;
+use std::fmt;
+use crate::database::repair_loans;
+
+/// A single schema upgrade step. Steps are applied in ascending `version`
+/// order, each inside its own transaction, and `PRAGMA user_version` is bumped
+/// in the same transaction so a failed step leaves the database untouched.
+pub struct Migration {
+    pub version: i32,
+    pub description: &'static str,
+    /// Applies the step. Anything worth telling the operator about (skipped
+    /// rows, converted data) is pushed onto `notes` and ends up in the report.
+    pub apply: fn(tx: &Transaction, notes: &mut Vec<String>) -> Result<()>,
+}
+
+/// Ordered list of every migration. Never edit or reorder a released entry;
+/// append a new one instead.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Baseline schema (logs, settings, department mappings, items)",
+        apply: migrate_v1_baseline,
+    },
+    Migration {
+        version: 2,
+        description: "Merge legacy item_names into the items catalogue",
+        apply: migrate_v2_merge_item_names,
+    },
+    Migration {
+        version: 3,
+        description: "Store log timestamps as UTC epoch milliseconds",
+        apply: migrate_v3_utc_timestamps,
+    },
+    Migration {
+        version: 4,
+        description: "Add item_status projection of each barcode's current state",
+        apply: migrate_v4_item_status,
+    },
+    Migration {
+        version: 5,
+        description: "Add loan records pairing check-outs with check-ins",
+        apply: migrate_v5_loans,
+    },
+    Migration {
+        version: 6,
+        description: "Add archive retention setting",
+        apply: migrate_v6_archive_retention,
+    },
+    Migration {
+        version: 7,
+        description: "Add audit trail for configuration and catalogue changes",
+        apply: migrate_v7_audit_log,
+    },
+    Migration {
+        version: 8,
+        description: "Allow scans to be voided with a reason",
+        apply: migrate_v8_void_scans,
+    },
+    Migration {
+        version: 9,
+        description: "Add overdue thresholds per department mapping and item",
+        apply: migrate_v9_overdue_thresholds,
+    },
+    Migration {
+        version: 10,
+        description: "Add persisted overdue alerts with acknowledgement and escalation",
+        apply: migrate_v10_alerts,
+    },
+    Migration {
+        version: 11,
+        description: "Add working calendar (opening hours and holidays)",
+        apply: migrate_v11_working_calendar,
+    },
+    Migration {
+        version: 12,
+        description: "Add alert check interval setting",
+        apply: migrate_v12_alert_check_interval,
+    },
+    Migration {
+        version: 13,
+        description: "Add SMTP settings and email recipients for overdue alerts",
+        apply: migrate_v13_email_notifications,
+    },
+    Migration {
+        version: 14,
+        description: "Create webhook targets and delivery outbox",
+        apply: migrate_v14_webhooks,
+    },
+    Migration {
+        version: 15,
+        description: "Add desktop notification setting",
+        apply: migrate_v15_desktop_notifications,
+    },
+    Migration {
+        version: 16,
+        description: "Store scanned barcodes upper-case like the catalogue",
+        apply: migrate_v16_normalize_scan_barcodes,
+    },
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AppliedMigration {
+    pub version: i32,
+    pub description: String,
+    pub notes: Vec<String>,
+}
+
+/// Outcome of bringing a database up to the latest schema version.
+#[derive(Debug, Clone, Serialize)]
+pub struct MigrationReport {
+    pub previous_version: i32,
+    pub current_version: i32,
+    pub applied: Vec<AppliedMigration>,
+}
+
+impl MigrationReport {
+    pub fn is_up_to_date(&self) -> bool {
+        self.applied.is_empty()
+    }
+}
+
+impl fmt::Display for MigrationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_up_to_date() {
+            return write!(f, "Database schema is up to date (version {})", self.current_version);
+        }
+
+        write!(
+            f,
+            "Database schema upgraded from version {} to {}:",
+            self.previous_version, self.current_version
+        )?;
+        for migration in &self.applied {
+            write!(f, "\n  v{}: {}", migration.version, migration.description)?;
+            for note in &migration.notes {
+                write!(f, "\n    - {}", note)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Highest schema version this build knows how to produce.
+pub fn latest_version() -> i32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+pub fn current_version(conn: &Connection) -> Result<i32> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+/// Applies every pending migration in order.
+///
+/// Each step takes the write lock up front (`BEGIN IMMEDIATE`) and re-reads the
+/// schema version, so when several stations start at once against the same
+/// shared file only one of them performs a given step.
+pub fn run_migrations(conn: &mut Connection) -> Result<MigrationReport> {
+    let previous_version = current_version(conn)?;
+    let latest = latest_version();
+
+    if previous_version > latest {
+        return Err(rusqlite::Error::SqliteFailure(
+            ffi::Error::new(ffi::SQLITE_ERROR),
+            Some(format!(
+                "Database schema version {} is newer than this application supports ({}). Please update the application.",
+                previous_version, latest
+            )),
+        ));
+    }
+
+    let mut applied = Vec::new();
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > previous_version) {
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        // Another station may have applied this step while we waited for the lock
+        let version_now: i32 = tx.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version_now >= migration.version {
+            continue;
+        }
+
+        let mut notes = Vec::new();
+        (migration.apply)(&tx, &mut notes)?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+
+        applied.push(AppliedMigration {
+            version: migration.version,
+            description: migration.description.to_string(),
+            notes,
+        });
+    }
+
+    Ok(MigrationReport {
+        previous_version,
+        current_version: current_version(conn)?,
+        applied,
+    })
+}
+
+// ------------------ Migration steps ------------------
+
+fn migrate_v1_baseline(tx: &Transaction, _notes: &mut Vec<String>) -> Result<()> {
+    // Databases created before versioning already have these tables, so every
+    // statement here must be safe to run against an existing install.
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS logs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp TEXT NOT NULL,
+            barcode TEXT NOT NULL,
+            action TEXT CHECK(action IN ('check-in', 'check-out')) NOT NULL,
+            department TEXT
+        );
+
+        CREATE TABLE IF NOT EXISTS settings (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS department_mappings (
+            prefix TEXT PRIMARY KEY,
+            department TEXT NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS items (
+            barcode TEXT PRIMARY KEY,
+            department TEXT,
+            description TEXT
+        );",
+    )?;
+
+    // Insert default department mappings only if table is empty
+    let count: i64 = tx.query_row("SELECT COUNT(*) FROM department_mappings", [], |row| row.get(0))?;
+    if count == 0 {
+        tx.execute(
+            "INSERT INTO department_mappings (prefix, department) VALUES
+            ('KÄKX', 'Käkkirurgi'),
+            ('ORTX', 'Ortopedi'),
+            ('NEURX', 'Neurokirurgi')",
+            [],
+        )?;
+    }
+
+    // Insert default settings
+    tx.execute(
+        "INSERT OR IGNORE INTO settings (key, value) VALUES
+        ('auto_export_enabled', 'false'),
+        ('export_path', ''),
+        ('alert_threshold_hours', '24'),
+        ('trigger_barcode', 'SCAN_START')",
+        [],
+    )?;
+
+    Ok(())
+}
+
+fn migrate_v2_merge_item_names(tx: &Transaction, notes: &mut Vec<String>) -> Result<()> {
+    // The catalogue is keyed on upper-case barcodes. SQLite's UPPER() only folds
+    // ASCII, so normalise in Rust to get 'ä' -> 'Ä' right.
+    let existing: Vec<String> = {
+        let mut stmt = tx.prepare("SELECT barcode FROM items")?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        rows.collect::<Result<_>>()?
+    };
+    for barcode in existing {
+        let normalized = barcode.trim().to_uppercase();
+        if normalized == barcode {
+            continue;
+        }
+        let updated = tx.execute(
+            "UPDATE OR IGNORE items SET barcode = ?2 WHERE barcode = ?1",
+            params![barcode, normalized],
+        )?;
+        if updated == 0 {
+            notes.push(format!(
+                "Kept item '{}' as is: '{}' already exists in the catalogue",
+                barcode, normalized
+            ));
+        }
+    }
+
+    // Some early portable builds stored names directly in an items.name column
+    let has_name_column: bool = tx.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info('items') WHERE name = 'name'",
+        [],
+        |row| row.get::<_, i64>(0).map(|count| count > 0),
+    )?;
+    if has_name_column {
+        let filled = tx.execute(
+            "UPDATE items SET description = name
+             WHERE (description IS NULL OR description = '') AND name IS NOT NULL AND name <> ''",
+            [],
+        )?;
+        notes.push(format!("Copied {} names from items.name into descriptions", filled));
+    }
+
+    // Older builds kept display names in a separate item_names table, created on
+    // first import, so it only exists on stations that imported a name list
+    let has_item_names: bool = tx.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'item_names'",
+        [],
+        |row| row.get::<_, i64>(0).map(|count| count > 0),
+    )?;
+    if !has_item_names {
+        return Ok(());
+    }
+
+    let legacy_names: Vec<(String, String)> = {
+        let mut stmt = tx.prepare("SELECT barcode, name FROM item_names")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+        rows.collect::<Result<_>>()?
+    };
+    let mut merged = 0;
+    for (barcode, name) in &legacy_names {
+        // An existing catalogue description wins over the legacy name
+        merged += tx.execute(
+            "INSERT INTO items (barcode, description) VALUES (?1, ?2)
+             ON CONFLICT(barcode) DO UPDATE SET description = excluded.description
+             WHERE items.description IS NULL OR items.description = ''",
+            params![barcode.trim().to_uppercase(), name],
+        )?;
+    }
+    if !legacy_names.is_empty() {
+        notes.push(format!(
+            "Merged {} of {} legacy item names into the catalogue",
+            merged,
+            legacy_names.len()
+        ));
+    }
+
+    tx.execute("DROP TABLE item_names", [])?;
+    Ok(())
+}
+
+/// Parses the timestamp formats older builds wrote (RFC 3339 with the station's
+/// offset, or a bare local time) into UTC epoch milliseconds
+fn parse_legacy_timestamp(raw: &str) -> Option<i64> {
+    let raw = raw.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(raw) {
+        return Some(time.timestamp_millis());
+    }
+    ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(raw, format).ok())
+        .and_then(|naive| Local.from_local_datetime(&naive).earliest())
+        .map(|time| time.timestamp_millis())
+}
+
+fn migrate_v3_utc_timestamps(tx: &Transaction, notes: &mut Vec<String>) -> Result<()> {
+    // SQLite can't change a column's type in place, so rebuild the table. Keep
+    // the AUTOINCREMENT counter so ids of deleted rows are never handed out again.
+    let sequence: Option<i64> = tx
+        .query_row("SELECT seq FROM sqlite_sequence WHERE name = 'logs'", [], |row| row.get(0))
+        .optional()?;
+
+    tx.execute_batch(
+        "CREATE TABLE logs_new (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp INTEGER NOT NULL,
+            barcode TEXT NOT NULL,
+            action TEXT CHECK(action IN ('check-in', 'check-out')) NOT NULL,
+            department TEXT
+        );
+
+        CREATE TABLE IF NOT EXISTS logs_unconverted (
+            id INTEGER PRIMARY KEY,
+            raw_timestamp TEXT,
+            barcode TEXT NOT NULL,
+            action TEXT NOT NULL,
+            department TEXT
+        );",
+    )?;
+
+    let rows: Vec<(i64, Value, String, String, Option<String>)> = {
+        let mut stmt = tx.prepare("SELECT id, timestamp, barcode, action, department FROM logs ORDER BY id")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+        })?;
+        rows.collect::<Result<_>>()?
+    };
+
+    let mut converted = 0;
+    let mut unconverted_ids = Vec::new();
+    for (id, raw, barcode, action, department) in rows {
+        let millis = match &raw {
+            Value::Text(text) => parse_legacy_timestamp(text),
+            Value::Integer(millis) => Some(*millis),
+            _ => None,
+        };
+
+        match millis {
+            Some(millis) => {
+                tx.execute(
+                    "INSERT INTO logs_new (id, timestamp, barcode, action, department) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![id, millis, barcode, action, department],
+                )?;
+                converted += 1;
+            }
+            None => {
+                let raw_text = match raw {
+                    Value::Text(text) => Some(text),
+                    Value::Real(real) => Some(real.to_string()),
+                    _ => None,
+                };
+                tx.execute(
+                    "INSERT OR REPLACE INTO logs_unconverted (id, raw_timestamp, barcode, action, department)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![id, raw_text, barcode, action, department],
+                )?;
+                unconverted_ids.push(id);
+            }
+        }
+    }
+
+    tx.execute_batch(
+        "DROP TABLE logs;
+        ALTER TABLE logs_new RENAME TO logs;
+        -- Per-item state and history lookups
+        CREATE INDEX idx_logs_barcode_timestamp ON logs (barcode, timestamp);
+        CREATE INDEX idx_logs_timestamp ON logs (timestamp);",
+    )?;
+
+    if let Some(seq) = sequence {
+        let updated = tx.execute(
+            "UPDATE sqlite_sequence SET seq = MAX(seq, ?1) WHERE name = 'logs'",
+            params![seq],
+        )?;
+        if updated == 0 {
+            tx.execute("INSERT INTO sqlite_sequence (name, seq) VALUES ('logs', ?1)", params![seq])?;
+        }
+    }
+
+    if converted > 0 {
+        notes.push(format!("Converted {} log timestamps to UTC", converted));
+    }
+    if !unconverted_ids.is_empty() {
+        let shown: Vec<String> = unconverted_ids.iter().take(20).map(|id| id.to_string()).collect();
+        notes.push(format!(
+            "{} log rows had unreadable timestamps and were moved to logs_unconverted (ids {}{})",
+            unconverted_ids.len(),
+            shown.join(", "),
+            if unconverted_ids.len() > shown.len() { ", ..." } else { "" }
+        ));
+    }
+    Ok(())
+}
+
+fn migrate_v4_item_status(tx: &Transaction, notes: &mut Vec<String>) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS item_status (
+            barcode TEXT PRIMARY KEY,
+            state TEXT CHECK(state IN ('check-in', 'check-out')) NOT NULL,
+            department TEXT,
+            since INTEGER NOT NULL,
+            log_id INTEGER NOT NULL
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_item_status_state ON item_status (state, department);",
+    )?;
+
+    let populated = tx.execute(
+        "INSERT INTO item_status (barcode, state, department, since, log_id)
+         SELECT l.barcode, l.action, l.department, l.timestamp, l.id
+         FROM logs l
+         WHERE l.id = (
+             SELECT latest.id FROM logs latest
+             WHERE latest.barcode = l.barcode
+             ORDER BY latest.timestamp DESC, latest.id DESC
+             LIMIT 1
+         )",
+        [],
+    )?;
+    notes.push(format!("Built current status for {} items from the log history", populated));
+    Ok(())
+}
+
+fn migrate_v5_loans(tx: &Transaction, notes: &mut Vec<String>) -> Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE logs ADD COLUMN station TEXT;
+
+        CREATE TABLE loans (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            barcode TEXT NOT NULL,
+            department TEXT,
+            out_log_id INTEGER NOT NULL,
+            out_time INTEGER NOT NULL,
+            out_station TEXT,
+            in_log_id INTEGER,
+            in_time INTEGER,
+            in_station TEXT
+        );
+
+        -- At most one open loan per item
+        CREATE UNIQUE INDEX idx_loans_open ON loans (barcode) WHERE in_time IS NULL;
+        CREATE INDEX idx_loans_barcode_out_time ON loans (barcode, out_time);
+        CREATE INDEX idx_loans_out_time ON loans (out_time);",
+    )?;
+
+    // Pair up the existing history the same way new scans are paired: a
+    // check-out opens a loan unless one is already open, a check-in closes it
+    let logs: Vec<(i64, i64, String, String, Option<String>)> = {
+        let mut stmt = tx.prepare(
+            "SELECT id, timestamp, barcode, action, department FROM logs ORDER BY barcode, timestamp, id",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+        })?;
+        rows.collect::<Result<_>>()?
+    };
+
+    let mut open_loan: Option<(String, i64)> = None;
+    let mut opened = 0;
+    let mut closed = 0;
+    let mut unmatched_check_ins = 0;
+    for (log_id, timestamp, barcode, action, department) in logs {
+        if open_loan.as_ref().is_some_and(|(open_barcode, _)| *open_barcode != barcode) {
+            open_loan = None;
+        }
+
+        match (action.as_str(), &open_loan) {
+            ("check-out", None) => {
+                tx.execute(
+                    "INSERT INTO loans (barcode, department, out_log_id, out_time) VALUES (?1, ?2, ?3, ?4)",
+                    params![barcode, department, log_id, timestamp],
+                )?;
+                open_loan = Some((barcode, tx.last_insert_rowid()));
+                opened += 1;
+            }
+            ("check-in", Some((_, loan_id))) => {
+                tx.execute(
+                    "UPDATE loans SET in_log_id = ?2, in_time = ?3 WHERE id = ?1",
+                    params![loan_id, log_id, timestamp],
+                )?;
+                open_loan = None;
+                closed += 1;
+            }
+            ("check-in", None) => unmatched_check_ins += 1,
+            _ => {} // Repeated check-out: the item never came back, keep the first loan open
+        }
+    }
+
+    if opened > 0 {
+        notes.push(format!(
+            "Paired {} loans from the log history ({} still open)",
+            opened,
+            opened - closed
+        ));
+    }
+    if unmatched_check_ins > 0 {
+        notes.push(format!(
+            "{} check-ins had no earlier check-out and were not turned into loans",
+            unmatched_check_ins
+        ));
+    }
+    Ok(())
+}
+
+fn migrate_v6_archive_retention(tx: &Transaction, _notes: &mut Vec<String>) -> Result<()> {
+    // Replaces the 30 days that used to be hardcoded in the startup cleanup
+    tx.execute(
+        "INSERT OR IGNORE INTO settings (key, value) VALUES ('archive_retention_days', '30')",
+        [],
+    )?;
+    Ok(())
+}
+
+fn migrate_v7_audit_log(tx: &Transaction, _notes: &mut Vec<String>) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE audit_log (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp INTEGER NOT NULL,
+            station TEXT,
+            operator TEXT,
+            action TEXT NOT NULL,
+            entity TEXT NOT NULL,
+            entity_key TEXT,
+            before_value TEXT,
+            after_value TEXT
+        );
+
+        CREATE INDEX idx_audit_log_timestamp ON audit_log (timestamp);
+        CREATE INDEX idx_audit_log_entity ON audit_log (entity, entity_key);",
+    )?;
+    Ok(())
+}
+
+fn migrate_v8_void_scans(tx: &Transaction, _notes: &mut Vec<String>) -> Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE logs ADD COLUMN voided_at INTEGER;
+        ALTER TABLE logs ADD COLUMN voided_by TEXT;
+        ALTER TABLE logs ADD COLUMN void_reason TEXT;",
+    )?;
+    Ok(())
+}
+
+/// NULL means "use the next rule down": item, then department, then the
+/// global `alert_threshold_hours` setting.
+fn migrate_v9_overdue_thresholds(tx: &Transaction, _notes: &mut Vec<String>) -> Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE department_mappings ADD COLUMN alert_threshold_hours INTEGER;
+        ALTER TABLE items ADD COLUMN alert_threshold_hours INTEGER;",
+    )?;
+    Ok(())
+}
+
+/// One alert per loan. It escalates from warning to critical in place and is
+/// resolved when the loan closes, so staff aren't told about the same loan twice.
+fn migrate_v10_alerts(tx: &Transaction, _notes: &mut Vec<String>) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE alerts (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            loan_id INTEGER NOT NULL,
+            barcode TEXT NOT NULL,
+            department TEXT,
+            level TEXT CHECK(level IN ('warning', 'critical')) NOT NULL,
+            state TEXT CHECK(state IN ('new', 'acknowledged', 'snoozed', 'resolved')) NOT NULL,
+            threshold_hours INTEGER NOT NULL,
+            rule TEXT,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL,
+            escalated_at INTEGER,
+            acknowledged_at INTEGER,
+            acknowledged_by TEXT,
+            snoozed_until INTEGER,
+            snoozed_by TEXT,
+            resolved_at INTEGER
+        );
+        CREATE UNIQUE INDEX idx_alerts_loan ON alerts (loan_id);
+        CREATE INDEX idx_alerts_state ON alerts (state);
+        CREATE INDEX idx_alerts_barcode ON alerts (barcode);",
+    )?;
+    Ok(())
+}
+
+/// Both tables start empty: with no opening hours every hour counts, as before.
+fn migrate_v11_working_calendar(tx: &Transaction, _notes: &mut Vec<String>) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE business_hours (
+            weekday INTEGER PRIMARY KEY CHECK(weekday BETWEEN 0 AND 6),
+            opens_minute INTEGER NOT NULL,
+            closes_minute INTEGER NOT NULL CHECK(closes_minute > opens_minute AND closes_minute <= 1440)
+        );
+
+        CREATE TABLE holidays (
+            date TEXT PRIMARY KEY,
+            name TEXT
+        );",
+    )?;
+    Ok(())
+}
+
+fn migrate_v12_alert_check_interval(tx: &Transaction, _notes: &mut Vec<String>) -> Result<()> {
+    // Replaces the hour that used to be hardcoded in the alert thread
+    tx.execute(
+        "INSERT OR IGNORE INTO settings (key, value) VALUES ('alert_check_interval_minutes', '60')",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Email stays off until SMTP is configured. `emailed_level` records what was
+/// last mailed for an alert, so it is mailed again only when it escalates.
+/// A station claims alerts before mailing them so two stations never send the
+/// same digest; the claim lapses if that station dies halfway. The SMTP
+/// password is kept in each station's local config, not here.
+fn migrate_v13_email_notifications(tx: &Transaction, _notes: &mut Vec<String>) -> Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE department_mappings ADD COLUMN alert_emails TEXT;
+        ALTER TABLE alerts ADD COLUMN emailed_at INTEGER;
+        ALTER TABLE alerts ADD COLUMN emailed_level TEXT;
+        ALTER TABLE alerts ADD COLUMN email_claimed_by TEXT;
+        ALTER TABLE alerts ADD COLUMN email_claimed_until INTEGER;
+
+        INSERT OR IGNORE INTO settings (key, value) VALUES
+            ('smtp_enabled', 'false'),
+            ('smtp_host', ''),
+            ('smtp_port', '587'),
+            ('smtp_security', 'starttls'),
+            ('smtp_username', ''),
+            ('smtp_from', ''),
+            ('smtp_default_recipients', '');",
+    )?;
+    Ok(())
+}
+
+/// One outbox row per event and target, kept until delivered or given up on,
+/// so nothing queued is lost when the app closes. The station sending a row
+/// claims it first so the other stations leave it alone.
+fn migrate_v14_webhooks(tx: &Transaction, _notes: &mut Vec<String>) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE webhook_targets (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            url TEXT NOT NULL,
+            secret TEXT,
+            events TEXT NOT NULL DEFAULT '*',
+            enabled INTEGER NOT NULL DEFAULT 1,
+            created_at INTEGER NOT NULL
+        );
+
+        CREATE TABLE webhook_outbox (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            target_id INTEGER NOT NULL REFERENCES webhook_targets(id) ON DELETE CASCADE,
+            event TEXT NOT NULL,
+            payload TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            next_attempt_at INTEGER NOT NULL,
+            last_attempt_at INTEGER,
+            last_status INTEGER,
+            last_error TEXT,
+            delivered_at INTEGER,
+            failed_at INTEGER,
+            claimed_by TEXT,
+            claimed_until INTEGER
+        );
+        CREATE INDEX idx_webhook_outbox_due ON webhook_outbox (delivered_at, failed_at, next_attempt_at);
+        CREATE INDEX idx_webhook_outbox_target ON webhook_outbox (target_id);",
+    )?;
+    Ok(())
+}
+
+fn migrate_v15_desktop_notifications(tx: &Transaction, _notes: &mut Vec<String>) -> Result<()> {
+    tx.execute(
+        "INSERT OR IGNORE INTO settings (key, value) VALUES ('desktop_notifications', 'true')",
+        [],
+    )?;
+    Ok(())
+}
+
+fn migrate_v16_normalize_scan_barcodes(tx: &Transaction, notes: &mut Vec<String>) -> Result<()> {
+    // Scans used to be stored as typed, and keyboard wedges type lower case, so
+    // they missed the catalogue and per-item lookups. Normalise in Rust as in v2.
+    let stored: Vec<String> = {
+        let mut stmt = tx.prepare("SELECT barcode FROM logs UNION SELECT barcode FROM loans")?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        rows.collect::<Result<_>>()?
+    };
+    let mut items: Vec<String> = stored
+        .iter()
+        .filter(|barcode| barcode.trim().to_uppercase() != **barcode)
+        .map(|barcode| barcode.trim().to_uppercase())
+        .collect();
+    items.sort();
+    items.dedup();
+
+    let mut renamed_logs = 0;
+    for item in &items {
+        let spellings: Vec<&String> = stored
+            .iter()
+            .filter(|barcode| *barcode != item && barcode.trim().to_uppercase() == *item)
+            .collect();
+        for spelling in &spellings {
+            renamed_logs += tx.execute("UPDATE logs SET barcode = ?2 WHERE barcode = ?1", params![spelling, item])?;
+            tx.execute("UPDATE alerts SET barcode = ?2 WHERE barcode = ?1", params![spelling, item])?;
+        }
+
+        // The spellings may each have loans of their own; pair the merged history
+        // again, keeping the loans (and their alerts) whose check-out still opens one
+        let barcodes: Vec<&str> = spellings.iter().map(|spelling| spelling.as_str()).chain([item.as_str()]).collect();
+        let removed = repair_loans(tx, item, &barcodes)?;
+        tx.execute(
+            "DELETE FROM alerts WHERE loan_id IN (SELECT value FROM json_each(?1))",
+            params![serde_json::json!(removed).to_string()],
+        )?;
+
+        for spelling in spellings.iter().map(|spelling| spelling.as_str()).chain([item.as_str()]) {
+            tx.execute("DELETE FROM item_status WHERE barcode = ?1", params![spelling])?;
+        }
+        tx.execute(
+            "INSERT INTO item_status (barcode, state, department, since, log_id)
+             SELECT barcode, action, department, timestamp, id
+             FROM logs
+             WHERE barcode = ?1 AND voided_at IS NULL
+             ORDER BY timestamp DESC, id DESC
+             LIMIT 1",
+            params![item],
+        )?;
+    }
+
+    if renamed_logs > 0 {
+        notes.push(format!(
+            "Upper-cased {} scans of {} items and paired their loans again",
+            renamed_logs,
+            items.len()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A database as the last build before migrations left it: text timestamps
+    /// with the station's offset, mixed-case catalogue keys and item_names
+    fn baseline_fixture() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE logs (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp TEXT NOT NULL,
+                barcode TEXT NOT NULL,
+                action TEXT CHECK(action IN ('check-in', 'check-out')) NOT NULL,
+                department TEXT
+            );
+            CREATE TABLE settings (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+            CREATE TABLE department_mappings (prefix TEXT PRIMARY KEY, department TEXT NOT NULL);
+            CREATE TABLE items (barcode TEXT PRIMARY KEY, department TEXT, description TEXT);
+            CREATE TABLE item_names (barcode TEXT PRIMARY KEY, name TEXT NOT NULL);
+
+            INSERT INTO department_mappings VALUES ('ORTX', 'Ortopedi');
+            INSERT INTO items VALUES ('ortx1', 'Ortopedi', NULL), ('ORTX2', NULL, 'Sax');
+            INSERT INTO item_names VALUES ('ortx1', 'Tång'), ('ORTX2', 'Gammal sax'), ('käkx9', 'Spegel');
+
+            INSERT INTO logs (id, timestamp, barcode, action, department) VALUES
+                (1, '2024-03-01T08:00:00+01:00', 'ORTX1', 'check-out', 'Ortopedi'),
+                (2, '2024-03-01T10:30:00+01:00', 'ORTX1', 'check-in', 'Ortopedi'),
+                (3, 'igår', 'ORTX2', 'check-out', 'Ortopedi'),
+                (4, '2024-03-02 09:15:00', 'ORTX3', 'check-out', 'Ortopedi'),
+                (5, '2024-03-02T12:00:00+01:00', 'ORTX3', 'check-out', 'Ortopedi'),
+                (6, '2024-03-03T07:00:00Z', 'ORTX4', 'check-in', 'Ortopedi'),
+                (7, '2024-03-03T08:00:00Z', 'ORTX4', 'check-out', 'Ortopedi');
+            DELETE FROM logs WHERE id = 7;",
+        )
+        .unwrap();
+        conn
+    }
+
+    /// barcode, out_log_id, out_time, in_log_id, in_time
+    type LoanRow = (String, i64, i64, Option<i64>, Option<i64>);
+
+    fn millis(rfc3339: &str) -> i64 {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap().timestamp_millis()
+    }
+
+    #[test]
+    fn migrates_baseline_fixture_to_latest() {
+        let mut conn = baseline_fixture();
+        let report = run_migrations(&mut conn).unwrap();
+        assert_eq!(report.previous_version, 0);
+        assert_eq!(report.current_version, latest_version());
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        assert_eq!(report.applied.len(), MIGRATIONS.len());
+
+        let again = run_migrations(&mut conn).unwrap();
+        assert!(again.is_up_to_date());
+    }
+
+    #[test]
+    fn merges_item_names_into_catalogue() {
+        let mut conn = baseline_fixture();
+        run_migrations(&mut conn).unwrap();
+
+        let items: Vec<(String, Option<String>, Option<String>)> = conn
+            .prepare("SELECT barcode, department, description FROM items ORDER BY barcode")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            items,
+            vec![
+                ("KÄKX9".to_string(), None, Some("Spegel".to_string())),
+                ("ORTX1".to_string(), Some("Ortopedi".to_string()), Some("Tång".to_string())),
+                // The catalogue description wins over the legacy name
+                ("ORTX2".to_string(), None, Some("Sax".to_string())),
+            ]
+        );
+
+        let item_names: i64 = conn
+            .query_row("SELECT COUNT(*) FROM sqlite_master WHERE name = 'item_names'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(item_names, 0);
+    }
+
+    #[test]
+    fn merge_without_item_names_table() {
+        let mut conn = baseline_fixture();
+        conn.execute("DROP TABLE item_names", []).unwrap();
+        run_migrations(&mut conn).unwrap();
+
+        let description: Option<String> = conn
+            .query_row("SELECT description FROM items WHERE barcode = 'ORTX1'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(description, None);
+    }
+
+    #[test]
+    fn converts_timestamps_to_utc_millis() {
+        let mut conn = baseline_fixture();
+        let report = run_migrations(&mut conn).unwrap();
+
+        let logs: Vec<(i64, i64)> = conn
+            .prepare("SELECT id, timestamp FROM logs ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        let naive = NaiveDateTime::parse_from_str("2024-03-02 09:15:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let local = Local.from_local_datetime(&naive).earliest().unwrap().timestamp_millis();
+        assert_eq!(
+            logs,
+            vec![
+                (1, millis("2024-03-01T07:00:00Z")),
+                (2, millis("2024-03-01T09:30:00Z")),
+                (4, local),
+                (5, millis("2024-03-02T11:00:00Z")),
+                (6, millis("2024-03-03T07:00:00Z")),
+            ]
+        );
+
+        let unconverted: (i64, String, String) = conn
+            .query_row("SELECT id, raw_timestamp, barcode FROM logs_unconverted", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .unwrap();
+        assert_eq!(unconverted, (3, "igår".to_string(), "ORTX2".to_string()));
+        assert!(report
+            .applied
+            .iter()
+            .flat_map(|m| &m.notes)
+            .any(|note| note.contains("moved to logs_unconverted (ids 3)")));
+
+        // Ids of deleted rows are not handed out again after the rebuild
+        conn.execute(
+            "INSERT INTO logs (timestamp, barcode, action) VALUES (0, 'ORTX5', 'check-out')",
+            [],
+        )
+        .unwrap();
+        assert_eq!(conn.last_insert_rowid(), 8);
+
+        let status: Vec<(String, String, i64, i64)> = conn
+            .prepare("SELECT barcode, state, since, log_id FROM item_status ORDER BY barcode")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            status,
+            vec![
+                ("ORTX1".to_string(), "check-in".to_string(), millis("2024-03-01T09:30:00Z"), 2),
+                ("ORTX3".to_string(), "check-out".to_string(), millis("2024-03-02T11:00:00Z"), 5),
+                ("ORTX4".to_string(), "check-in".to_string(), millis("2024-03-03T07:00:00Z"), 6),
+            ]
+        );
+    }
+
+    #[test]
+    fn backfills_loans_from_history() {
+        let mut conn = baseline_fixture();
+        let report = run_migrations(&mut conn).unwrap();
+
+        let loans: Vec<LoanRow> = conn
+            .prepare("SELECT barcode, out_log_id, out_time, in_log_id, in_time FROM loans ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(loans.len(), 2);
+        assert_eq!(
+            loans[0],
+            (
+                "ORTX1".to_string(),
+                1,
+                millis("2024-03-01T07:00:00Z"),
+                Some(2),
+                Some(millis("2024-03-01T09:30:00Z"))
+            )
+        );
+        // A repeated check-out keeps the first loan open; the lone check-in opens nothing
+        assert_eq!((loans[1].0.as_str(), loans[1].1, loans[1].3), ("ORTX3", 4, None));
+
+        let notes: Vec<&String> = report.applied.iter().flat_map(|m| &m.notes).collect();
+        assert!(notes.iter().any(|note| note.contains("Paired 2 loans from the log history (1 still open)")));
+        assert!(notes.iter().any(|note| note.starts_with("1 check-ins had no earlier check-out")));
+    }
+
+    #[test]
+    fn upper_cases_scanned_barcodes_and_merges_their_loans() {
+        let mut conn = baseline_fixture();
+        // The same item scanned in two spellings, each with a loan of its own
+        conn.execute_batch(
+            "INSERT INTO logs (id, timestamp, barcode, action, department) VALUES
+                (20, '2024-03-04T08:00:00Z', 'ortx5', 'check-out', 'Ortopedi'),
+                (21, '2024-03-04T09:00:00Z', 'ORTX5', 'check-out', 'Ortopedi'),
+                (22, '2024-03-04T10:00:00Z', 'ortx5 ', 'check-in', 'Ortopedi');",
+        )
+        .unwrap();
+        let report = run_migrations(&mut conn).unwrap();
+
+        let barcodes: Vec<String> = conn
+            .prepare("SELECT barcode FROM logs WHERE id >= 20 ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(barcodes, vec!["ORTX5", "ORTX5", "ORTX5"]);
+
+        // The second check-out is a repeat once the spellings agree
+        let loans: Vec<(String, i64, Option<i64>)> = conn
+            .prepare("SELECT barcode, out_log_id, in_log_id FROM loans WHERE out_log_id >= 20")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(loans, vec![("ORTX5".to_string(), 20, Some(22))]);
+
+        let status: Vec<(String, String, i64)> = conn
+            .prepare("SELECT barcode, state, log_id FROM item_status WHERE barcode LIKE 'ortx5%'")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(status, vec![("ORTX5".to_string(), "check-in".to_string(), 22)]);
+
+        assert!(report
+            .applied
+            .iter()
+            .flat_map(|m| &m.notes)
+            .any(|note| note == "Upper-cased 2 scans of 1 items and paired their loans again"));
+    }
+}
@@ -0,0 +1,459 @@
+//! Email digests of overdue items, so someone hears about them even when the
+//! tray window is closed or nobody is at the station.
+//!
+//! Each department mapping can list its own recipients; items under no mapping
+//! with recipients go to `smtp_default_recipients`. An alert is mailed once
+//! while it is new and once more if it escalates to critical, so the hourly
+//! check doesn't resend the same digest. Only stations with the SMTP password
+//! in their local config send, and they claim alerts first so two of them
+//! checking at once don't both mail the same digest.
+use crate::alert::{Alert, OverdueItem};
+use crate::database::{normalize_barcode, station_name, Database, DepartmentMapping};
+use crate::db_service::DbService;
+use crate::station_config::StationConfig;
+use chrono::Local;
+use lettre::message::{header::ContentType, Mailbox};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use std::collections::BTreeMap;
+
+pub const DEFAULT_SUBJECT_TEMPLATE: &str = "Harrys Lilla Lager: {count} försenade artiklar ({department})";
+pub const DEFAULT_DIGEST_TEMPLATE: &str = "Följande artiklar från {department} har varit utcheckade längre än tillåtet:
+
+{items}
+
+Skickat från {station} {now}.";
+pub const DEFAULT_ITEM_TEMPLATE: &str =
+    "- {barcode} {description}: utcheckad {checked_out}, {hours} h ute, gräns {threshold} h ({level})";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtpSecurity {
+    /// Plain connection, for a local relay or test sink only
+    None,
+    StartTls,
+    /// Implicit TLS, usually port 465
+    Tls,
+}
+
+/// How long a station may take to mail the alerts it claimed before another station may
+pub const EMAIL_CLAIM_LEASE_MINUTES: i64 = 10;
+
+/// SMTP and template settings, read from the settings table
+#[derive(Debug, Clone)]
+pub struct SmtpSettings {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    pub username: String,
+    /// From this station's local config, never the shared settings
+    pub password: String,
+    pub from: String,
+    pub default_recipients: String,
+    pub subject_template: String,
+    pub digest_template: String,
+    pub item_template: String,
+}
+
+impl SmtpSettings {
+    pub fn load(db: &Database) -> rusqlite::Result<Self> {
+        let setting = |key: &str| -> rusqlite::Result<String> { Ok(db.get_setting(key)?.unwrap_or_default()) };
+        let template = |key: &str, default: &str| -> rusqlite::Result<String> {
+            let value = setting(key)?;
+            Ok(if value.trim().is_empty() { default.to_string() } else { value })
+        };
+
+        Ok(SmtpSettings {
+            enabled: setting("smtp_enabled")? == "true",
+            host: setting("smtp_host")?,
+            port: setting("smtp_port")?.parse().unwrap_or(587),
+            security: match setting("smtp_security")?.as_str() {
+                "none" => SmtpSecurity::None,
+                "tls" => SmtpSecurity::Tls,
+                _ => SmtpSecurity::StartTls,
+            },
+            username: setting("smtp_username")?,
+            password: String::new(),
+            from: setting("smtp_from")?,
+            default_recipients: setting("smtp_default_recipients")?,
+            subject_template: template("email_subject_template", DEFAULT_SUBJECT_TEMPLATE)?,
+            digest_template: template("email_digest_template", DEFAULT_DIGEST_TEMPLATE)?,
+            item_template: template("email_item_template", DEFAULT_ITEM_TEMPLATE)?,
+        })
+    }
+}
+
+pub struct EmailNotifier {
+    settings: SmtpSettings,
+}
+
+/// Replaces each `{key}` in `template` with its value
+pub fn render(template: &str, values: &[(&str, String)]) -> String {
+    values
+        .iter()
+        .fold(template.to_string(), |text, (key, value)| text.replace(&format!("{{{}}}", key), value))
+}
+
+/// Parses a comma- or semicolon-separated address list
+pub fn parse_recipients(text: &str) -> Result<Vec<Mailbox>, String> {
+    text.split([',', ';'])
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+        .map(|address| address.parse().map_err(|e| format!("Invalid email address '{}': {}", address, e)))
+        .collect()
+}
+
+impl EmailNotifier {
+    pub fn new(db: &DbService) -> Result<Self, rusqlite::Error> {
+        let mut settings = db.with(SmtpSettings::load)?;
+        settings.password = StationConfig::open()?.get("smtp_password")?.unwrap_or_default();
+        Ok(EmailNotifier { settings })
+    }
+
+    /// Also false at stations that lack the password for an authenticated server
+    pub fn is_enabled(&self) -> bool {
+        let settings = &self.settings;
+        settings.enabled
+            && !settings.host.trim().is_empty()
+            && (settings.username.is_empty() || !settings.password.is_empty())
+    }
+
+    fn transport(&self) -> Result<SmtpTransport, Box<dyn std::error::Error>> {
+        let settings = &self.settings;
+        let builder = match settings.security {
+            SmtpSecurity::None => SmtpTransport::builder_dangerous(&settings.host),
+            SmtpSecurity::StartTls => SmtpTransport::starttls_relay(&settings.host)?,
+            SmtpSecurity::Tls => SmtpTransport::relay(&settings.host)?,
+        };
+        let mut builder = builder
+            .port(settings.port)
+            .timeout(Some(std::time::Duration::from_secs(30)));
+        if !settings.username.is_empty() {
+            builder = builder.credentials(Credentials::new(settings.username.clone(), settings.password.clone()));
+        }
+        Ok(builder.build())
+    }
+
+    fn send(&self, transport: &SmtpTransport, to: &[Mailbox], subject: &str, body: String) -> Result<(), Box<dyn std::error::Error>> {
+        let mut message = Message::builder()
+            .from(self.settings.from.parse()?)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN);
+        for recipient in to {
+            message = message.to(recipient.clone());
+        }
+        transport.send(&message.body(body)?)?;
+        Ok(())
+    }
+
+    pub fn send_test(&self, to: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let recipients = parse_recipients(to)?;
+        let body = format!("Testmeddelande från Harrys Lilla Lager på {}.", station_name());
+        self.send(&self.transport()?, &recipients, "Harrys Lilla Lager: test", body)
+    }
+
+    /// Mails one digest per department for alerts not mailed at their current
+    /// level yet, then marks them. Returns how many emails were sent.
+    pub fn send_digests(&self, db: &DbService, overdue: &[OverdueItem]) -> Result<usize, Box<dyn std::error::Error>> {
+        if !self.is_enabled() {
+            return Ok(0);
+        }
+        let alerts = db.with(|db| db.claim_alerts_to_email(chrono::Duration::minutes(EMAIL_CLAIM_LEASE_MINUTES)))?;
+        if alerts.is_empty() {
+            return Ok(0);
+        }
+
+        let mut mailed = Vec::new();
+        let result = self.mail_claimed(db, &alerts, overdue, &mut mailed);
+
+        // Whatever wasn't mailed is up for grabs again at the next check
+        let unsent: Vec<Alert> = alerts.into_iter().filter(|alert| !mailed.contains(&alert.id)).collect();
+        if let Err(e) = db.with(|db| db.release_email_claims(&unsent)) {
+            eprintln!("Failed to release unsent email alerts: {}", e);
+        }
+        result
+    }
+
+    /// Groups the claimed alerts into digests and mails them, collecting the ids of those mailed
+    fn mail_claimed(&self, db: &DbService, alerts: &[Alert], overdue: &[OverdueItem], mailed: &mut Vec<i64>) -> Result<usize, Box<dyn std::error::Error>> {
+        let mut mappings: Vec<DepartmentMapping> = db
+            .with(|db| db.get_department_mappings())?
+            .into_iter()
+            .filter(|mapping| mapping.alert_emails.as_deref().is_some_and(|emails| !emails.trim().is_empty()))
+            .collect();
+        mappings.sort_by_key(|mapping| std::cmp::Reverse(mapping.prefix.chars().count()));
+
+        // (department, recipients) -> items, departments in name order
+        let mut digests: BTreeMap<(String, String), Vec<(&Alert, &OverdueItem)>> = BTreeMap::new();
+        for alert in alerts {
+            let Some(item) = overdue.iter().find(|item| item.loan_id == alert.loan_id) else {
+                continue;
+            };
+            let barcode = normalize_barcode(&alert.barcode);
+            let mapping = mappings
+                .iter()
+                .find(|mapping| barcode.starts_with(&normalize_barcode(&mapping.prefix)));
+            let (department, recipients) = match mapping {
+                Some(mapping) => (mapping.department.clone(), mapping.alert_emails.clone().unwrap_or_default()),
+                None => (
+                    alert.department.clone().unwrap_or_else(|| "Okänd enhet".to_string()),
+                    self.settings.default_recipients.clone(),
+                ),
+            };
+            if recipients.trim().is_empty() {
+                continue;
+            }
+            digests.entry((department, recipients)).or_default().push((alert, item));
+        }
+        if digests.is_empty() {
+            return Ok(0);
+        }
+
+        let transport = self.transport()?;
+        let mut sent = 0;
+        for ((department, recipients), items) in digests {
+            let items: Vec<(&Alert, &OverdueItem, String)> = items
+                .into_iter()
+                .map(|(alert, item)| {
+                    let description = db.with(|db| db.get_item_name(&item.barcode)).ok().flatten().unwrap_or_default();
+                    (alert, item, description)
+                })
+                .collect();
+            let (subject, body) = self.render_digest(&department, &items);
+
+            // A bad address list for one department shouldn't hold up the others
+            let result = parse_recipients(&recipients)
+                .map_err(|e| e.into())
+                .and_then(|to| self.send(&transport, &to, &subject, body));
+            match result {
+                Ok(()) => {
+                    let digest: Vec<Alert> = items.iter().map(|(alert, _, _)| (*alert).clone()).collect();
+                    db.with(|db| db.mark_alerts_emailed(&digest))?;
+                    mailed.extend(digest.iter().map(|alert| alert.id));
+                    sent += 1;
+                }
+                Err(e) => eprintln!("Failed to email overdue digest for {}: {}", department, e),
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Subject and body of one department's digest; `items` carry the item description
+    fn render_digest(&self, department: &str, items: &[(&Alert, &OverdueItem, String)]) -> (String, String) {
+        let lines: Vec<String> = items
+            .iter()
+            .map(|(alert, item, description)| {
+                render(
+                    &self.settings.item_template,
+                    &[
+                        ("barcode", item.barcode.clone()),
+                        ("description", description.clone()),
+                        ("department", item.department.clone().unwrap_or_default()),
+                        ("checked_out", item.checked_out_time.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string()),
+                        ("station", item.checked_out_station.clone().unwrap_or_default()),
+                        ("hours", item.hours_overdue.to_string()),
+                        ("raw_hours", item.raw_hours.to_string()),
+                        ("threshold", item.threshold_hours.to_string()),
+                        ("level", alert.level.as_str().to_string()),
+                    ],
+                )
+            })
+            .collect();
+        let values = [
+            ("count", items.len().to_string()),
+            ("department", department.to_string()),
+            ("items", lines.join("\n")),
+            ("station", station_name().to_string()),
+            ("now", Local::now().format("%Y-%m-%d %H:%M").to_string()),
+        ];
+        (
+            render(&self.settings.subject_template, &values),
+            render(&self.settings.digest_template, &values),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alert::{AlertLevel, AlertManager};
+    use crate::database::tests::test_db;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    fn settings(port: u16) -> SmtpSettings {
+        SmtpSettings {
+            enabled: true,
+            host: "127.0.0.1".to_string(),
+            port,
+            security: SmtpSecurity::None,
+            username: String::new(),
+            password: String::new(),
+            from: "lager@example.com".to_string(),
+            default_recipients: "central@example.com".to_string(),
+            subject_template: DEFAULT_SUBJECT_TEMPLATE.to_string(),
+            digest_template: DEFAULT_DIGEST_TEMPLATE.to_string(),
+            item_template: DEFAULT_ITEM_TEMPLATE.to_string(),
+        }
+    }
+
+    /// Accepts `sessions` SMTP sessions on a local port and returns the DATA of each message
+    fn smtp_sink(sessions: usize) -> (u16, std::thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = std::thread::spawn(move || {
+            let mut messages = Vec::new();
+            for _ in 0..sessions {
+                let (stream, _) = listener.accept().unwrap();
+                messages.extend(smtp_session(stream));
+            }
+            messages
+        });
+        (port, handle)
+    }
+
+    fn smtp_session(stream: TcpStream) -> Vec<String> {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        let mut messages = Vec::new();
+        writer.write_all(b"220 localhost ESMTP\r\n").unwrap();
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap_or(0) > 0 {
+            let command = line.trim_end().to_uppercase();
+            line.clear();
+            if command.starts_with("EHLO") || command.starts_with("HELO") {
+                writer.write_all(b"250 localhost\r\n").unwrap();
+            } else if command == "DATA" {
+                writer.write_all(b"354 go ahead\r\n").unwrap();
+                let mut data = String::new();
+                while reader.read_line(&mut line).unwrap() > 0 && line != ".\r\n" {
+                    data.push_str(&line);
+                    line.clear();
+                }
+                line.clear();
+                messages.push(data);
+                writer.write_all(b"250 queued\r\n").unwrap();
+            } else if command == "QUIT" {
+                writer.write_all(b"221 bye\r\n").unwrap();
+                break;
+            } else {
+                writer.write_all(b"250 ok\r\n").unwrap();
+            }
+        }
+        messages
+    }
+
+    /// Two overdue loans, one under ORTX (mapped to its own recipients) and one
+    /// under a prefix nobody is mailed for, which goes to the default recipients
+    fn overdue_db(name: &str) -> (DbService, Vec<OverdueItem>) {
+        let db = test_db(name);
+        db.set_setting("alert_threshold_hours", "24", None).unwrap();
+        db.set_department_mapping("ortx", "Ortopedi", None).unwrap();
+        db.set_department_alert_emails("ortx", Some("ort@example.com; chef@example.com"), None).unwrap();
+        let out = Local::now() - chrono::Duration::hours(30);
+        db.log_scan_at("ORTX1", "check-out", Some("Ortopedi"), out).unwrap();
+        db.log_scan_at("NEURX1", "check-out", Some("Neurologi"), out).unwrap();
+
+        let db = DbService::from_database(db);
+        let overdue = AlertManager::new(db.clone()).unwrap().check_overdue_items().unwrap();
+        assert_eq!(overdue.len(), 2);
+        db.with(|db| db.sync_alerts(&overdue)).unwrap();
+        (db, overdue)
+    }
+
+    #[test]
+    fn parses_recipient_lists() {
+        let recipients = parse_recipients(" a@example.com, Bo <b@example.com>;;c@example.com ").unwrap();
+        let addresses: Vec<String> = recipients.iter().map(|mailbox| mailbox.email.to_string()).collect();
+        assert_eq!(addresses, ["a@example.com", "b@example.com", "c@example.com"]);
+        assert_eq!(recipients[1].name.as_deref(), Some("Bo"));
+        assert!(parse_recipients("").unwrap().is_empty());
+
+        let error = parse_recipients("a@example.com, not an address").unwrap_err();
+        assert!(error.contains("'not an address'"), "{}", error);
+    }
+
+    #[test]
+    fn renders_a_digest_from_the_templates() {
+        let (db, overdue) = overdue_db("notifier-render");
+        let alerts = db.with(|db| db.get_alerts(false, None)).unwrap();
+        let notifier = EmailNotifier {
+            settings: SmtpSettings {
+                subject_template: "{count} från {department}".to_string(),
+                digest_template: "{items}\n-- {station}".to_string(),
+                item_template: "{barcode} ({description}) {hours}/{threshold} h {level}".to_string(),
+                ..settings(25)
+            },
+        };
+        let items: Vec<(&Alert, &OverdueItem, String)> = overdue
+            .iter()
+            .map(|item| (alerts.iter().find(|alert| alert.loan_id == item.loan_id).unwrap(), item, "Sax".to_string()))
+            .collect();
+
+        let (subject, body) = notifier.render_digest("Ortopedi", &items);
+        assert_eq!(subject, "2 från Ortopedi");
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines.contains(&"ORTX1 (Sax) 30/24 h warning"), "{}", body);
+        assert!(lines.contains(&"NEURX1 (Sax) 30/24 h warning"), "{}", body);
+        assert_eq!(lines[2], format!("-- {}", station_name()));
+        assert_eq!(render("{a}{b}{a}", &[("a", "1".to_string())]), "1{b}1");
+    }
+
+    #[test]
+    fn claimed_alerts_are_not_handed_out_twice() {
+        let (db, _) = overdue_db("notifier-claims");
+        let lease = chrono::Duration::minutes(EMAIL_CLAIM_LEASE_MINUTES);
+        let claimed = db.with(|db| db.claim_alerts_to_email(lease)).unwrap();
+        assert_eq!(claimed.len(), 2);
+        // Another station checking at the same time gets nothing
+        assert!(db.with(|db| db.claim_alerts_to_email(lease)).unwrap().is_empty());
+
+        db.with(|db| db.mark_alerts_emailed(&claimed[..1])).unwrap();
+        db.with(|db| db.release_email_claims(&claimed[1..])).unwrap();
+        let again = db.with(|db| db.claim_alerts_to_email(lease)).unwrap();
+        assert_eq!(again.iter().map(|alert| alert.id).collect::<Vec<_>>(), [claimed[1].id]);
+
+        // A station that died halfway holds its claim only until the lease runs out
+        db.with(|db| db.release_email_claims(&again)).unwrap();
+        assert_eq!(db.with(|db| db.claim_alerts_to_email(chrono::Duration::zero())).unwrap().len(), 1);
+        assert_eq!(db.with(|db| db.claim_alerts_to_email(lease)).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn mails_one_digest_per_department_once() {
+        let (db, overdue) = overdue_db("notifier-send");
+        let (port, sink) = smtp_sink(2);
+        let notifier = EmailNotifier { settings: settings(port) };
+
+        assert_eq!(notifier.send_digests(&db, &overdue).unwrap(), 2);
+        // Already mailed at this level, so nothing is claimed and no connection is made
+        assert_eq!(notifier.send_digests(&db, &overdue).unwrap(), 0);
+
+        let messages = sink.join().unwrap();
+        assert_eq!(messages.len(), 2);
+        let to_ortopedi = messages.iter().find(|message| message.contains("ORTX1")).unwrap();
+        assert!(to_ortopedi.contains("To: ort@example.com, chef@example.com"), "{}", to_ortopedi);
+        assert!(!to_ortopedi.contains("NEURX1"));
+        let to_default = messages.iter().find(|message| message.contains("NEURX1")).unwrap();
+        assert!(to_default.contains("To: central@example.com"), "{}", to_default);
+
+        let alerts = db.with(|db| db.get_alerts(false, None)).unwrap();
+        assert!(alerts.iter().all(|alert| alert.emailed_level == Some(AlertLevel::Warning)));
+    }
+
+    #[test]
+    fn stations_without_the_password_leave_mailing_to_others() {
+        let (db, overdue) = overdue_db("notifier-no-password");
+        let notifier = EmailNotifier {
+            settings: SmtpSettings {
+                username: "lager".to_string(),
+                ..settings(1)
+            },
+        };
+        assert!(!notifier.is_enabled());
+        assert_eq!(notifier.send_digests(&db, &overdue).unwrap(), 0);
+        let lease = chrono::Duration::minutes(EMAIL_CLAIM_LEASE_MINUTES);
+        assert_eq!(db.with(|db| db.claim_alerts_to_email(lease)).unwrap().len(), 2);
+    }
+}
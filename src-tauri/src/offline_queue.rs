@@ -0,0 +1,239 @@
+use crate::database::DepartmentMapping;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use rusqlite::{params, Connection, ErrorCode, Result};
+use std::path::{Path, PathBuf};
+
+/// A scan that couldn't be written to the shared database yet
+#[derive(Debug, Clone)]
+pub struct PendingScan {
+    pub id: i64,
+    pub barcode: String,
+    pub action: String,
+    pub department: Option<String>,
+    pub scanned_at: DateTime<Local>,
+}
+
+/// A queued scan the shared database refused for good, set aside so the
+/// scans after it can still go through
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedScan {
+    pub id: i64,
+    pub barcode: String,
+    pub action: String,
+    pub department: Option<String>,
+    pub scanned_at: DateTime<Local>,
+    pub error: String,
+    pub failed_at: DateTime<Local>,
+}
+
+/// True when the shared database can't be written right now (network drive
+/// gone, file locked beyond our retries, ...) as opposed to a real bug.
+pub fn is_unavailable(error: &rusqlite::Error) -> bool {
+    matches!(
+        error.sqlite_error_code(),
+        Some(ErrorCode::DatabaseBusy)
+            | Some(ErrorCode::DatabaseLocked)
+            | Some(ErrorCode::CannotOpen)
+            | Some(ErrorCode::SystemIoFailure)
+            | Some(ErrorCode::ReadOnly)
+            | Some(ErrorCode::FileLockingProtocolFailed)
+    )
+}
+
+/// Durable, station-local journal of scans waiting for the shared database.
+///
+/// Lives in the user's local app data folder, never on the network share, so
+/// it keeps working while the share is unreachable. Entries are replayed in
+/// the order they were scanned. It also keeps the last department mappings
+/// and checked-out items seen, so a station started while the share is down
+/// can still validate and toggle scans.
+pub struct OfflineQueue {
+    conn: Connection,
+}
+
+fn parse_time(row: &rusqlite::Row, index: usize) -> Result<DateTime<Local>> {
+    let text: String = row.get(index)?;
+    DateTime::parse_from_rfc3339(&text)
+        .map(|time| time.with_timezone(&Local))
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e)))
+}
+
+impl OfflineQueue {
+    pub fn open() -> Result<Self> {
+        Self::open_at(&Self::get_queue_path())
+    }
+
+    pub fn open_at(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "synchronous", "FULL")?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS pending_scans (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                barcode TEXT NOT NULL,
+                action TEXT NOT NULL,
+                department TEXT,
+                scanned_at TEXT NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS failed_scans (
+                id INTEGER PRIMARY KEY,
+                barcode TEXT NOT NULL,
+                action TEXT NOT NULL,
+                department TEXT,
+                scanned_at TEXT NOT NULL,
+                error TEXT NOT NULL,
+                failed_at TEXT NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS cached_mappings (
+                prefix TEXT PRIMARY KEY,
+                department TEXT NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS cached_checked_out (
+                barcode TEXT PRIMARY KEY
+            );",
+        )?;
+        Ok(OfflineQueue { conn })
+    }
+
+    fn get_queue_path() -> PathBuf {
+        if let Some(path) = std::env::var_os("OFFLINE_QUEUE_PATH") {
+            return PathBuf::from(path);
+        }
+
+        let mut path = dirs::data_local_dir().unwrap_or_else(|| PathBuf::from("."));
+        path.push("HarrysLillaLager");
+        std::fs::create_dir_all(&path).unwrap_or_default();
+        path.push("pending_scans.db");
+        path
+    }
+
+    pub fn enqueue(&self, barcode: &str, action: &str, department: Option<&str>, scanned_at: DateTime<Local>) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO pending_scans (barcode, action, department, scanned_at) VALUES (?1, ?2, ?3, ?4)",
+            params![barcode, action, department, scanned_at.to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    pub fn pending(&self) -> Result<Vec<PendingScan>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, barcode, action, department, scanned_at FROM pending_scans ORDER BY id",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(PendingScan {
+                id: row.get(0)?,
+                barcode: row.get(1)?,
+                action: row.get(2)?,
+                department: row.get(3)?,
+                scanned_at: parse_time(row, 4)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    pub fn count(&self) -> Result<usize> {
+        self.conn
+            .query_row("SELECT COUNT(*) FROM pending_scans", [], |row| row.get::<_, i64>(0))
+            .map(|count| count as usize)
+    }
+
+    pub fn remove(&self, id: i64) -> Result<()> {
+        self.conn.execute("DELETE FROM pending_scans WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    /// Moves a queued scan the shared database won't take to `failed_scans`
+    pub fn move_to_failed(&mut self, id: i64, error: &str) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO failed_scans (id, barcode, action, department, scanned_at, error, failed_at)
+             SELECT id, barcode, action, department, scanned_at, ?2, ?3 FROM pending_scans WHERE id = ?1",
+            params![id, error, Local::now().to_rfc3339()],
+        )?;
+        tx.execute("DELETE FROM pending_scans WHERE id = ?1", params![id])?;
+        tx.commit()
+    }
+
+    /// Scans set aside by [`OfflineQueue::move_to_failed`], oldest first
+    pub fn failed(&self) -> Result<Vec<FailedScan>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, barcode, action, department, scanned_at, error, failed_at FROM failed_scans ORDER BY id",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(FailedScan {
+                id: row.get(0)?,
+                barcode: row.get(1)?,
+                action: row.get(2)?,
+                department: row.get(3)?,
+                scanned_at: parse_time(row, 4)?,
+                error: row.get(5)?,
+                failed_at: parse_time(row, 6)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Drops a failed scan once someone has dealt with it
+    pub fn discard_failed(&self, id: i64) -> Result<bool> {
+        Ok(self.conn.execute("DELETE FROM failed_scans WHERE id = ?1", params![id])? > 0)
+    }
+
+    pub fn failed_count(&self) -> Result<usize> {
+        self.conn
+            .query_row("SELECT COUNT(*) FROM failed_scans", [], |row| row.get::<_, i64>(0))
+            .map(|count| count as usize)
+    }
+
+    /// Replaces the locally kept copy of the shared state
+    pub fn save_cache(&mut self, mappings: &[DepartmentMapping], checked_out: &[String]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM cached_mappings", [])?;
+        for mapping in mappings {
+            tx.execute(
+                "INSERT OR REPLACE INTO cached_mappings (prefix, department) VALUES (?1, ?2)",
+                params![mapping.prefix, mapping.department],
+            )?;
+        }
+        tx.execute("DELETE FROM cached_checked_out", [])?;
+        for barcode in checked_out {
+            tx.execute("INSERT OR IGNORE INTO cached_checked_out (barcode) VALUES (?1)", params![barcode])?;
+        }
+        tx.commit()
+    }
+
+    /// Mappings (prefix and department only) and checked-out barcodes as last saved
+    pub fn load_cache(&self) -> Result<(Vec<DepartmentMapping>, Vec<String>)> {
+        let mappings = {
+            let mut stmt = self.conn.prepare("SELECT prefix, department FROM cached_mappings ORDER BY prefix")?;
+            let rows = stmt.query_map([], |row| {
+                Ok(DepartmentMapping {
+                    prefix: row.get(0)?,
+                    department: row.get(1)?,
+                    alert_threshold_hours: None,
+                    alert_emails: None,
+                })
+            })?;
+            rows.collect::<Result<Vec<_>>>()?
+        };
+        let checked_out = {
+            let mut stmt = self.conn.prepare("SELECT barcode FROM cached_checked_out ORDER BY barcode")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            rows.collect::<Result<Vec<_>>>()?
+        };
+        Ok((mappings, checked_out))
+    }
+
+    /// Keeps one item's cached state in step with a scan made since the last save
+    pub fn cache_item_state(&self, barcode: &str, checked_out: bool) -> Result<()> {
+        if checked_out {
+            self.conn
+                .execute("INSERT OR IGNORE INTO cached_checked_out (barcode) VALUES (?1)", params![barcode])?;
+        } else {
+            self.conn.execute("DELETE FROM cached_checked_out WHERE barcode = ?1", params![barcode])?;
+        }
+        Ok(())
+    }
+}
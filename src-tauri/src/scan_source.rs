@@ -0,0 +1,649 @@
+//! Where barcodes come from. Every input (the keyboard wedge, a serial
+//! scanner, a TCP line socket, a replayed file) is a [`ScanSource`] that sends
+//! what it reads into one [`ScanSources`] pipeline, so all of them go through
+//! the same checks and logging. Several sources can run at once; each scan
+//! carries the id of the source it came from.
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Read timeout of blocking sources, which is also how quickly they notice a stop request
+pub const READ_TIMEOUT: Duration = Duration::from_millis(200);
+/// Pause between replayed barcodes unless told otherwise
+pub const DEFAULT_REPLAY_INTERVAL: Duration = Duration::from_millis(300);
+/// Off by default, and only reachable from this machine until the address is changed
+pub const DEFAULT_TCP_SCANNER_ADDRESS: &str = "127.0.0.1:4100";
+/// Longer frames are line noise or a wrong baud rate, not a barcode
+const MAX_FRAME_BYTES: usize = 256;
+
+/// One barcode, tagged with the source that read it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceScan {
+    pub source: String,
+    pub barcode: String,
+    pub scanned_at: DateTime<Local>,
+}
+
+/// Handed to a source when it starts; sends its barcodes into the pipeline
+#[derive(Debug, Clone)]
+pub struct ScanSink {
+    source: String,
+    sender: Sender<SourceScan>,
+}
+
+impl ScanSink {
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Returns false once the pipeline is gone
+    pub fn send(&self, barcode: String) -> bool {
+        let scan = SourceScan {
+            source: self.source.clone(),
+            barcode,
+            scanned_at: Local::now(),
+        };
+        self.sender.send(scan).is_ok()
+    }
+}
+
+#[cfg(test)]
+impl ScanSink {
+    /// A sink for driving a source on its own, and the receiving end of its scans
+    pub(crate) fn channel(source: &str) -> (Self, mpsc::Receiver<SourceScan>) {
+        let (sender, receiver) = mpsc::channel();
+        let sink = ScanSink {
+            source: source.to_string(),
+            sender,
+        };
+        (sink, receiver)
+    }
+}
+
+/// Whether a source currently has its device, socket or file
+#[derive(Debug, Clone, Default)]
+pub struct SourceHealth {
+    pub connected: bool,
+    pub last_error: Option<String>,
+}
+
+/// Health shared between a source and its reader threads
+pub type SharedHealth = Arc<Mutex<SourceHealth>>;
+
+pub fn set_health(health: &SharedHealth, connected: bool, last_error: Option<String>) {
+    if let Ok(mut health) = health.lock() {
+        health.connected = connected;
+        health.last_error = last_error;
+    }
+}
+
+pub fn read_health(health: &SharedHealth) -> SourceHealth {
+    health.lock().map(|health| health.clone()).unwrap_or_default()
+}
+
+/// An input that produces barcodes on its own thread
+pub trait ScanSource: Send {
+    /// Unique among running sources; scans are tagged with it
+    fn id(&self) -> &str;
+    /// "keyboard", "serial", "tcp" or "replay"
+    fn kind(&self) -> &'static str;
+    /// Where it reads from: the port, address or file
+    fn describe(&self) -> String;
+    /// Starts reading into `sink`. Errors that stop the source from starting at
+    /// all (a bad address, a missing file) are returned; later ones show up in
+    /// [`ScanSource::health`].
+    fn start(&mut self, sink: ScanSink) -> Result<(), String>;
+    fn stop(&mut self);
+    fn health(&self) -> SourceHealth;
+}
+
+/// What the admin panel shows for each running source
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceStatus {
+    pub id: String,
+    pub kind: String,
+    pub detail: String,
+    pub connected: bool,
+    pub last_error: Option<String>,
+    pub scan_count: u64,
+    pub last_barcode: Option<String>,
+    pub last_scan_at: Option<DateTime<Local>>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct SourceActivity {
+    scan_count: u64,
+    last_barcode: Option<String>,
+    last_scan_at: Option<DateTime<Local>>,
+}
+
+/// The running sources and the one thread that processes their scans, in
+/// the order they arrive
+pub struct ScanSources {
+    sender: Mutex<Option<Sender<SourceScan>>>,
+    sources: Mutex<Vec<Box<dyn ScanSource>>>,
+    activity: Arc<Mutex<HashMap<String, SourceActivity>>>,
+}
+
+impl ScanSources {
+    pub fn new() -> Self {
+        ScanSources {
+            sender: Mutex::new(None),
+            sources: Mutex::new(Vec::new()),
+            activity: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Starts the pipeline thread, which calls `handle` for every scan
+    pub fn start(&self, mut handle: impl FnMut(&SourceScan) + Send + 'static) {
+        let (sender, receiver) = mpsc::channel::<SourceScan>();
+        if let Ok(mut current) = self.sender.lock() {
+            *current = Some(sender);
+        }
+
+        let activity = Arc::clone(&self.activity);
+        std::thread::spawn(move || {
+            for scan in receiver {
+                if let Ok(mut activity) = activity.lock() {
+                    let entry = activity.entry(scan.source.clone()).or_default();
+                    entry.scan_count += 1;
+                    entry.last_barcode = Some(scan.barcode.clone());
+                    entry.last_scan_at = Some(scan.scanned_at);
+                }
+                handle(&scan);
+            }
+        });
+    }
+
+    /// Starts `source`, replacing (and stopping) a running source with the same id
+    pub fn add(&self, mut source: Box<dyn ScanSource>) -> Result<(), String> {
+        let sender = self
+            .sender
+            .lock()
+            .ok()
+            .and_then(|sender| sender.clone())
+            .ok_or_else(|| "The scan pipeline isn't running".to_string())?;
+        self.remove(source.id());
+
+        let sink = ScanSink {
+            source: source.id().to_string(),
+            sender,
+        };
+        source.start(sink)?;
+        println!("Scan source {} started: {}", source.id(), source.describe());
+        self.sources.lock().map_err(|e| e.to_string())?.push(source);
+        Ok(())
+    }
+
+    /// Stops and removes a source; returns whether it was running
+    pub fn remove(&self, id: &str) -> bool {
+        let removed = self.sources.lock().ok().and_then(|mut sources| {
+            let index = sources.iter().position(|source| source.id() == id)?;
+            Some(sources.remove(index))
+        });
+        match removed {
+            Some(mut source) => {
+                source.stop();
+                println!("Scan source {} stopped", id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn status(&self) -> Vec<SourceStatus> {
+        let activity = self.activity.lock().map(|activity| activity.clone()).unwrap_or_default();
+        let Ok(sources) = self.sources.lock() else {
+            return Vec::new();
+        };
+        sources
+            .iter()
+            .map(|source| {
+                let health = source.health();
+                let activity = activity.get(source.id()).cloned().unwrap_or_default();
+                SourceStatus {
+                    id: source.id().to_string(),
+                    kind: source.kind().to_string(),
+                    detail: source.describe(),
+                    connected: health.connected,
+                    last_error: health.last_error,
+                    scan_count: activity.scan_count,
+                    last_barcode: activity.last_barcode,
+                    last_scan_at: activity.last_scan_at,
+                }
+            })
+            .collect()
+    }
+}
+
+impl Default for ScanSources {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sleeps for `duration`, returning early once `stop` is set
+pub fn wait_unless_stopped(stop: &AtomicBool, duration: Duration) {
+    let until = Instant::now() + duration;
+    while !stop.load(Ordering::Relaxed) && Instant::now() < until {
+        std::thread::sleep(READ_TIMEOUT.min(until - Instant::now()));
+    }
+}
+
+/// What ends one barcode in a byte stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Terminator {
+    /// CR, LF or CRLF, whichever the scanner sends
+    Any,
+    Cr,
+    Lf,
+    Crlf,
+    Tab,
+    /// ETX (0x03), for scanners that frame with STX/ETX
+    Etx,
+}
+
+impl Terminator {
+    pub fn parse(text: &str) -> Option<Self> {
+        Some(match text.trim().to_ascii_lowercase().as_str() {
+            "" | "any" => Terminator::Any,
+            "cr" => Terminator::Cr,
+            "lf" => Terminator::Lf,
+            "crlf" => Terminator::Crlf,
+            "tab" => Terminator::Tab,
+            "etx" => Terminator::Etx,
+            _ => return None,
+        })
+    }
+}
+
+/// Splits incoming bytes into barcodes
+#[derive(Debug)]
+pub struct FrameDecoder {
+    terminator: Terminator,
+    buffer: Vec<u8>,
+    /// The frame grew past `MAX_FRAME_BYTES`; drop bytes until the next terminator
+    overflowed: bool,
+}
+
+impl FrameDecoder {
+    pub fn new(terminator: Terminator) -> Self {
+        FrameDecoder { terminator, buffer: Vec::new(), overflowed: false }
+    }
+
+    /// Feeds bytes in and returns the barcodes they completed
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        let mut barcodes = Vec::new();
+        for &byte in bytes {
+            let ends = match self.terminator {
+                Terminator::Any => byte == b'\r' || byte == b'\n',
+                Terminator::Cr => byte == b'\r',
+                // CR is dropped below, so CRLF ends on the LF
+                Terminator::Lf | Terminator::Crlf => byte == b'\n',
+                Terminator::Tab => byte == b'\t',
+                Terminator::Etx => byte == 0x03,
+            };
+            if !ends {
+                if self.buffer.len() >= MAX_FRAME_BYTES {
+                    self.overflowed = true;
+                    self.buffer.clear();
+                }
+                self.buffer.push(byte);
+                continue;
+            }
+
+            let frame = std::mem::take(&mut self.buffer);
+            if std::mem::take(&mut self.overflowed) {
+                continue;
+            }
+            // Drops STX prefixes, stray CRs and other control bytes some scanners add
+            let barcode: String = String::from_utf8_lossy(&frame)
+                .chars()
+                .filter(|c| !c.is_control())
+                .collect::<String>()
+                .trim()
+                .to_string();
+            if !barcode.is_empty() {
+                barcodes.push(barcode);
+            }
+        }
+        barcodes
+    }
+}
+
+/// Accepts TCP connections and reads one barcode per line from each, for
+/// network scanners and for feeding scans from scripts and tests
+pub struct TcpSource {
+    id: String,
+    address: String,
+    local_addr: Option<SocketAddr>,
+    stop: Arc<AtomicBool>,
+    health: SharedHealth,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TcpSource {
+    /// `address` is what to listen on, e.g. "127.0.0.1:4100"
+    pub fn new(id: &str, address: &str) -> Self {
+        TcpSource {
+            id: id.to_string(),
+            address: address.trim().to_string(),
+            local_addr: None,
+            stop: Arc::new(AtomicBool::new(false)),
+            health: SharedHealth::default(),
+            thread: None,
+        }
+    }
+
+    /// The address actually bound, once started (useful with port 0)
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+}
+
+fn read_lines(mut stream: TcpStream, sink: ScanSink, stop: Arc<AtomicBool>) {
+    let peer = stream.peer_addr().map(|peer| peer.to_string()).unwrap_or_default();
+    if let Err(e) = stream.set_nonblocking(false).and_then(|_| stream.set_read_timeout(Some(READ_TIMEOUT))) {
+        eprintln!("Scan source {}: {}", sink.source(), e);
+        return;
+    }
+    let mut decoder = FrameDecoder::new(Terminator::Any);
+    let mut buffer = [0u8; 256];
+    while !stop.load(Ordering::Relaxed) {
+        match stream.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => {
+                for barcode in decoder.push(&buffer[..read]) {
+                    if !sink.send(barcode) {
+                        return;
+                    }
+                }
+            }
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut | std::io::ErrorKind::Interrupted) => {}
+            Err(e) => {
+                eprintln!("Scan source {}: connection from {} failed: {}", sink.source(), peer, e);
+                break;
+            }
+        }
+    }
+    // A last line without a newline still counts once the client closes
+    for barcode in decoder.push(b"\n") {
+        sink.send(barcode);
+    }
+}
+
+impl ScanSource for TcpSource {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "tcp"
+    }
+
+    fn describe(&self) -> String {
+        self.local_addr.map(|addr| addr.to_string()).unwrap_or_else(|| self.address.clone())
+    }
+
+    fn start(&mut self, sink: ScanSink) -> Result<(), String> {
+        let listener = TcpListener::bind(&self.address).map_err(|e| format!("Could not listen on {}: {}", self.address, e))?;
+        listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+        self.local_addr = listener.local_addr().ok();
+        self.stop.store(false, Ordering::Relaxed);
+        set_health(&self.health, true, None);
+
+        let stop = Arc::clone(&self.stop);
+        let health = Arc::clone(&self.health);
+        self.thread = Some(std::thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let sink = sink.clone();
+                        let stop = Arc::clone(&stop);
+                        std::thread::spawn(move || read_lines(stream, sink, stop));
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => std::thread::sleep(READ_TIMEOUT),
+                    Err(e) => {
+                        set_health(&health, true, Some(e.to_string()));
+                        std::thread::sleep(READ_TIMEOUT);
+                    }
+                }
+            }
+            set_health(&health, false, None);
+        }));
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // Waiting frees the port for a restarted source
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    fn health(&self) -> SourceHealth {
+        read_health(&self.health)
+    }
+}
+
+impl Drop for TcpSource {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ReplayInput {
+    File(PathBuf),
+    Stdin,
+}
+
+/// Replays barcodes from a file or stdin, one per line. Empty lines and lines
+/// starting with `#` are skipped. The source stays listed after the input
+/// ends, as disconnected.
+pub struct ReplaySource {
+    id: String,
+    input: ReplayInput,
+    /// Pause between barcodes, so a replay looks like someone scanning
+    interval: Duration,
+    stop: Arc<AtomicBool>,
+    health: SharedHealth,
+}
+
+impl ReplaySource {
+    pub fn new(input: ReplayInput, interval: Duration) -> Self {
+        let id = match &input {
+            ReplayInput::File(path) => format!("replay:{}", path.display()),
+            ReplayInput::Stdin => "replay:stdin".to_string(),
+        };
+        ReplaySource {
+            id,
+            input,
+            interval,
+            stop: Arc::new(AtomicBool::new(false)),
+            health: SharedHealth::default(),
+        }
+    }
+
+    /// "-" means stdin, anything else is a file path
+    pub fn from_arg(arg: &str, interval: Duration) -> Self {
+        match arg.trim() {
+            "-" => Self::new(ReplayInput::Stdin, interval),
+            path => Self::new(ReplayInput::File(PathBuf::from(path)), interval),
+        }
+    }
+}
+
+impl ScanSource for ReplaySource {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "replay"
+    }
+
+    fn describe(&self) -> String {
+        match &self.input {
+            ReplayInput::File(path) => path.display().to_string(),
+            ReplayInput::Stdin => "stdin".to_string(),
+        }
+    }
+
+    fn start(&mut self, sink: ScanSink) -> Result<(), String> {
+        let reader: Box<dyn BufRead + Send> = match &self.input {
+            ReplayInput::File(path) => {
+                let file = std::fs::File::open(path).map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+                Box::new(BufReader::new(file))
+            }
+            ReplayInput::Stdin => Box::new(BufReader::new(std::io::stdin())),
+        };
+        self.stop.store(false, Ordering::Relaxed);
+        set_health(&self.health, true, None);
+
+        let interval = self.interval;
+        let stop = Arc::clone(&self.stop);
+        let health = Arc::clone(&self.health);
+        // Not joined on stop: a read from stdin can't be interrupted, and the
+        // thread ends by itself at the next line
+        std::thread::spawn(move || {
+            let mut replayed = 0;
+            let mut error = None;
+            for line in reader.lines() {
+                if stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                let line = match line {
+                    Ok(line) => line,
+                    Err(e) => {
+                        error = Some(e.to_string());
+                        break;
+                    }
+                };
+                let barcode = line.trim();
+                if barcode.is_empty() || barcode.starts_with('#') {
+                    continue;
+                }
+                if !sink.send(barcode.to_string()) {
+                    break;
+                }
+                replayed += 1;
+                wait_unless_stopped(&stop, interval);
+            }
+            println!("Scan source {} finished after {} scans", sink.source(), replayed);
+            set_health(&health, false, error);
+        });
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    fn health(&self) -> SourceHealth {
+        read_health(&self.health)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_split_across_reads_are_joined() {
+        let mut decoder = FrameDecoder::new(Terminator::Any);
+        assert!(decoder.push(b"ORT").is_empty());
+        assert!(decoder.push(b"X1").is_empty());
+        assert_eq!(decoder.push(b"\r\nNEU"), ["ORTX1"]);
+        assert_eq!(decoder.push(b"RX2\rORTX3\n"), ["NEURX2", "ORTX3"]);
+    }
+
+    #[test]
+    fn each_terminator_ends_its_own_frames() {
+        let stream = b"A1\rB2\nC3\r\nD4\tE5\x03";
+        let decode = |terminator| FrameDecoder::new(terminator).push(stream);
+
+        // CRLF counts once, and the blank frame between CR and LF is skipped
+        assert_eq!(decode(Terminator::Any), ["A1", "B2", "C3"]);
+        // LFs are dropped with the other control bytes
+        assert_eq!(decode(Terminator::Cr), ["A1", "B2C3"]);
+        assert_eq!(decode(Terminator::Lf), ["A1B2", "C3"]);
+        assert_eq!(decode(Terminator::Crlf), ["A1B2", "C3"]);
+        assert_eq!(decode(Terminator::Tab), ["A1B2C3D4"]);
+        // STX/ETX framing, with the STX and stray control bytes dropped
+        let mut decoder = FrameDecoder::new(Terminator::Etx);
+        assert_eq!(decoder.push(b"\x02ORTX1\x03\x02ORTX2\r\x03\x02ORT"), ["ORTX1", "ORTX2"]);
+        assert_eq!(decoder.push(b"X3\x03"), ["ORTX3"]);
+
+        let mut decoder = FrameDecoder::new(Terminator::Crlf);
+        assert_eq!(decoder.push(b"A1\r\nB2\r"), ["A1"]);
+        assert_eq!(decoder.push(b"\n"), ["B2"]);
+    }
+
+    #[test]
+    fn oversized_frames_are_dropped_whole() {
+        let mut decoder = FrameDecoder::new(Terminator::Lf);
+        let noise = vec![b'X'; MAX_FRAME_BYTES * 2 + 10];
+        assert!(decoder.push(&noise).is_empty());
+        // Nothing of the noise leaks into the frame after it
+        assert!(decoder.push(b"YZ\n").is_empty());
+        assert_eq!(decoder.push(b"ORTX1\n"), ["ORTX1"]);
+
+        let longest = vec![b'A'; MAX_FRAME_BYTES];
+        assert_eq!(decoder.push(&[&longest[..], b"\n"].concat()), [String::from_utf8(longest).unwrap()]);
+    }
+
+    #[test]
+    fn replays_a_file_through_the_pipeline() {
+        let dir = std::env::temp_dir().join(format!("scan-source-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("scans.txt");
+        std::fs::write(&path, "# morning round\nORTX1\n\n  NEURX2  \nORTX1\n").unwrap();
+
+        let (sender, scans) = mpsc::channel();
+        let sources = ScanSources::new();
+        sources.start(move |scan| {
+            let _ = sender.send(scan.clone());
+        });
+        sources
+            .add(Box::new(ReplaySource::new(ReplayInput::File(path.clone()), Duration::ZERO)))
+            .unwrap();
+
+        let id = format!("replay:{}", path.display());
+        let received: Vec<(String, String)> = (0..3)
+            .map(|_| scans.recv_timeout(Duration::from_secs(2)).unwrap())
+            .map(|scan| (scan.source, scan.barcode))
+            .collect();
+        assert_eq!(
+            received,
+            [
+                (id.clone(), "ORTX1".to_string()),
+                (id.clone(), "NEURX2".to_string()),
+                (id.clone(), "ORTX1".to_string()),
+            ]
+        );
+        assert!(scans.recv_timeout(Duration::from_millis(200)).is_err());
+
+        // Still listed once the file has run out, as disconnected
+        let status = sources.status();
+        assert_eq!(status.len(), 1);
+        assert_eq!((status[0].id.as_str(), status[0].kind.as_str()), (id.as_str(), "replay"));
+        assert_eq!((status[0].scan_count, status[0].last_barcode.as_deref()), (3, Some("ORTX1")));
+        assert!(!status[0].connected);
+
+        let missing = ReplaySource::new(ReplayInput::File(dir.join("missing.txt")), Duration::ZERO);
+        assert!(sources.add(Box::new(missing)).unwrap_err().starts_with("Could not open"));
+        assert!(sources.remove(&id));
+        assert!(sources.status().is_empty());
+    }
+}
@@ -1,9 +1,13 @@
 use rdev::{listen, Event, EventType, Key};
+use serde_json::Value;
+use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use tauri::{AppHandle, Emitter};
-use crate::database::Database;
+use crate::db_service::DbService;
 use crate::logger::Logger;
+use crate::scan_source::{read_health, set_health, ScanSink, ScanSource, SharedHealth, SourceHealth, SourceScan};
+use crate::webhook::WebhookDispatcher;
 
 pub struct Scanner {
     pub last_input: Instant,
@@ -28,22 +32,92 @@ impl Scanner {
         Self::default()
     }
 
-    pub fn start_listening(app_handle: AppHandle) -> Result<(), Box<dyn std::error::Error>> {
-        let scanner = Arc::new(Mutex::new(Scanner::new()));
-        let logger = Arc::new(Mutex::new(Logger::new()?));
-        
+    pub fn start_manual_session(&mut self) {
+        self.is_scanning = true;
+        self.last_input = Instant::now();
+        println!("Manual scan session started");
+    }
+
+    pub fn stop_session(&mut self) {
+        self.is_scanning = false;
+        println!("Scan session stopped");
+    }
+}
+
+/// The keyboard-wedge scanner, read through a global keyboard hook. Input only
+/// counts as a scan during a scan session (started by the trigger barcode or
+/// from the UI) or when it arrives in a scanner-like burst.
+pub struct KeyboardSource {
+    app_handle: AppHandle,
+    /// The one held in `AppState`, so manual sessions and UI commands see the
+    /// same state as the hook
+    scanner: Arc<Mutex<Scanner>>,
+    db: DbService,
+    webhooks: Arc<WebhookDispatcher>,
+    /// Where the hook sends barcodes; `None` while stopped
+    sink: Arc<Mutex<Option<ScanSink>>>,
+    hooked: bool,
+    health: SharedHealth,
+}
+
+impl KeyboardSource {
+    pub fn new(app_handle: AppHandle, scanner: Arc<Mutex<Scanner>>, db: DbService, webhooks: Arc<WebhookDispatcher>) -> Self {
+        KeyboardSource {
+            app_handle,
+            scanner,
+            db,
+            webhooks,
+            sink: Arc::new(Mutex::new(None)),
+            hooked: false,
+            health: SharedHealth::default(),
+        }
+    }
+}
+
+impl ScanSource for KeyboardSource {
+    fn id(&self) -> &str {
+        "keyboard"
+    }
+
+    fn kind(&self) -> &'static str {
+        "keyboard"
+    }
+
+    fn describe(&self) -> String {
+        "Keyboard wedge".to_string()
+    }
+
+    fn start(&mut self, sink: ScanSink) -> Result<(), String> {
         // Load trigger barcode from settings
-        if let Ok(db) = Database::new() {
-            if let Ok(Some(trigger)) = db.get_setting("trigger_barcode") {
-                if let Ok(mut s) = scanner.lock() {
-                    s.trigger_barcode = trigger;
-                }
+        if let Ok(Some(trigger)) = self.db.with(|db| db.get_setting("trigger_barcode")) {
+            if let Ok(mut s) = self.scanner.lock() {
+                s.trigger_barcode = trigger;
             }
         }
+        if let Ok(mut current) = self.sink.lock() {
+            *current = Some(sink);
+        }
+        set_health(&self.health, true, None);
+        if self.hooked {
+            return Ok(());
+        }
+        self.hooked = true;
+
+        let scanner_clone = Arc::clone(&self.scanner);
+        let sink_clone = Arc::clone(&self.sink);
+        let health = Arc::clone(&self.health);
+        let app_clone = self.app_handle.clone();
 
-        let scanner_clone = Arc::clone(&scanner);
-        let logger_clone = Arc::clone(&logger);
-        let app_clone = app_handle.clone();
+        // Session events are queued for webhooks here rather than in the hook:
+        // a slow share must not hold up keyboard input for the whole desktop
+        let (session_events, published) = mpsc::channel::<(&'static str, Value)>();
+        let db = self.db.clone();
+        let webhooks = Arc::clone(&self.webhooks);
+        std::thread::spawn(move || {
+            for (event, data) in published {
+                webhooks.publish(&db, event, &data);
+            }
+        });
 
         std::thread::spawn(move || {
             let mut char_buffer = String::new();
@@ -51,6 +125,9 @@ impl Scanner {
             let mut burst_detected = false;
 
             if let Err(error) = listen(move |event: Event| {
+                let Some(sink) = sink_clone.lock().ok().and_then(|sink| sink.clone()) else {
+                    return;
+                };
                 if let EventType::KeyPress(key) = event.event_type {
                     let now = Instant::now();
                     
@@ -66,33 +143,11 @@ impl Scanner {
                                     scanner.is_scanning = true;
                                     scanner.last_input = now;
                                     let _ = app_clone.emit("scan-session-started", ());
+                                    let _ = session_events.send(("scan-session-started", serde_json::json!({ "trigger": "barcode" })));
                                     println!("Scan session started by trigger barcode");
                                 } else if scanner.is_scanning || burst_detected {
-                                    // CRITICAL FIX: Only process if barcode has valid department prefix
-                                    if let Ok(logger) = logger_clone.lock() {
-                                        // Check if barcode has valid department prefix before processing
-                                        if logger.has_valid_department_prefix(&barcode) {
-                                            drop(logger); // Release lock before processing
-                                            if let Ok(mut logger) = logger_clone.lock() {
-                                                match logger.process_barcode_scan(&barcode) {
-                                                    Ok(action) => {
-                                                        let _ = app_clone.emit("barcode-scanned", serde_json::json!({
-                                                            "barcode": barcode,
-                                                            "action": action.action,
-                                                            "department": action.department
-                                                        }));
-                                                        println!("Processed barcode: {} - {}", barcode, action.action);
-                                                    }
-                                                    Err(e) => {
-                                                        eprintln!("Error processing barcode {}: {}", barcode, e);
-                                                    }
-                                                }
-                                            }
-                                        } else {
-                                            println!("Ignored barcode (no valid department prefix): {}", barcode);
-                                        }
-                                    }
-                                    
+                                    sink.send(barcode);
+
                                     // Reset burst detection
                                     burst_detected = false;
                                     
@@ -128,6 +183,7 @@ impl Scanner {
                         if scanner.is_scanning && now.duration_since(scanner.last_input).as_millis() > scanner.session_timeout_ms as u128 {
                             scanner.is_scanning = false;
                             let _ = app_clone.emit("scan-session-ended", ());
+                            let _ = session_events.send(("scan-session-ended", serde_json::json!({ "reason": "timeout" })));
                             println!("Scan session ended due to timeout");
                         }
                     }
@@ -140,21 +196,67 @@ impl Scanner {
                 }
             }) {
                 eprintln!("Error listening to keyboard input: {:?}", error);
+                set_health(&health, false, Some(format!("{:?}", error)));
             }
         });
 
         Ok(())
     }
 
-    pub fn start_manual_session(&mut self) {
-        self.is_scanning = true;
-        self.last_input = Instant::now();
-        println!("Manual scan session started");
+    /// rdev can't remove its hook, so keys are ignored instead until the next start
+    fn stop(&mut self) {
+        if let Ok(mut sink) = self.sink.lock() {
+            *sink = None;
+        }
+        set_health(&self.health, false, None);
     }
 
-    pub fn stop_session(&mut self) {
-        self.is_scanning = false;
-        println!("Scan session stopped");
+    fn health(&self) -> SourceHealth {
+        read_health(&self.health)
+    }
+}
+
+/// What the scan pipeline does with every barcode, whichever source read it:
+/// checks the department prefix, logs the checkout or return, and tells the
+/// UI and webhooks. Returns whether the barcode was processed.
+pub fn process_scanned_barcode(
+    app_handle: &AppHandle,
+    logger: &Arc<Mutex<Logger>>,
+    db: &DbService,
+    webhooks: &WebhookDispatcher,
+    scan: &SourceScan,
+) -> bool {
+    let barcode = scan.barcode.as_str();
+    let Ok(mut logger) = logger.lock() else {
+        return false;
+    };
+    // CRITICAL FIX: Only process if barcode has valid department prefix
+    if !logger.has_valid_department_prefix(barcode) {
+        println!("Ignored barcode (no valid department prefix): {}", barcode);
+        return false;
+    }
+    match logger.process_barcode_scan(barcode) {
+        Ok(action) => {
+            drop(logger);
+            let result = serde_json::json!({
+                "barcode": barcode,
+                "action": action.action,
+                "department": action.department,
+                "queued": action.queued,
+                "source": scan.source
+            });
+            let _ = app_handle.emit("barcode-scanned", &result);
+            // Queued scans go out to webhooks when the offline queue is flushed
+            if !action.queued {
+                webhooks.publish(db, "barcode-scanned", &result);
+            }
+            println!("Processed barcode from {}: {} - {}", scan.source, barcode, action.action);
+            true
+        }
+        Err(e) => {
+            eprintln!("Error processing barcode {}: {}", barcode, e);
+            false
+        }
     }
 }
 
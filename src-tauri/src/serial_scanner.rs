@@ -0,0 +1,380 @@
+//! Barcode scanners on a serial/COM port (including USB scanners in virtual
+//! COM mode), read on a background thread and reconnected after an unplug.
+//!
+//! Unlike a keyboard-wedge scanner a serial scanner is a dedicated device, so
+//! its scans don't need a scan session. Its settings are kept per station,
+//! since each station has its own scanner on its own port.
+use crate::scan_source::{
+    read_health, set_health, wait_unless_stopped, FrameDecoder, ScanSink, ScanSource, SharedHealth, SourceHealth,
+    Terminator, READ_TIMEOUT,
+};
+use crate::station_config::StationConfig;
+use serde::{Deserialize, Serialize};
+use serialport::{DataBits, FlowControl, Parity, SerialPortType, StopBits};
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How long to wait before trying to open the port again
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SerialSettings {
+    pub enabled: bool,
+    pub port: String,
+    pub baud_rate: u32,
+    /// 5-8
+    pub data_bits: u8,
+    /// "none", "odd" or "even"
+    pub parity: String,
+    /// 1 or 2
+    pub stop_bits: u8,
+    /// "none", "software" or "hardware"
+    pub flow_control: String,
+    pub terminator: Terminator,
+}
+
+/// A port as reported by the operating system
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SerialPortEntry {
+    pub name: String,
+    /// "usb", "pci", "bluetooth" or "unknown"
+    pub kind: String,
+    pub description: Option<String>,
+    pub manufacturer: Option<String>,
+    pub serial_number: Option<String>,
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+}
+
+impl SerialSettings {
+    /// Off until a port is chosen; 9600 8N1 with CR/LF is what most scanners ship with
+    pub fn load(config: &StationConfig) -> rusqlite::Result<Self> {
+        let setting = |key: &str| -> rusqlite::Result<String> { Ok(config.get(key)?.unwrap_or_default()) };
+        Ok(SerialSettings {
+            enabled: setting("serial_enabled")? == "true",
+            port: setting("serial_port")?.trim().to_string(),
+            baud_rate: setting("serial_baud_rate")?.parse().unwrap_or(9600),
+            data_bits: setting("serial_data_bits")?.parse().unwrap_or(8),
+            parity: setting("serial_parity")?,
+            stop_bits: setting("serial_stop_bits")?.parse().unwrap_or(1),
+            flow_control: setting("serial_flow_control")?,
+            terminator: Terminator::parse(&setting("serial_terminator")?).unwrap_or(Terminator::Any),
+        })
+    }
+
+    /// Checks everything except whether the port exists, which may change
+    pub fn validate(&self) -> Result<(), String> {
+        if self.enabled && self.port.is_empty() {
+            return Err("A serial port must be chosen".to_string());
+        }
+        if self.baud_rate == 0 {
+            return Err("Baud rate must be above 0".to_string());
+        }
+        self.framing().map(|_| ())
+    }
+
+    fn framing(&self) -> Result<(DataBits, Parity, StopBits, FlowControl), String> {
+        let data_bits = match self.data_bits {
+            5 => DataBits::Five,
+            6 => DataBits::Six,
+            7 => DataBits::Seven,
+            8 => DataBits::Eight,
+            other => return Err(format!("Invalid data bits {}, expected 5-8", other)),
+        };
+        let parity = match self.parity.trim().to_ascii_lowercase().as_str() {
+            "" | "none" => Parity::None,
+            "odd" => Parity::Odd,
+            "even" => Parity::Even,
+            other => return Err(format!("Invalid parity '{}', expected none, odd or even", other)),
+        };
+        let stop_bits = match self.stop_bits {
+            1 => StopBits::One,
+            2 => StopBits::Two,
+            other => return Err(format!("Invalid stop bits {}, expected 1 or 2", other)),
+        };
+        let flow_control = match self.flow_control.trim().to_ascii_lowercase().as_str() {
+            "" | "none" => FlowControl::None,
+            "software" => FlowControl::Software,
+            "hardware" => FlowControl::Hardware,
+            other => return Err(format!("Invalid flow control '{}', expected none, software or hardware", other)),
+        };
+        Ok((data_bits, parity, stop_bits, flow_control))
+    }
+
+    fn open(&self) -> Result<Box<dyn serialport::SerialPort>, String> {
+        let (data_bits, parity, stop_bits, flow_control) = self.framing()?;
+        serialport::new(&self.port, self.baud_rate)
+            .data_bits(data_bits)
+            .parity(parity)
+            .stop_bits(stop_bits)
+            .flow_control(flow_control)
+            .timeout(READ_TIMEOUT)
+            .open()
+            .map_err(|e| format!("Could not open {}: {}", self.port, e))
+    }
+}
+
+pub fn list_ports() -> Result<Vec<SerialPortEntry>, String> {
+    let ports = serialport::available_ports().map_err(|e| e.to_string())?;
+    Ok(ports
+        .into_iter()
+        .map(|port| {
+            let mut entry = SerialPortEntry {
+                name: port.port_name,
+                kind: "unknown".to_string(),
+                description: None,
+                manufacturer: None,
+                serial_number: None,
+                vid: None,
+                pid: None,
+            };
+            match port.port_type {
+                SerialPortType::UsbPort(usb) => {
+                    entry.kind = "usb".to_string();
+                    entry.description = usb.product;
+                    entry.manufacturer = usb.manufacturer;
+                    entry.serial_number = usb.serial_number;
+                    entry.vid = Some(usb.vid);
+                    entry.pid = Some(usb.pid);
+                }
+                SerialPortType::PciPort => entry.kind = "pci".to_string(),
+                SerialPortType::BluetoothPort => entry.kind = "bluetooth".to_string(),
+                SerialPortType::Unknown => {}
+            }
+            entry
+        })
+        .collect())
+}
+
+/// Reads one serial port. The port is reopened every few seconds while it
+/// can't be opened or after it goes away, until the source is stopped.
+pub struct SerialSource {
+    settings: SerialSettings,
+    stop: Arc<AtomicBool>,
+    health: SharedHealth,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SerialSource {
+    pub fn new(settings: SerialSettings) -> Self {
+        SerialSource {
+            settings,
+            stop: Arc::new(AtomicBool::new(false)),
+            health: SharedHealth::default(),
+            thread: None,
+        }
+    }
+}
+
+impl ScanSource for SerialSource {
+    fn id(&self) -> &str {
+        "serial"
+    }
+
+    fn kind(&self) -> &'static str {
+        "serial"
+    }
+
+    fn describe(&self) -> String {
+        format!("{} at {} baud", self.settings.port, self.settings.baud_rate)
+    }
+
+    fn start(&mut self, sink: ScanSink) -> Result<(), String> {
+        self.settings.validate()?;
+        self.stop.store(false, Ordering::Relaxed);
+        let settings = self.settings.clone();
+        let stop = Arc::clone(&self.stop);
+        let health = Arc::clone(&self.health);
+
+        self.thread = Some(std::thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                let mut port = match settings.open() {
+                    Ok(port) => port,
+                    Err(e) => {
+                        if read_health(&health).last_error.as_deref() != Some(e.as_str()) {
+                            eprintln!("Serial scanner: {}", e);
+                        }
+                        set_health(&health, false, Some(e));
+                        wait_unless_stopped(&stop, RECONNECT_DELAY);
+                        continue;
+                    }
+                };
+                println!("Serial scanner connected on {}", settings.port);
+                set_health(&health, true, None);
+
+                let mut decoder = FrameDecoder::new(settings.terminator);
+                let mut buffer = [0u8; 256];
+                let error = loop {
+                    if stop.load(Ordering::Relaxed) {
+                        break None;
+                    }
+                    match port.read(&mut buffer) {
+                        Ok(0) => std::thread::sleep(READ_TIMEOUT),
+                        Ok(read) => {
+                            for barcode in decoder.push(&buffer[..read]) {
+                                sink.send(barcode);
+                            }
+                        }
+                        Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {}
+                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                        // Unplugged, or the driver reset the port
+                        Err(e) => break Some(format!("Lost {}: {}", settings.port, e)),
+                    }
+                };
+
+                drop(port);
+                set_health(&health, false, error.clone());
+                if let Some(e) = error {
+                    eprintln!("Serial scanner: {}", e);
+                    wait_unless_stopped(&stop, RECONNECT_DELAY);
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // Waiting closes the port, so a restarted source can open it straight away
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    fn health(&self) -> SourceHealth {
+        read_health(&self.health)
+    }
+}
+
+impl Drop for SerialSource {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::time::Instant;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("serial-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn settings_for(port: &str) -> SerialSettings {
+        SerialSettings {
+            enabled: true,
+            port: port.to_string(),
+            baud_rate: 9600,
+            data_bits: 8,
+            parity: "none".to_string(),
+            stop_bits: 1,
+            flow_control: "none".to_string(),
+            terminator: Terminator::Any,
+        }
+    }
+
+    /// Waits for the source to report `connected`, allowing for one reconnect delay
+    fn wait_for_connected(source: &SerialSource, connected: bool) -> SourceHealth {
+        let deadline = Instant::now() + Duration::from_secs(2) + RECONNECT_DELAY;
+        loop {
+            let health = source.health();
+            if health.connected == connected || Instant::now() > deadline {
+                return health;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn settings_come_from_the_station_config() {
+        let config = StationConfig::open_at(&temp_path("station.db")).unwrap();
+        let settings = SerialSettings::load(&config).unwrap();
+        assert_eq!(
+            settings,
+            SerialSettings {
+                enabled: false,
+                port: String::new(),
+                parity: String::new(),
+                flow_control: String::new(),
+                ..settings_for("")
+            }
+        );
+        assert!(settings.validate().is_ok());
+
+        config.set("serial_enabled", "true").unwrap();
+        assert!(SerialSettings::load(&config).unwrap().validate().is_err(), "no port chosen");
+        config.set("serial_port", " /dev/ttyUSB0 ").unwrap();
+        config.set("serial_parity", "mark").unwrap();
+        assert!(SerialSettings::load(&config).unwrap().validate().is_err());
+        config.set("serial_parity", "Even").unwrap();
+        config.set("serial_baud_rate", "19200").unwrap();
+        config.set("serial_terminator", "crlf").unwrap();
+        let settings = SerialSettings::load(&config).unwrap();
+        assert!(settings.validate().is_ok());
+        assert_eq!(
+            (settings.port.as_str(), settings.baud_rate, settings.terminator),
+            ("/dev/ttyUSB0", 19200, Terminator::Crlf)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reads_framed_barcodes_and_reconnects_after_an_unplug() {
+        use serialport::{SerialPort, TTYPort};
+
+        // The source opens the link, so the pty behind it can be swapped like a replugged scanner
+        let link = temp_path("scanner");
+        let plug_in = || {
+            let (master, slave) = TTYPort::pair().unwrap();
+            let _ = std::fs::remove_file(&link);
+            std::os::unix::fs::symlink(slave.name().unwrap(), &link).unwrap();
+            (master, slave)
+        };
+
+        let (mut master, slave) = plug_in();
+        let (sink, scans) = ScanSink::channel("serial");
+        let mut source = SerialSource::new(settings_for(link.to_str().unwrap()));
+        source.start(sink).unwrap();
+        assert!(wait_for_connected(&source, true).connected);
+
+        master.write_all(b"ORTX1\r\nORT").unwrap();
+        master.write_all(b"X2\r\n\x02NEURX3\x03\r\n").unwrap();
+        let received: Vec<String> = (0..3)
+            .map(|_| scans.recv_timeout(Duration::from_secs(2)).unwrap())
+            .map(|scan| {
+                assert_eq!(scan.source, "serial");
+                scan.barcode
+            })
+            .collect();
+        assert_eq!(received, ["ORTX1", "ORTX2", "NEURX3"]);
+
+        // Unplug
+        drop(master);
+        drop(slave);
+        let health = wait_for_connected(&source, false);
+        assert!(!health.connected);
+        assert!(health.last_error.is_some());
+
+        // Plug back in
+        let (mut master, _slave) = plug_in();
+        assert!(wait_for_connected(&source, true).connected, "{:?}", source.health());
+        master.write_all(b"ORTX4\n").unwrap();
+        assert_eq!(scans.recv_timeout(Duration::from_secs(2)).unwrap().barcode, "ORTX4");
+
+        // Stopping closes the port without waiting out a reconnect delay
+        let started = Instant::now();
+        source.stop();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(scans.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+}
@@ -0,0 +1,80 @@
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use std::path::{Path, PathBuf};
+
+/// Settings that belong to this station rather than to everyone on the share
+pub const STATION_SETTINGS: &[&str] = &[
+    "smtp_password",
+    "serial_enabled",
+    "serial_port",
+    "serial_baud_rate",
+    "serial_data_bits",
+    "serial_parity",
+    "serial_stop_bits",
+    "serial_flow_control",
+    "serial_terminator",
+    "tcp_scanner_enabled",
+    "tcp_scanner_address",
+];
+
+/// Whether `key` is kept in the station's local config instead of the shared settings table
+pub fn is_station_setting(key: &str) -> bool {
+    STATION_SETTINGS.contains(&key)
+}
+
+/// What the UI gets instead of a stored password
+pub const REDACTED: &str = "********";
+
+/// Credentials are never handed back to the UI, only whether one is set
+pub fn is_secret(key: &str) -> bool {
+    key.ends_with("_password")
+}
+
+/// Per-station settings, kept in the local app data folder next to the
+/// offline queue. Secrets stay off the network share this way, and hardware
+/// like a serial scanner can be set up differently at each station.
+pub struct StationConfig {
+    conn: Connection,
+}
+
+impl StationConfig {
+    pub fn open() -> Result<Self> {
+        Self::open_at(&Self::get_config_path())
+    }
+
+    pub fn open_at(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS station_settings (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );",
+        )?;
+        Ok(StationConfig { conn })
+    }
+
+    fn get_config_path() -> PathBuf {
+        if let Some(path) = std::env::var_os("STATION_CONFIG_PATH") {
+            return PathBuf::from(path);
+        }
+
+        let mut path = dirs::data_local_dir().unwrap_or_else(|| PathBuf::from("."));
+        path.push("HarrysLillaLager");
+        std::fs::create_dir_all(&path).unwrap_or_default();
+        path.push("station.db");
+        path
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        self.conn
+            .prepare_cached("SELECT value FROM station_settings WHERE key = ?1")?
+            .query_row(params![key], |row| row.get(0))
+            .optional()
+    }
+
+    pub fn set(&self, key: &str, value: &str) -> Result<()> {
+        self.conn
+            .prepare_cached("INSERT OR REPLACE INTO station_settings (key, value) VALUES (?1, ?2)")?
+            .execute(params![key, value])?;
+        Ok(())
+    }
+}
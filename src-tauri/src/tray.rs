@@ -1,10 +1,27 @@
 use tauri::{
     tray::{TrayIconBuilder, TrayIconEvent, MouseButton},
-    menu::{MenuBuilder, MenuItemBuilder, PredefinedMenuItem},
-    AppHandle, Manager, WebviewWindowBuilder, Emitter,
+    menu::{Menu, MenuBuilder, MenuItemBuilder, PredefinedMenuItem},
+    AppHandle, Manager, WebviewWindowBuilder, Emitter, Listener, Wry,
 };
+use std::sync::{Arc, Mutex};
 // We'll construct a custom single-color tray icon (only for the tray) in the requested color #f88379.
 use tauri::image::Image; // provides Image::from_rgba for custom icon creation
+use tauri_plugin_notification::NotificationExt;
+use crate::alert::{AlertLevel, AlertSummary, OverdueItem};
+use crate::badge;
+use crate::db_service::DbService;
+use crate::export::Exporter;
+use crate::logger::Logger;
+
+const TRAY_ID: &str = "main";
+const TRAY_TOOLTIP: &str = "Harry's Lilla Lager - Kirurgiskt lagersystem";
+/// Generated 32x32 PNG (derived from icon.svg with color #f88379)
+const TRAY_ICON: &[u8] = include_bytes!("../icons/tray-icon-32.png");
+/// Barcodes named in a notification before it says "and N more"
+const NOTIFICATION_ITEMS: usize = 3;
+/// Scans listed in the tray menu
+const MENU_RECENT_SCANS: i64 = 5;
+const UNDO_REASON: &str = "Undone from tray menu";
 
 pub struct TrayManager;
 
@@ -52,36 +69,17 @@ impl TrayManager {
         Err("No tray icon found".into())
     }
 
-    pub fn setup_system_tray(app: &AppHandle) -> Result<(), Box<dyn std::error::Error>> {
-        // Create menu items
-        let show_admin = MenuItemBuilder::with_id("show_admin", "Open Admin Panel").build(app)?;
-        let quick_scan = MenuItemBuilder::with_id("quick_scan", "Quick Scan").build(app)?;
-        let start_scan = MenuItemBuilder::with_id("start_scan", "Start Scan Session").build(app)?;
-        let export_data = MenuItemBuilder::with_id("export_data", "Export Data").build(app)?;
-        let separator = PredefinedMenuItem::separator(app)?;
-        let quit = PredefinedMenuItem::quit(app, Some("Quit"))?;
-
-        // Build menu
-        let menu = MenuBuilder::new(app)
-            .item(&show_admin)
-            .item(&separator)
-            .item(&quick_scan)
-            .item(&start_scan)
-            .item(&separator)
-            .item(&export_data)
-            .item(&separator)
-            .item(&quit)
-            .build()?;
+    pub fn setup_system_tray(app: &AppHandle, logger: Arc<Mutex<Logger>>, db: DbService) -> Result<(), Box<dyn std::error::Error>> {
+        let menu = Self::build_menu(app, &db)?;
 
-        // Use a generated 32x32 PNG (derived from icon.svg with color #f88379) for tray icon.
         // Falls back to default window icon if parsing fails.
-        let tray_icon_image = Image::from_bytes(include_bytes!("../icons/tray-icon-32.png"))
+        let tray_icon_image = Image::from_bytes(TRAY_ICON)
             .unwrap_or_else(|_| app.default_window_icon().unwrap().clone());
 
-        let _tray = TrayIconBuilder::new()
+        let _tray = TrayIconBuilder::with_id(TRAY_ID)
             .menu(&menu)
             .icon(tray_icon_image)
-            .tooltip("Harry's Lilla Lager - Kirurgiskt lagersystem")
+            .tooltip(TRAY_TOOLTIP)
             .on_menu_event(move |app_handle, event| {
                 match event.id().as_ref() {
                     "show_admin" => {
@@ -99,8 +97,15 @@ impl TrayManager {
                         let _ = app_handle.emit("manual-scan-start", ());
                         println!("Manual scan session started from tray");
                     }
-                    "export_data" => {
-                        let _ = app_handle.emit("export-request", ());
+                    "undo_last_scan" => {
+                        if let Err(e) = Self::undo_last_scan(app_handle, &logger) {
+                            eprintln!("Failed to undo last scan from tray: {}", e);
+                        }
+                    }
+                    "export_checked_out" => {
+                        if let Err(e) = Self::export_checked_out(app_handle, &db) {
+                            eprintln!("Failed to export checked-out items from tray: {}", e);
+                        }
                     }
                     "quit" => {
                         app_handle.exit(0);
@@ -137,6 +142,206 @@ impl TrayManager {
         Ok(())
     }
 
+    /// Keeps the tray menu, badge and tooltip current, and raises a desktop
+    /// notification when the alert check finds new overdue items. Works from
+    /// the backend events, so it doesn't need any window open.
+    pub fn watch_events(app: &AppHandle, db: DbService) {
+        let handle = app.clone();
+        let summary_db = db.clone();
+        app.listen_any("overdue-summary", move |event| {
+            if let Ok(summary) = serde_json::from_str::<AlertSummary>(event.payload()) {
+                Self::update_overdue_badge(&handle, summary);
+            }
+            Self::refresh_menu(&handle, &summary_db);
+        });
+
+        // Scans, voids, acknowledgements and flushed offline scans can resolve
+        // alerts between checks
+        for event in ["barcode-scanned", "scan-voided", "alert-updated", "pending-scans-changed"] {
+            let handle = app.clone();
+            let db = db.clone();
+            app.listen_any(event, move |_| {
+                Self::refresh_overdue_badge(&handle, &db);
+                Self::refresh_menu(&handle, &db);
+            });
+        }
+
+        let handle = app.clone();
+        let settings_db = db.clone();
+        app.listen_any("overdue-alert", move |event| {
+            let enabled = settings_db.with(|db| db.get_setting("desktop_notifications")).ok().flatten();
+            if enabled.as_deref() == Some("false") {
+                return;
+            }
+            let Ok(alert) = serde_json::from_str::<serde_json::Value>(event.payload()) else {
+                return;
+            };
+            let items: Vec<OverdueItem> = alert
+                .get("items")
+                .and_then(|items| serde_json::from_value(items.clone()).ok())
+                .unwrap_or_default();
+            if let Err(e) = Self::notify_overdue(&handle, &items) {
+                eprintln!("Failed to show overdue notification: {}", e);
+            }
+        });
+
+        Self::refresh_overdue_badge(app, &db);
+    }
+
+    /// Menu with what is out per department, the overdue count, the latest
+    /// scans and the quick actions
+    fn build_menu(app: &AppHandle, db: &DbService) -> Result<Menu<Wry>, Box<dyn std::error::Error>> {
+        let info = |text: &str| MenuItemBuilder::new(text).enabled(false).build(app);
+
+        let mut menu = MenuBuilder::new(app)
+            .item(&MenuItemBuilder::with_id("show_admin", "Open Admin Panel").build(app)?)
+            .separator();
+
+        match db.with(|db| db.get_department_stats()) {
+            Ok(departments) => {
+                let total: i64 = departments.iter().map(|(_, count)| count).sum();
+                menu = menu.item(&info(&format!("Checked out: {}", total))?);
+                for (department, count) in &departments {
+                    menu = menu.item(&info(&format!("    {}: {}", department, count))?);
+                }
+            }
+            Err(e) => {
+                eprintln!("Failed to read department counts for tray: {}", e);
+                menu = menu.item(&info("Checked out: unavailable")?);
+            }
+        }
+        if let Ok(summary) = db.with(|db| db.get_alert_summary()) {
+            let overdue = match summary.critical {
+                0 => format!("Overdue: {}", summary.open),
+                critical => format!("Overdue: {} ({} critical)", summary.open, critical),
+            };
+            menu = menu.item(&info(&overdue)?);
+        }
+
+        menu = menu.separator().item(&info("Last scans")?);
+        let recent = db.with(|db| db.get_logs(Some(MENU_RECENT_SCANS), false)).unwrap_or_default();
+        if recent.is_empty() {
+            menu = menu.item(&info("    No scans yet")?);
+        }
+        for log in &recent {
+            let action = if log.action == "check-in" { "In" } else { "Out" };
+            let mut label = format!("    {}  {}  {}", log.timestamp.format("%H:%M"), action, log.barcode);
+            if let Some(department) = &log.department {
+                label.push_str(&format!(" ({})", department));
+            }
+            menu = menu.item(&info(&label)?);
+        }
+
+        // Only this station's own scans can be undone from here, as in the admin panel
+        let undo = match db.with(|db| db.get_last_scan()).ok().flatten() {
+            Some(last) => MenuItemBuilder::with_id("undo_last_scan", format!("Undo Last Scan ({})", last.barcode)).build(app)?,
+            None => MenuItemBuilder::with_id("undo_last_scan", "Undo Last Scan").enabled(false).build(app)?,
+        };
+
+        Ok(menu
+            .item(&undo)
+            .separator()
+            .item(&MenuItemBuilder::with_id("quick_scan", "Quick Scan").build(app)?)
+            .item(&MenuItemBuilder::with_id("start_scan", "Start Scan Session").build(app)?)
+            .item(&MenuItemBuilder::with_id("export_checked_out", "Export Checked-Out List Now").build(app)?)
+            .separator()
+            .item(&PredefinedMenuItem::quit(app, Some("Quit"))?)
+            .build()?)
+    }
+
+    fn refresh_menu(app: &AppHandle, db: &DbService) {
+        let Some(tray) = app.tray_by_id(TRAY_ID) else {
+            return;
+        };
+        match Self::build_menu(app, db) {
+            Ok(menu) => {
+                let _ = tray.set_menu(Some(menu));
+            }
+            Err(e) => eprintln!("Failed to rebuild tray menu: {}", e),
+        }
+    }
+
+    fn undo_last_scan(app: &AppHandle, logger: &Arc<Mutex<Logger>>) -> Result<(), Box<dyn std::error::Error>> {
+        let voided = logger
+            .lock()
+            .map_err(|e| e.to_string())?
+            .void_scan(None, UNDO_REASON, None)?;
+        println!("Undid scan of {} from tray", voided.barcode);
+        // Same event as the void command, so the UI and this menu refresh
+        let _ = app.emit("scan-voided", &voided);
+        Ok(())
+    }
+
+    fn export_checked_out(app: &AppHandle, db: &DbService) -> Result<(), Box<dyn std::error::Error>> {
+        let file_path = Exporter::new(db.clone()).quick_export_checked_out()?;
+        println!("Exported checked-out items from tray to {:?}", file_path);
+        let _ = app.emit("checked-out-exported", file_path.to_string_lossy());
+        app.notification()
+            .builder()
+            .title("Utcheckade artiklar exporterade")
+            .body(file_path.to_string_lossy())
+            .show()?;
+        Ok(())
+    }
+
+    fn refresh_overdue_badge(app: &AppHandle, db: &DbService) {
+        match db.with(|db| db.get_alert_summary()) {
+            Ok(summary) => Self::update_overdue_badge(app, summary),
+            Err(e) => eprintln!("Failed to read overdue count for tray: {}", e),
+        }
+    }
+
+    /// Badged icon and a tooltip with the count; the plain icon when nothing is overdue
+    pub fn update_overdue_badge(app: &AppHandle, summary: AlertSummary) {
+        let Some(tray) = app.tray_by_id(TRAY_ID) else {
+            return;
+        };
+
+        let tooltip = match summary {
+            AlertSummary { open: 0, .. } => TRAY_TOOLTIP.to_string(),
+            AlertSummary { open, critical: 0, .. } => format!("Harry's Lilla Lager - {} försenade artiklar", open),
+            AlertSummary { open, critical, .. } => {
+                format!("Harry's Lilla Lager - {} försenade artiklar ({} kritiska)", open, critical)
+            }
+        };
+        let _ = tray.set_tooltip(Some(&tooltip));
+
+        match badge::overdue_badge(TRAY_ICON, summary.open, summary.critical > 0) {
+            Ok(icon) => {
+                let (width, height) = icon.dimensions();
+                let _ = tray.set_icon(Some(Image::new_owned(icon.into_raw(), width, height)));
+            }
+            Err(e) => eprintln!("Failed to draw tray badge: {}", e),
+        }
+    }
+
+    fn notify_overdue(app: &AppHandle, items: &[OverdueItem]) -> Result<(), Box<dyn std::error::Error>> {
+        if items.is_empty() {
+            return Ok(());
+        }
+        let critical = items.iter().any(|item| item.level == AlertLevel::Critical);
+        let title = match (items.len(), critical) {
+            (1, true) => "Kritiskt försenad artikel".to_string(),
+            (1, false) => "Försenad artikel".to_string(),
+            (count, true) => format!("{} försenade artiklar, kritiska finns", count),
+            (count, false) => format!("{} försenade artiklar", count),
+        };
+        let mut lines: Vec<String> = items
+            .iter()
+            .take(NOTIFICATION_ITEMS)
+            .map(|item| {
+                let department = item.department.as_deref().unwrap_or("okänd enhet");
+                format!("{} ({}), {} h ute", item.barcode, department, item.hours_overdue)
+            })
+            .collect();
+        if items.len() > NOTIFICATION_ITEMS {
+            lines.push(format!("och {} till", items.len() - NOTIFICATION_ITEMS));
+        }
+
+        app.notification().builder().title(title).body(lines.join("\n")).show()?;
+        Ok(())
+    }
+
     fn create_admin_window(app: &AppHandle) -> Result<(), tauri::Error> {
         let _window = WebviewWindowBuilder::new(app, "main", tauri::WebviewUrl::App("index.html".into()))
             .title("Harry's Lilla Lager - Admin Panel")
@@ -0,0 +1,512 @@
+//! Outbound webhooks, so other systems (the ward's Teams connector, the
+//! ticketing tool) hear about scans, overdue alerts and scan sessions.
+//!
+//! Events are written to the `webhook_outbox` table first, one row per
+//! subscribed target, and a background thread delivers them. Failed
+//! deliveries are retried with exponential backoff, and anything still queued
+//! when the app closes goes out after the next start. Deliveries to one target
+//! can arrive out of order after a retry, so receivers should order by
+//! `occurred_at` and use the `X-Inventory-Delivery` header to drop duplicates.
+//! Every station runs a dispatcher; each claims the rows it is about to send,
+//! so a delivery only goes out from one of them.
+//!
+//! Every request is a JSON [`WebhookPayload`] POST. When the target has a
+//! secret, `X-Inventory-Signature` is `sha256=` followed by the hex HMAC-SHA256
+//! of `"{X-Inventory-Timestamp}.{body}"`.
+use crate::database::station_name;
+use crate::db_service::DbService;
+use chrono::{DateTime, Duration, Local};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+
+/// Events a target can subscribe to
+pub const WEBHOOK_EVENTS: &[&str] = &[
+    "barcode-scanned",
+    "overdue-alert",
+    "scan-session-started",
+    "scan-session-ended",
+];
+
+/// Bumped whenever a field is removed or changes meaning
+pub const PAYLOAD_SCHEMA_VERSION: u32 = 1;
+
+/// Deliveries given up on after this many failed attempts (about 8 hours of retries)
+pub const MAX_ATTEMPTS: i64 = 12;
+
+const FIRST_RETRY_SECONDS: i64 = 30;
+const MAX_RETRY_SECONDS: i64 = 60 * 60;
+const REQUEST_TIMEOUT_SECONDS: u64 = 10;
+/// Retries and events queued by other stations are picked up within this time
+const POLL_SECONDS: u64 = 5;
+const BATCH_SIZE: i64 = 50;
+/// Delivered rows are kept this long for troubleshooting
+const DELIVERED_RETENTION_DAYS: i64 = 30;
+/// Long enough for a whole batch of timeouts; a station that dies mid-batch
+/// leaves its rows to the others after this
+const CLAIM_LEASE_SECONDS: i64 = BATCH_SIZE * REQUEST_TIMEOUT_SECONDS as i64 + 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookTarget {
+    pub id: i64,
+    pub name: String,
+    pub url: String,
+    /// Only used to sign requests, never sent to the UI
+    #[serde(skip)]
+    pub secret: Option<String>,
+    pub has_secret: bool,
+    /// Subscribed events; empty means all of them
+    pub events: Vec<String>,
+    pub enabled: bool,
+    pub created_at: DateTime<Local>,
+}
+
+/// The editable part of a [`WebhookTarget`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookTargetSettings {
+    pub name: String,
+    pub url: String,
+    /// Left out to keep the current secret when updating; empty removes it
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default)]
+    pub events: Vec<String>,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub id: i64,
+    pub target_id: i64,
+    pub event: String,
+    /// The request body, exactly as sent
+    pub payload: String,
+    pub created_at: DateTime<Local>,
+    pub attempts: i64,
+    pub next_attempt_at: DateTime<Local>,
+    pub last_attempt_at: Option<DateTime<Local>>,
+    /// HTTP status of the last attempt, if the target answered at all
+    pub last_status: Option<u16>,
+    pub last_error: Option<String>,
+    pub delivered_at: Option<DateTime<Local>>,
+    /// Set once `MAX_ATTEMPTS` attempts have failed
+    pub failed_at: Option<DateTime<Local>>,
+}
+
+/// Body of every webhook request
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookPayload<'a, T: Serialize> {
+    pub schema_version: u32,
+    pub event: &'a str,
+    pub occurred_at: DateTime<Local>,
+    pub station: &'a str,
+    /// Same data the UI gets with the event of the same name
+    pub data: &'a T,
+}
+
+impl WebhookTarget {
+    pub fn wants(&self, event: &str) -> bool {
+        self.enabled && (self.events.is_empty() || self.events.iter().any(|wanted| wanted == event))
+    }
+}
+
+impl WebhookTargetSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("Webhook name is required".to_string());
+        }
+        let url = self.url.trim().to_ascii_lowercase();
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(format!("Webhook URL must start with http:// or https://: {}", self.url));
+        }
+        match self.events.iter().find(|event| !WEBHOOK_EVENTS.contains(&event.as_str())) {
+            Some(event) => Err(format!("Unknown webhook event '{}'", event)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// `sha256=<hex HMAC>` of `"{timestamp}.{body}"`
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    let digest: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256={}", digest)
+}
+
+/// Wait before the next attempt once `attempts` have failed: 30 s, doubling, at most an hour
+pub fn retry_delay(attempts: i64) -> Duration {
+    let doublings = attempts.clamp(1, 20) - 1;
+    Duration::seconds((FIRST_RETRY_SECONDS << doublings).min(MAX_RETRY_SECONDS))
+}
+
+/// Queues `event` for every enabled target subscribed to it. Errors are only
+/// printed: a webhook problem must never get in the way of a scan.
+pub fn publish<T: Serialize>(db: &DbService, event: &str, data: &T) {
+    let payload = WebhookPayload {
+        schema_version: PAYLOAD_SCHEMA_VERSION,
+        event,
+        occurred_at: Local::now(),
+        station: station_name(),
+        data,
+    };
+    let result = serde_json::to_string(&payload)
+        .map_err(|e| e.to_string())
+        .and_then(|payload| db.with(|db| db.enqueue_webhook(event, &payload)).map_err(|e| e.to_string()));
+    if let Err(e) = result {
+        eprintln!("Failed to queue {} webhook: {}", event, e);
+    }
+}
+
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout(std::time::Duration::from_secs(REQUEST_TIMEOUT_SECONDS))
+        .user_agent(concat!("HarrysLillaLager/", env!("CARGO_PKG_VERSION")))
+        .build()
+}
+
+/// POSTs one payload. Returns the HTTP status, or the status (if any) and
+/// error when the target didn't answer 2xx.
+fn deliver(agent: &ureq::Agent, target: &WebhookTarget, event: &str, delivery: &str, body: &str) -> Result<u16, (Option<u16>, String)> {
+    let timestamp = Local::now().timestamp();
+    let mut request = agent
+        .post(&target.url)
+        .set("Content-Type", "application/json")
+        .set("X-Inventory-Event", event)
+        .set("X-Inventory-Delivery", delivery)
+        .set("X-Inventory-Timestamp", &timestamp.to_string());
+    if let Some(secret) = target.secret.as_deref().filter(|secret| !secret.is_empty()) {
+        request = request.set("X-Inventory-Signature", &sign(secret, timestamp, body));
+    }
+
+    match request.send_string(body) {
+        Ok(response) => Ok(response.status()),
+        Err(ureq::Error::Status(status, response)) => {
+            let body = response.into_string().unwrap_or_default();
+            let body: String = body.chars().take(200).collect();
+            Err((Some(status), format!("HTTP {}: {}", status, body.trim())))
+        }
+        Err(e) => Err((None, e.to_string())),
+    }
+}
+
+/// Sends a "test" event straight to `target`, bypassing the outbox, and
+/// returns the HTTP status
+pub fn send_test(target: &WebhookTarget) -> Result<u16, String> {
+    let data = serde_json::json!({ "target": target.name });
+    let payload = WebhookPayload {
+        schema_version: PAYLOAD_SCHEMA_VERSION,
+        event: "test",
+        occurred_at: Local::now(),
+        station: station_name(),
+        data: &data,
+    };
+    let body = serde_json::to_string(&payload).map_err(|e| e.to_string())?;
+    deliver(&agent(), target, "test", "test", &body).map_err(|(_, error)| error)
+}
+
+/// Attempts every delivery that is due, returning how many succeeded. The
+/// database isn't locked while a request is in flight.
+pub fn deliver_due(db: &DbService) -> rusqlite::Result<usize> {
+    let due = db.with(|db| db.claim_due_webhooks(BATCH_SIZE, Duration::seconds(CLAIM_LEASE_SECONDS)))?;
+    if due.is_empty() {
+        return Ok(0);
+    }
+    let targets: HashMap<i64, WebhookTarget> = db
+        .with(|db| db.get_webhook_targets())?
+        .into_iter()
+        .map(|target| (target.id, target))
+        .collect();
+
+    let agent = agent();
+    let mut delivered = 0;
+    // A target that is down gets one attempt per round, not one per queued event
+    let mut failing: HashSet<i64> = HashSet::new();
+    // Claimed but not attempted this round; another station may take them
+    let mut skipped = Vec::new();
+    for entry in due {
+        let Some(target) = targets.get(&entry.target_id).filter(|target| !failing.contains(&target.id)) else {
+            skipped.push(entry.id);
+            continue;
+        };
+        let result = deliver(&agent, target, &entry.event, &entry.id.to_string(), &entry.payload);
+        let entry = match result {
+            Ok(status) => {
+                delivered += 1;
+                db.with(|db| db.record_webhook_attempt(entry.id, Some(status), None))?
+            }
+            Err((status, error)) => {
+                failing.insert(target.id);
+                db.with(|db| db.record_webhook_attempt(entry.id, status, Some(&error)))?
+            }
+        };
+        if entry.failed_at.is_some() {
+            eprintln!(
+                "Gave up on {} webhook {} to {} after {} attempts: {}",
+                entry.event,
+                entry.id,
+                target.name,
+                entry.attempts,
+                entry.last_error.as_deref().unwrap_or("")
+            );
+        }
+    }
+    db.with(|db| db.release_webhook_claims(&skipped))?;
+    Ok(delivered)
+}
+
+/// Delivers the outbox in the background: every few seconds, and straight
+/// away when asked to after an event is queued
+pub struct WebhookDispatcher {
+    wake: Mutex<Option<Sender<()>>>,
+}
+
+impl WebhookDispatcher {
+    pub fn new() -> Self {
+        WebhookDispatcher { wake: Mutex::new(None) }
+    }
+
+    pub fn start(&self, db: DbService) {
+        let (wake, woken) = mpsc::channel();
+        if let Ok(mut sender) = self.wake.lock() {
+            *sender = Some(wake);
+        }
+
+        std::thread::spawn(move || {
+            let mut last_purge: Option<DateTime<Local>> = None;
+            loop {
+                if let Err(e) = deliver_due(&db) {
+                    eprintln!("Error delivering webhooks: {}", e);
+                }
+
+                let now = Local::now();
+                if last_purge.is_none_or(|last| now - last > Duration::hours(1)) {
+                    let before = now - Duration::days(DELIVERED_RETENTION_DAYS);
+                    if let Err(e) = db.with(|db| db.purge_delivered_webhooks(before)) {
+                        eprintln!("Error purging delivered webhooks: {}", e);
+                    }
+                    last_purge = Some(now);
+                }
+
+                match woken.recv_timeout(std::time::Duration::from_secs(POLL_SECONDS)) {
+                    Ok(()) => while woken.try_recv().is_ok() {},
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        });
+    }
+
+    /// Queues `event` like [`publish`] and delivers it without waiting for the next poll
+    pub fn publish<T: Serialize>(&self, db: &DbService, event: &str, data: &T) {
+        publish(db, event, data);
+        self.request_delivery();
+    }
+
+    /// Delivers what is queued now instead of at the next poll
+    pub fn request_delivery(&self) {
+        if let Some(wake) = self.wake.lock().ok().and_then(|wake| wake.clone()) {
+            let _ = wake.send(());
+        }
+    }
+}
+
+impl Default for WebhookDispatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tests::test_db;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    /// Headers (lower-cased names) and body of a request the test target received
+    #[derive(Debug)]
+    struct Received {
+        headers: HashMap<String, String>,
+        body: String,
+    }
+
+    /// An HTTP target on a local port answering with `statuses` in turn, then 200
+    fn target_server(statuses: Vec<u16>) -> (String, Arc<Mutex<Vec<Received>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&received);
+        std::thread::spawn(move || {
+            let mut statuses = statuses.into_iter();
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let mut headers = HashMap::new();
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                    match line.trim_end().split_once(": ") {
+                        Some((name, value)) => headers.insert(name.to_lowercase(), value.to_string()),
+                        None => break,
+                    };
+                }
+                let length = headers.get("content-length").map_or(0, |length| length.parse().unwrap());
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                log.lock().unwrap().push(Received {
+                    headers,
+                    body: String::from_utf8(body).unwrap(),
+                });
+                let status = statuses.next().unwrap_or(200);
+                write!(stream, "HTTP/1.1 {} Test\r\nContent-Length: 4\r\nConnection: close\r\n\r\nbody", status).unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    fn settings(url: &str, secret: Option<&str>) -> WebhookTargetSettings {
+        WebhookTargetSettings {
+            name: "Teams".to_string(),
+            url: url.to_string(),
+            secret: secret.map(str::to_string),
+            events: vec!["barcode-scanned".to_string()],
+            enabled: true,
+        }
+    }
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        assert_eq!(
+            sign("s3cret", 1_700_000_000, r#"{"event":"test"}"#),
+            "sha256=1c5b24400e91c3c2a54a5fc594c52fc115eb8e1d8d6b200dc40d90fa6ef6e273"
+        );
+        assert_eq!(
+            sign("Jefe", 1, "what do ya want for nothing?"),
+            "sha256=a9049465ce89cfb8e9aac62333abda66f80e3403f51f57f6bae3c3a4485eca38"
+        );
+    }
+
+    #[test]
+    fn retries_back_off_up_to_an_hour() {
+        let delays: Vec<i64> = (0..=9).map(|attempts| retry_delay(attempts).num_seconds()).collect();
+        assert_eq!(delays, [30, 30, 60, 120, 240, 480, 960, 1920, 3600, 3600]);
+        assert_eq!(retry_delay(MAX_ATTEMPTS).num_seconds(), MAX_RETRY_SECONDS);
+        assert_eq!(retry_delay(i64::MAX).num_seconds(), MAX_RETRY_SECONDS);
+    }
+
+    #[test]
+    fn delivers_signed_and_retries_after_a_server_error() {
+        let (url, received) = target_server(vec![500]);
+        let db = test_db("webhook-delivery");
+        db.save_webhook_target(None, &settings(&url, Some("s3cret")), None).unwrap();
+        let db = DbService::from_database(db);
+        publish(&db, "barcode-scanned", &serde_json::json!({ "barcode": "ORTX1", "action": "check-out" }));
+        publish(&db, "overdue-alert", &serde_json::json!({ "count": 1 }));
+
+        assert_eq!(deliver_due(&db).unwrap(), 0);
+        let outbox = db.with(|db| db.get_webhook_outbox(true, None)).unwrap();
+        assert_eq!(outbox.len(), 1, "the target only subscribed to scans");
+        let entry = &outbox[0];
+        assert_eq!((entry.attempts, entry.last_status), (1, Some(500)));
+        assert!(entry.next_attempt_at > Local::now() + Duration::seconds(20));
+        // Not due again yet
+        assert_eq!(deliver_due(&db).unwrap(), 0);
+        assert_eq!(received.lock().unwrap().len(), 1);
+
+        db.with(|db| db.retry_webhook(entry.id)).unwrap();
+        assert_eq!(deliver_due(&db).unwrap(), 1);
+        let entry = &db.with(|db| db.get_webhook_outbox(true, None)).unwrap()[0];
+        assert!(entry.delivered_at.is_some());
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        for request in received.iter() {
+            let header = |name: &str| request.headers.get(name).cloned().unwrap_or_default();
+            let timestamp: i64 = header("x-inventory-timestamp").parse().unwrap();
+            assert_eq!(header("x-inventory-signature"), sign("s3cret", timestamp, &request.body));
+            assert_eq!(header("x-inventory-event"), "barcode-scanned");
+            assert_eq!(header("x-inventory-delivery"), entry.id.to_string());
+            assert_eq!(request.body, entry.payload);
+        }
+    }
+
+    #[test]
+    fn publishing_through_the_dispatcher_delivers_without_waiting_for_the_poll() {
+        let (url, received) = target_server(Vec::new());
+        let db = test_db("webhook-wake");
+        db.save_webhook_target(None, &settings(&url, None), None).unwrap();
+        let db = DbService::from_database(db);
+        let dispatcher = WebhookDispatcher::new();
+        dispatcher.start(db.clone());
+        // Let the first round find the outbox empty and go to sleep
+        std::thread::sleep(std::time::Duration::from_millis(500));
+
+        dispatcher.publish(&db, "barcode-scanned", &serde_json::json!({ "barcode": "ORTX1" }));
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(POLL_SECONDS - 2);
+        while received.lock().unwrap().is_empty() && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn claimed_deliveries_are_left_to_the_station_holding_them() {
+        let (url, received) = target_server(vec![]);
+        let db = test_db("webhook-claims");
+        db.save_webhook_target(None, &settings(&url, None), None).unwrap();
+        let db = DbService::from_database(db);
+        publish(&db, "barcode-scanned", &serde_json::json!({ "barcode": "ORTX1" }));
+
+        // Another station is delivering it right now
+        let claimed = db.with(|db| db.claim_due_webhooks(BATCH_SIZE, Duration::seconds(CLAIM_LEASE_SECONDS))).unwrap();
+        assert_eq!(claimed.len(), 1);
+        assert_eq!(deliver_due(&db).unwrap(), 0);
+        assert!(received.lock().unwrap().is_empty());
+
+        // ...and gave it back without trying
+        db.with(|db| db.release_webhook_claims(&[claimed[0].id])).unwrap();
+        assert_eq!(deliver_due(&db).unwrap(), 1);
+        assert_eq!(deliver_due(&db).unwrap(), 0);
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert!(!received[0].headers.contains_key("x-inventory-signature"));
+    }
+
+    #[test]
+    fn secrets_are_write_only() {
+        let db = test_db("webhook-secret");
+        let target = db.save_webhook_target(None, &settings("http://127.0.0.1:9/hook", Some("s3cret")), None).unwrap();
+        assert!(target.has_secret);
+        let json = serde_json::to_string(&db.get_webhook_targets().unwrap()).unwrap();
+        assert!(json.contains(r#""has_secret":true"#), "{}", json);
+        assert!(!json.contains("s3cret"), "{}", json);
+
+        // Saving without a secret keeps it, an empty one removes it
+        db.save_webhook_target(Some(target.id), &settings("http://127.0.0.1:9/other", None), None).unwrap();
+        let kept = db.get_webhook_target(target.id).unwrap().unwrap();
+        assert_eq!((kept.url.as_str(), kept.secret.as_deref()), ("http://127.0.0.1:9/other", Some("s3cret")));
+        db.save_webhook_target(Some(target.id), &settings("http://127.0.0.1:9/other", Some("")), None).unwrap();
+        let cleared = db.get_webhook_target(target.id).unwrap().unwrap();
+        assert!(!cleared.has_secret && cleared.secret.is_none());
+
+        let audit = db.get_audit_log(Some("webhook_target"), None).unwrap();
+        assert!(audit.iter().all(|entry| !format!("{:?}", entry).contains("s3cret")));
+    }
+}
@@ -0,0 +1,88 @@
+//! Multi-process contention check for the shared database.
+//!
+//! Spawns this test binary several times as worker processes that all scan the
+//! same handful of barcodes against one SQLite file, the way several stations
+//! share `data/inventory.db` on a network drive, then verifies that no scan was
+//! lost and that every item's history still alternates check-out / check-in.
+use harrys_lilla_lager_lib::database::ScanLog;
+use harrys_lilla_lager_lib::db_service::{DbService, RetryPolicy};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const BARCODES: [&str; 5] = ["ORTX9001", "ORTX9002", "ORTX9003", "ORTX9004", "ORTX9005"];
+const WORKERS: usize = 4;
+const SCANS_PER_WORKER: usize = 50;
+
+/// Set on the child processes; without it `stress_worker` does nothing
+const WORKER_ENV: &str = "DB_STRESS_WORKER";
+
+fn temp_db_path() -> PathBuf {
+    std::env::temp_dir().join(format!("db-stress-{}.db", std::process::id()))
+}
+
+fn remove_db(path: &Path) {
+    for suffix in ["", "-journal"] {
+        let mut file = path.to_path_buf().into_os_string();
+        file.push(suffix);
+        let _ = std::fs::remove_file(file);
+    }
+}
+
+#[test]
+fn stress_worker() {
+    let Some(worker) = std::env::var(WORKER_ENV).ok().and_then(|value| value.parse::<usize>().ok()) else {
+        return;
+    };
+
+    let db = DbService::open().expect("worker could not open the database");
+    for n in 0..SCANS_PER_WORKER {
+        let barcode = BARCODES[(worker + n) % BARCODES.len()];
+        db.with_policy(RetryPolicy::SCAN, |db| db.record_scan(barcode, Some("Ortopedi")))
+            .expect("scan was not recorded");
+    }
+}
+
+#[test]
+fn concurrent_stations_lose_no_scans() {
+    let db_path = temp_db_path();
+    remove_db(&db_path);
+
+    let exe = std::env::current_exe().unwrap();
+    let children = (0..WORKERS)
+        .map(|worker| {
+            Command::new(&exe)
+                .args(["stress_worker", "--exact", "--nocapture", "--test-threads=1"])
+                .env(WORKER_ENV, worker.to_string())
+                .env("INVENTORY_DB_PATH", &db_path)
+                .spawn()
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    let failed_workers = children
+        .into_iter()
+        .filter_map(|mut child| child.wait().ok())
+        .filter(|status| !status.success())
+        .count();
+
+    std::env::set_var("INVENTORY_DB_PATH", &db_path);
+    let db = DbService::open().unwrap();
+    let mut logs: Vec<ScanLog> = db.with(|db| db.get_logs(None, false)).unwrap();
+    logs.sort_by_key(|log| log.id);
+    drop(db);
+    remove_db(&db_path);
+
+    let mut last_action: HashMap<String, String> = HashMap::new();
+    let mut repeated = 0;
+    for log in &logs {
+        if last_action.get(&log.barcode) == Some(&log.action) {
+            repeated += 1;
+        }
+        last_action.insert(log.barcode.clone(), log.action.clone());
+    }
+
+    assert_eq!(failed_workers, 0, "worker processes failed");
+    assert_eq!(logs.len(), WORKERS * SCANS_PER_WORKER, "scans were lost");
+    assert_eq!(repeated, 0, "an item was checked in or out twice in a row");
+}
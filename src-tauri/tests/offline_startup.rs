@@ -0,0 +1,95 @@
+//! A station must start and keep taking scans when the shared database is
+//! unreachable, using the state it saved the last time it was connected.
+use harrys_lilla_lager_lib::db_service::DbService;
+use harrys_lilla_lager_lib::logger::Logger;
+use harrys_lilla_lager_lib::webhook::{WebhookDispatcher, WebhookTargetSettings};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("offline-startup-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn starts_offline_from_the_local_cache() {
+    let dir = temp_dir();
+    let share = dir.join("share");
+    std::fs::create_dir_all(&share).unwrap();
+    std::env::set_var("OFFLINE_QUEUE_PATH", dir.join("pending_scans.db"));
+    std::env::set_var("INVENTORY_DB_PATH", share.join("inventory.db"));
+
+    // A normal day: the station saw the mappings and checked ORTX1 out
+    {
+        let db = DbService::open_or_offline().unwrap();
+        assert!(db.is_connected());
+        let target = WebhookTargetSettings {
+            name: "Teams".to_string(),
+            url: "http://127.0.0.1:9/hook".to_string(),
+            secret: None,
+            events: vec!["barcode-scanned".to_string()],
+            enabled: true,
+        };
+        db.with(|db| db.save_webhook_target(None, &target, None)).unwrap();
+        let mut logger = Logger::new(db, Arc::new(WebhookDispatcher::new())).unwrap();
+        let scan = logger.process_barcode_scan("ORTX1").unwrap();
+        assert_eq!((scan.action.as_str(), scan.queued), ("check-out", false));
+    }
+
+    // Next morning the share is gone
+    std::fs::rename(&share, dir.join("share-gone")).unwrap();
+    let db = DbService::open_or_offline().unwrap();
+    assert!(!db.is_connected());
+    let mut logger = Logger::new(db.clone(), Arc::new(WebhookDispatcher::new())).unwrap();
+
+    let scan = logger.process_barcode_scan("ORTX1").unwrap();
+    assert_eq!(scan.action, "check-in");
+    assert_eq!(scan.department.as_deref(), Some("Ortopedi"));
+    assert!(scan.queued);
+    let scan = logger.process_barcode_scan("ORTX2").unwrap();
+    assert_eq!((scan.action.as_str(), scan.queued), ("check-out", true));
+    assert!(logger.process_barcode_scan("NOPE1").is_err());
+    assert_eq!(logger.pending_scan_count().unwrap(), 2);
+
+    // The share comes back: the first call connects and the queue drains
+    std::fs::rename(dir.join("share-gone"), &share).unwrap();
+    assert_eq!(logger.flush_offline_queue().unwrap(), 0);
+    assert!(db.is_connected());
+    let logs = logger.get_recent_logs(None, false).unwrap();
+    let mut scans: Vec<(String, String)> = logs.into_iter().map(|log| (log.barcode, log.action)).collect();
+    scans.sort();
+    assert_eq!(
+        scans,
+        vec![
+            ("ORTX1".to_string(), "check-in".to_string()),
+            ("ORTX1".to_string(), "check-out".to_string()),
+            ("ORTX2".to_string(), "check-out".to_string()),
+        ]
+    );
+
+    // Webhooks couldn't be reached while the scans were queued, so they hear about them now
+    let outbox = db.with(|db| db.get_webhook_outbox(true, None)).unwrap();
+    let mut published: Vec<(String, String)> = outbox
+        .iter()
+        .map(|entry| {
+            let payload: serde_json::Value = serde_json::from_str(&entry.payload).unwrap();
+            assert_eq!(payload["data"]["source"], "offline-queue");
+            let data = &payload["data"];
+            (data["barcode"].as_str().unwrap().to_string(), data["action"].as_str().unwrap().to_string())
+        })
+        .collect();
+    published.sort();
+    assert_eq!(
+        published,
+        vec![
+            ("ORTX1".to_string(), "check-in".to_string()),
+            ("ORTX2".to_string(), "check-out".to_string()),
+        ]
+    );
+
+    drop(logger);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&dir);
+}
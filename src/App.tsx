@@ -6,6 +6,7 @@ import InputSection from './components/InputSection';
 import InventoryTable from './components/InventoryTable';
 import AdminSettingsModal from './components/AdminSettingsModal';
 import logoSvg from './assets/logo.svg';
+import { FailedScan } from './types';
 
 // Type definitions
 interface Article {
@@ -54,6 +55,10 @@ const App: React.FC = () => {
   const [newItemBarcode, setNewItemBarcode] = useState("");
   const [newItemDepartment, setNewItemDepartment] = useState("");
   const [newItemDescription, setNewItemDescription] = useState("");
+  // Scans waiting in the offline queue for the shared database
+  const [pendingScans, setPendingScans] = useState(0);
+  // Queued scans the shared database rejected; they have to be entered by hand
+  const [failedScans, setFailedScans] = useState<FailedScan[]>([]);
 
   // State for toast notifications
   const [toast, setToast] = useState<{
@@ -88,6 +93,16 @@ const App: React.FC = () => {
         console.log('Manual scan complete:', event.payload);
         loadData();
       });
+
+      await listen('scan-voided', (event: any) => {
+        console.log('Scan voided:', event.payload);
+        loadData();
+      });
+
+      await listen<{ count: number; failed: number }>('pending-scans-changed', (event) => {
+        setPendingScans(event.payload.count);
+        loadData();
+      });
     };
 
     setupListeners();
@@ -103,6 +118,14 @@ const App: React.FC = () => {
   }, []);
 
   const loadData = async () => {
+    // Fetched on its own so the indicator still works while the shared database is down
+    invoke<number>('get_pending_scan_count')
+      .then(setPendingScans)
+      .catch((error) => console.error('Error loading pending scan count:', error));
+    invoke<FailedScan[]>('get_failed_scans')
+      .then(setFailedScans)
+      .catch((error) => console.error('Error loading failed scans:', error));
+
     try {
       const [logs, mappings, checkedOut, allItems] = await Promise.all([
         invoke<ScanLog[]>('get_recent_logs', { limit: 100 }),
@@ -171,7 +194,11 @@ const App: React.FC = () => {
     try {
       // Use Tauri backend for scanning if available (already uppercase)
       const result = await invoke<any>('manual_scan_barcode', { barcode: trimmedId });
-      showToast(`Item ${result.action}: ${trimmedId}`, 'success');
+      if (result.queued) {
+        showToast(`Item ${result.action}: ${trimmedId} (saved offline, will sync when the database is back)`, 'warning');
+      } else {
+        showToast(`Item ${result.action}: ${trimmedId}`, 'success');
+      }
       
       // Clear the input field and refocus after successful scan
       setItemId('');
@@ -206,6 +233,25 @@ const App: React.FC = () => {
     }
   };
 
+  /**
+   * Show the rejected offline scans and drop them once they've been entered by hand
+   */
+  const handleReviewFailedScans = async () => {
+    const list = failedScans
+      .map((scan) => `${scan.barcode} ${scan.action} at ${new Date(scan.scanned_at).toLocaleString()}: ${scan.error}`)
+      .join('\n');
+    if (!window.confirm(`These offline scans were rejected and must be entered by hand:\n\n${list}\n\nRemove them from this list?`)) {
+      return;
+    }
+    try {
+      await Promise.all(failedScans.map((scan) => invoke('discard_failed_scan', { id: scan.id })));
+      setFailedScans(await invoke<FailedScan[]>('get_failed_scans'));
+    } catch (error) {
+      console.error('Error discarding failed scans:', error);
+      showToast('Could not clear the rejected scans.', 'error');
+    }
+  };
+
   /**
    * Admin login handler
    */
@@ -417,6 +463,40 @@ const App: React.FC = () => {
           }}>
             HARRY'S LILLA LAGER
           </h1>
+
+          {pendingScans > 0 && (
+            <div
+              style={{
+                padding: '6px 10px',
+                fontSize: '12px',
+                border: '2px solid #000',
+                backgroundColor: '#fbbf24',
+                textTransform: 'uppercase',
+                fontWeight: 'bold'
+              }}
+              title="Scans saved on this station while the shared database was unreachable"
+            >
+              {pendingScans} offline
+            </div>
+          )}
+
+          {failedScans.length > 0 && (
+            <div
+              onClick={handleReviewFailedScans}
+              style={{
+                padding: '6px 10px',
+                fontSize: '12px',
+                border: '2px solid #000',
+                backgroundColor: '#f87171',
+                textTransform: 'uppercase',
+                fontWeight: 'bold',
+                cursor: 'pointer'
+              }}
+              title="Offline scans the shared database rejected; click to review"
+            >
+              {failedScans.length} rejected
+            </div>
+          )}
           
           <div style={{ display: 'flex', gap: '10px' }}>
             <button
@@ -11,12 +11,278 @@ export interface ScanLog {
   barcode: string;
   action: string;
   department?: string;
+  station?: string;
+  voided_at?: string;
+  voided_by?: string;
+  void_reason?: string;
   item_name?: string; // Display name for the item
 }
 
+export interface Loan {
+  id: number;
+  barcode: string;
+  department?: string;
+  out_log_id: number;
+  out_time: string;
+  out_station?: string;
+  in_log_id?: number;
+  in_time?: string;
+  in_station?: string;
+  duration_minutes?: number;
+}
+
+/** A scan queued offline that the shared database rejected when it came back */
+export interface FailedScan {
+  id: number;
+  barcode: string;
+  action: string;
+  department?: string;
+  scanned_at: string;
+  error: string;
+  failed_at: string;
+}
+
+export type LogSortField = 'timestamp' | 'barcode' | 'department' | 'action';
+
+export interface LogCursor {
+  value?: string;
+  timestamp: number;
+  id: number;
+}
+
+export interface LogQuery {
+  from?: string;
+  to?: string;
+  department?: string;
+  action?: string;
+  barcode?: string;
+  barcode_prefix?: string;
+  description?: string;
+  station?: string;
+  include_voided?: boolean;
+  include_archived?: boolean;
+  sort?: LogSortField;
+  descending?: boolean;
+  page_size?: number;
+  cursor?: LogCursor;
+}
+
+export interface LogPage {
+  logs: ScanLog[];
+  next_cursor?: LogCursor;
+}
+
+export interface CheckedOutSnapshot {
+  at: string;
+  items: ScanLog[];
+  departments: [string, number][];
+}
+
+export interface TurnaroundStats {
+  key: string;
+  loans: number;
+  open_loans: number;
+  average_minutes?: number;
+  median_minutes?: number;
+  p90_minutes?: number;
+  max_minutes?: number;
+}
+
+export interface ItemUsage {
+  barcode: string;
+  description?: string;
+  department?: string;
+  loans: number;
+  total_minutes_out: number;
+  last_used?: string;
+}
+
+export interface Analytics {
+  from?: string;
+  to?: string;
+  by_department: TurnaroundStats[];
+  by_item: TurnaroundStats[];
+  loans_per_day: { period: string; loans: number }[];
+  loans_per_week: { period: string; loans: number }[];
+  busiest_hours: { hour: number; check_outs: number; check_ins: number }[];
+  most_used: ItemUsage[];
+  least_used: ItemUsage[];
+  idle: ItemUsage[];
+}
+
+export type AnalyticsReport = 'departments' | 'items' | 'days' | 'weeks' | 'hours' | 'usage' | 'idle';
+
+export type AlertLevel = 'warning' | 'critical';
+export type AlertState = 'new' | 'acknowledged' | 'snoozed' | 'resolved';
+
+export type ThresholdRule =
+  | { kind: 'item'; barcode: string }
+  | { kind: 'department'; prefix: string; department: string }
+  | { kind: 'global' };
+
+export interface Alert {
+  id: number;
+  loan_id: number;
+  barcode: string;
+  department?: string;
+  level: AlertLevel;
+  state: AlertState;
+  threshold_hours: number;
+  rule?: ThresholdRule;
+  created_at: string;
+  updated_at: string;
+  escalated_at?: string;
+  acknowledged_at?: string;
+  acknowledged_by?: string;
+  snoozed_until?: string;
+  snoozed_by?: string;
+  resolved_at?: string;
+  emailed_at?: string;
+  emailed_level?: AlertLevel;
+}
+
+/** Unresolved alerts, as shown on the tray badge (`overdue-summary` event) */
+export interface AlertSummary {
+  open: number;
+  critical: number;
+  new: number;
+}
+
+export interface OpeningHours {
+  /** 0 = Monday ... 6 = Sunday */
+  weekday: number;
+  opens: string;
+  closes: string;
+}
+
+export interface Holiday {
+  date: string;
+  name?: string;
+}
+
+export interface SchedulerStatus {
+  interval_minutes: number;
+  last_run?: string;
+  next_run?: string;
+  last_alert_count: number;
+  last_error?: string;
+}
+
+export interface AuditEntry {
+  id: number;
+  timestamp: string;
+  station?: string;
+  operator?: string;
+  action: string;
+  entity: string;
+  entity_key?: string;
+  before_value?: string;
+  after_value?: string;
+}
+
+export type TimelineEntry = { timestamp: string } & (
+  | { kind: 'scan'; log: ScanLog; forced: boolean; loan_id?: number; loan_minutes?: number }
+  | { kind: 'catalogue_change'; entry: AuditEntry }
+);
+
+export interface ItemHistoryStats {
+  total_loans: number;
+  average_minutes_out?: number;
+  longest_minutes_out?: number;
+  last_seen?: string;
+  last_department?: string;
+  last_station?: string;
+  currently_out: boolean;
+  forced_scans: number;
+  voided_scans: number;
+}
+
+export interface ItemHistory {
+  barcode: string;
+  item?: { barcode: string; department?: string; description?: string };
+  timeline: TimelineEntry[];
+  loans: Loan[];
+  stats: ItemHistoryStats;
+}
+
 export interface DepartmentMapping {
   prefix: string;
   department: string;
+  alert_threshold_hours?: number;
+  /** Comma-separated addresses for overdue digests */
+  alert_emails?: string;
+}
+
+export type WebhookEvent =
+  | 'barcode-scanned'
+  | 'overdue-alert'
+  | 'scan-session-started'
+  | 'scan-session-ended';
+
+export interface WebhookTargetSettings {
+  name: string;
+  url: string;
+  /** Leave out to keep the current secret when updating; empty removes it */
+  secret?: string;
+  /** Empty subscribes to every event */
+  events: WebhookEvent[];
+  enabled: boolean;
+}
+
+export interface WebhookTarget extends Omit<WebhookTargetSettings, 'secret'> {
+  id: number;
+  /** The secret itself is never sent back */
+  has_secret: boolean;
+  created_at: string;
+}
+
+export interface WebhookOutboxEntry {
+  id: number;
+  target_id: number;
+  event: WebhookEvent;
+  /** JSON body, as sent */
+  payload: string;
+  created_at: string;
+  attempts: number;
+  next_attempt_at: string;
+  last_attempt_at?: string;
+  last_status?: number;
+  last_error?: string;
+  delivered_at?: string;
+  failed_at?: string;
+}
+
+/** Body of every webhook request */
+export interface WebhookPayload<T = unknown> {
+  schema_version: number;
+  event: WebhookEvent | 'test';
+  occurred_at: string;
+  station: string;
+  data: T;
+}
+
+export interface SerialPortEntry {
+  name: string;
+  kind: 'usb' | 'pci' | 'bluetooth' | 'unknown';
+  description?: string;
+  manufacturer?: string;
+  serial_number?: string;
+  vid?: number;
+  pid?: number;
+}
+
+/** A running scanner input, as returned by get_scan_sources */
+export interface SourceStatus {
+  /** Scans are tagged with this: "keyboard", "serial", "tcp" or "replay:<file>" */
+  id: string;
+  kind: 'keyboard' | 'serial' | 'tcp' | 'replay';
+  /** The port, address or file it reads from */
+  detail: string;
+  connected: boolean;
+  last_error?: string;
+  scan_count: number;
+  last_barcode?: string;
+  last_scan_at?: string;
 }
 
 export interface Item {